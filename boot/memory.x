@@ -16,3 +16,13 @@ _heap_size = 64M;
 _max_hart_id = 8;
 
 PROVIDE(_etext = _stext + SIZEOF(.text));
+
+/* Once the kernel uses `alloc`, rustc links in an allocator shim that
+   always has unwind tables, whatever the panic strategy. riscv-rt's
+   link.x keeps .eh_frame as an INFO section at address 0, where its
+   32-bit PC-relative relocations can't reach the code at 0x80000000,
+   so drop it here first: the kernel never unwinds. */
+SECTIONS
+{
+    /DISCARD/ : { *(.eh_frame) *(.eh_frame_hdr) }
+}
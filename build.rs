@@ -1,6 +1,15 @@
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[path = "src/fs/layout.rs"]
+#[allow(dead_code)]
+mod layout;
+#[path = "tools/mkfs.rs"]
+mod mkfs;
+
+const USER_TARGET: &str = "riscv64gc-unknown-none-elf";
 
 fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -10,5 +19,87 @@ fn main() {
     println!("cargo:rustc-link-search={}", out_dir.display());
     println!("cargo:rerun-if-changed=memory.x");
 
+    let user_bin_dir = build_user_programs(&out_dir);
+    build_fs_image(&out_dir, &user_bin_dir);
+
     println!("cargo:rerun-if-changed=build.rs");
 }
+
+/// Build the programs in `user/` with a nested cargo, so the kernel can
+/// embed them with `include_bytes!(concat!(env!("USER_BIN_DIR"), "/init"))`.
+fn build_user_programs(out_dir: &Path) -> PathBuf {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_dir = manifest_dir.join("user");
+    let target_dir = out_dir.join("user");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+    let status = Command::new(cargo)
+        .current_dir(&user_dir)
+        .args([
+            "build",
+            "--release",
+            "--target",
+            USER_TARGET,
+            "--target-dir",
+        ])
+        .arg(&target_dir)
+        // Don't link user programs with the kernel's linker scripts,
+        // and don't run clippy on them when linting the kernel.
+        .env("CARGO_ENCODED_RUSTFLAGS", "")
+        .env_remove("RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .status()
+        .expect("failed to run cargo for user programs");
+    assert!(status.success(), "failed to build user programs");
+
+    let bin_dir = target_dir.join(USER_TARGET).join("release");
+    println!("cargo:rustc-env=USER_BIN_DIR={}", bin_dir.display());
+    println!("cargo:rerun-if-changed={}", user_dir.join("src").display());
+    println!(
+        "cargo:rerun-if-changed={}",
+        user_dir.join("user.ld").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        user_dir.join("Cargo.toml").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        manifest_dir.join("src/syscall/num.rs").display()
+    );
+
+    bin_dir
+}
+
+/// Build the root file system image with every user program in it,
+/// the kernel embeds it with `include_bytes!(env!("FS_IMG"))`.
+fn build_fs_image(out_dir: &Path, user_bin_dir: &Path) {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let mut programs = fs::read_dir(manifest_dir.join("user/src/bin"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+            let contents = fs::read(user_bin_dir.join(&name)).unwrap();
+            (name, contents)
+        })
+        .collect::<Vec<_>>();
+    programs.sort();
+
+    let img = out_dir.join("fs.img");
+    mkfs::mkfs(&img, &programs);
+    println!("cargo:rustc-env=FS_IMG={}", img.display());
+
+    // Also put it next to the kernel, for qemu's virtio disk.
+    // OUT_DIR is somewhere under <target>/<triple>/<profile>/build
+    let profile_dir = out_dir
+        .ancestors()
+        .find(|dir| dir.file_name().is_some_and(|name| name == "build"))
+        .and_then(Path::parent);
+    if let Some(profile_dir) = profile_dir {
+        fs::copy(&img, profile_dir.join("fs.img")).unwrap();
+    }
+    println!("cargo:rerun-if-changed=src/fs/layout.rs");
+    println!("cargo:rerun-if-changed=tools/mkfs.rs");
+}
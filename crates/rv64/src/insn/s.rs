@@ -5,6 +5,26 @@ instruction!(
     sfence_vma, "sfence.vma zero, zero"
 );
 
+/// Flush the TLB entries of virtual address `va`, in all address spaces
+#[inline]
+pub fn sfence_vma_va(va: usize) {
+    unsafe { core::arch::asm!("sfence.vma {}, zero", in(reg) va) };
+}
+
+/// Flush the TLB entries of address space `asid`,
+/// except those of global mappings
+#[inline]
+pub fn sfence_vma_asid(asid: usize) {
+    unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) };
+}
+
+/// Flush the TLB entries of virtual address `va` in address space `asid`,
+/// except those of global mappings
+#[inline]
+pub fn sfence_vma_va_asid(va: usize, asid: usize) {
+    unsafe { core::arch::asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid) };
+}
+
 instruction!(
     /// Return from S mode to U mode and jump to `sepc`
     unsafe sret, "sret", nomem, nostack
@@ -364,6 +364,12 @@ macro_rules! csr_reg_ro {
 macro_rules! csr_set_clear {
     ($reg:ident, $setter:ident, $clear:ident, $mask:expr) => {
         impl $reg {
+            /// Set the bits of the mask in the register.
+            ///
+            /// # Safety
+            ///
+            /// It changes how the hart runs, e.g. lets it take interrupts
+            /// or access user memory, which the caller must be ready for.
             #[inline]
             pub unsafe fn $setter(&self) {
                 unsafe {
@@ -371,6 +377,11 @@ macro_rules! csr_set_clear {
                 }
             }
 
+            /// Clear the bits of the mask in the register.
+            ///
+            /// # Safety
+            ///
+            /// Like the setter.
             #[inline]
             pub unsafe fn $clear(&self) {
                 unsafe {
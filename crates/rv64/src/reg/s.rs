@@ -22,6 +22,7 @@ impl sstatus {
     pub const SIE: BitFlag = BitFlag::new(1, 1); // Supervisor Interrupt Enable
 }
 csr_set_clear!(sstatus, set_sie, clear_sie, sstatus::SIE);
+csr_set_clear!(sstatus, set_sum, clear_sum, sstatus::SUM);
 impl Sstatus {
     /// Read `sstatus.SPP`
     #[inline]
@@ -34,6 +35,12 @@ impl Sstatus {
     pub fn sie(&self) -> bool {
         sstatus::SIE.mask(self.0) == 1
     }
+
+    /// Read `sstatus.SUM`
+    #[inline]
+    pub fn sum(&self) -> bool {
+        sstatus::SUM.read(self.0) == 1
+    }
 }
 
 csr_reg_rw!(
@@ -219,7 +226,7 @@ impl satp {
     pub const PPN: BitFlag = BitFlag::new(44, 0);
 }
 
-#[derive(Debug, IntEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
 #[repr(u8)]
 pub enum SatpMode {
     Bare = 0,
@@ -235,6 +242,11 @@ impl satp {
         SatpMode::try_from(self.read_mask(satp::MODE) as u8).ok()
     }
 
+    #[inline]
+    pub fn asid(&self) -> usize {
+        self.read_mask(satp::ASID)
+    }
+
     #[inline]
     pub unsafe fn set(&self, mode: SatpMode, asid: usize, pa: usize) {
         self.write(self.make(mode, asid, pa));
@@ -29,6 +29,14 @@ pub enum PageWidth {
     W48 = PAGE_OFFSET.width() + VPN_WIDTH * 4,
 }
 
+impl PageWidth {
+    /// Number of bytes in a page of this width
+    #[inline]
+    pub const fn size(self) -> usize {
+        1 << self as usize
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct PhysAddr(usize);
@@ -315,16 +323,12 @@ impl<T: PagingSchema + 'static> PageTable<T> {
                 }
             } else {
                 if let Some(allocator) = alloc {
-                    let page_width = if l == level {
-                        PageWidth::try_from(pl.page_offset.width()).expect("invalid page width")
-                    } else {
-                        PageWidth::W4K
-                    };
+                    // Page-table pages are always 4K.
                     unsafe {
                         let page = allocator
-                            .palloc(page_width)
+                            .palloc(PageWidth::W4K)
                             .ok_or(PageTableError::AllocFailed)?;
-                        page.memset(0usize, 1 << usize::from(page_width));
+                        page.memset(0usize, PAGE_SIZE);
                         *pte = PTE::new(page, PteFlags::new());
                     }
                 } else {
@@ -338,6 +342,28 @@ impl<T: PagingSchema + 'static> PageTable<T> {
         return Err(PageTableError::InvalidPageTable);
     }
 
+    /// Level of the leaf PTEs that map pages of `width`
+    fn level_of(width: PageWidth) -> Option<usize> {
+        T::page_levels()
+            .iter()
+            .position(|pl| pl.page_width() == width)
+    }
+
+    /// Highest level whose pages `va` and `pa` are both aligned to,
+    /// and that fit in `size`
+    fn largest_level(va: VirtAddr, pa: PhysAddr, size: usize) -> usize {
+        T::page_levels()
+            .iter()
+            .rposition(|pl| {
+                let sz = pl.page_size();
+                usize::from(va) % sz == 0 && usize::from(pa) % sz == 0 && sz <= size
+            })
+            .unwrap_or(0)
+    }
+
+    /// Map `[va, va + size)` to `[pa, pa + size)`, with the largest pages
+    /// both addresses are aligned to at each step, e.g. 2M and 1G superpages
+    /// in the middle of a large range, and 4K pages at its ends.
     pub unsafe fn map_pages(
         &mut self,
         va: impl Into<VirtAddr>,
@@ -345,6 +371,26 @@ impl<T: PagingSchema + 'static> PageTable<T> {
         pa: impl Into<PhysAddr>,
         perm: PteFlags,
         allocator: &(impl PageAllocator + Sync + Send),
+    ) -> Result<(), PageTableError> {
+        self.map_pages_as(va, size, pa, perm, None, allocator)
+    }
+
+    /// Like `map_pages`, but only with pages of `width` if it's `Some`,
+    /// then `va`, `pa` and `size` must be aligned to it.
+    ///
+    /// # Safety
+    ///
+    /// `[pa, pa + size)` must be memory that may be accessed with `perm`
+    /// at `va`, and the tables of this page table must come from
+    /// `allocator`.
+    pub unsafe fn map_pages_as(
+        &mut self,
+        va: impl Into<VirtAddr>,
+        size: usize,
+        pa: impl Into<PhysAddr>,
+        perm: PteFlags,
+        width: Option<PageWidth>,
+        allocator: &(impl PageAllocator + Sync + Send),
     ) -> Result<(), PageTableError> {
         let va = va.into();
         let pa = pa.into();
@@ -352,20 +398,134 @@ impl<T: PagingSchema + 'static> PageTable<T> {
             return Err(PageTableError::InvalidMapSize);
         }
 
-        let end = VirtAddr::from(size - 1).page_rounddown().into();
+        let size = usize::from(VirtAddr::from(size).page_roundup());
+        let forced = match width {
+            Some(width) => {
+                let level = Self::level_of(width).ok_or(PageTableError::InvalidPageLevel)?;
+                let sz = width.size();
+                if usize::from(va) % sz != 0 || usize::from(pa) % sz != 0 || size % sz != 0 {
+                    return Err(PageTableError::MisalignedMapping);
+                }
+                Some(level)
+            }
+            None => None,
+        };
         let perm = perm.set_valid(true);
 
-        for offset in (0..=end).step_by(PAGE_SIZE) {
-            let (_, pte) = self.walk(va + offset, 0, Some(allocator))?;
+        let mut offset = 0;
+        while offset < size {
+            let level = forced
+                .unwrap_or_else(|| Self::largest_level(va + offset, pa + offset, size - offset));
+            let (pl, pte) = self.walk(va + offset, level, Some(allocator))?;
             if pte.flags().valid() {
-                return Err(PageTableError::DuplicateMapping(0, *pte));
+                return Err(PageTableError::DuplicateMapping(level, *pte));
             }
             *pte = PTE::new(pa + offset, perm);
+            offset += pl.page_size();
         }
 
         Ok(())
     }
 
+    /// Find the leaf PTE mapping `va`, or the level of the invalid PTE
+    /// that leaves it unmapped.
+    fn lookup(&self, va: VirtAddr) -> Lookup {
+        let mut table = self as *const Self;
+        for pl in T::page_levels().iter().rev() {
+            let pte = unsafe { &(*table).table[pl.vpn.read(va.into())] } as *const PTE;
+            let flags = unsafe { (*pte).flags() };
+            if !flags.valid() {
+                return Lookup::Hole(pl);
+            }
+            if flags.is_leaf() {
+                return Lookup::Leaf(pl, pte as *mut PTE);
+            }
+            table = unsafe { (*pte).addr().as_ptr::<Self>() };
+        }
+        // A level 0 PTE pointing to another table isn't valid.
+        Lookup::Hole(&T::page_levels()[0])
+    }
+
+    /// Iterate over the mapped leaves overlapping `[va, va + size)`,
+    /// yielding the virtual address, PTE and page width of each one.
+    pub fn leaves(&self, va: impl Into<VirtAddr>, size: usize) -> Leaves<'_, T> {
+        let va = usize::from(va.into());
+        Leaves {
+            table: self,
+            va,
+            end: va.saturating_add(size).min(usize::from(T::max_va()) + 1),
+        }
+    }
+
+    /// Calls `f` with each leaf PTE in `[va, va + size)`, which must not
+    /// have superpages that are only partly in it.
+    unsafe fn for_each_leaf(
+        &mut self,
+        va: VirtAddr,
+        size: usize,
+        mut f: impl FnMut(&mut PTE),
+    ) -> Result<(), PageTableError> {
+        let start = usize::from(va);
+        let end = start.saturating_add(size).min(usize::from(T::max_va()) + 1);
+        let mut a = start;
+        while a < end {
+            match self.lookup(VirtAddr::from(a)) {
+                Lookup::Hole(pl) => a = align_down(a, pl.page_size()) + pl.page_size(),
+                Lookup::Leaf(pl, pte) => {
+                    let base = align_down(a, pl.page_size());
+                    if base < start || base + pl.page_size() > end {
+                        return Err(PageTableError::MisalignedMapping);
+                    }
+                    f(&mut *pte);
+                    a = base + pl.page_size();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the mappings in `[va, va + size)`, skipping unmapped pages.
+    /// Pass `free` to give the pages back to it. Fails on a superpage that is
+    /// only partly in the range, after unmapping the pages before it.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the unmapped pages through this page table any
+    /// more, including TLB entries, and with `free` they must have come
+    /// from it and not be mapped anywhere else.
+    pub unsafe fn unmap_range(
+        &mut self,
+        va: impl Into<VirtAddr>,
+        size: usize,
+        free: Option<&(impl PageAllocator + Sync + Send)>,
+    ) -> Result<(), PageTableError> {
+        self.for_each_leaf(va.into(), size, |pte| {
+            if let Some(allocator) = free {
+                allocator.pfree(pte.addr());
+            }
+            *pte = PTE::new_invalid();
+        })
+    }
+
+    /// Replace the flags of the mappings in `[va, va + size)` by what `f`
+    /// returns for them, skipping unmapped pages. Fails on a superpage that
+    /// is only partly in the range, after changing the pages before it.
+    ///
+    /// # Safety
+    ///
+    /// The pages must be memory that may be accessed with the new flags,
+    /// and stale TLB entries must be flushed before relying on them.
+    pub unsafe fn protect_range(
+        &mut self,
+        va: impl Into<VirtAddr>,
+        size: usize,
+        mut f: impl FnMut(PteFlags) -> PteFlags,
+    ) -> Result<(), PageTableError> {
+        self.for_each_leaf(va.into(), size, |pte| {
+            *pte = PTE::new(pte.addr(), f(pte.flags()).set_valid(true));
+        })
+    }
+
     /// Recursively free page-table pages. The `PageTable` itself need to be freed by the caller.
     /// Safety: All leaf mappings must already have been removed.
     pub unsafe fn free_walk(&mut self, allocator: &(impl PageAllocator + Sync + Send)) {
@@ -391,10 +551,16 @@ impl<T: PagingSchema + 'static> PageTable<T> {
         max_depth: usize,
     ) -> core::fmt::Result {
         let offset = cur_depth * 4;
+        let levels = T::page_levels();
+        let level = &levels[levels.len() - 1 - cur_depth];
         for (i, pte) in self.table.iter().enumerate() {
             let flags = pte.flags();
             if flags.valid() {
-                write!(f, "{} {:offset$}PTE[{}] = {:?}\n", cur_depth, "", i, pte)?;
+                write!(f, "{} {:offset$}PTE[{}] = {:?}", cur_depth, "", i, pte)?;
+                if flags.is_leaf() {
+                    write!(f, " {:?}", level.page_width())?;
+                }
+                write!(f, "\n")?;
                 if flags.xwr() == 0b000 && cur_depth < max_depth {
                     unsafe { pte.addr().as_mut::<Self>() }.unwrap().dump(
                         f,
@@ -431,6 +597,43 @@ impl<T: PagingSchema + 'static> Debug for PageTable<T> {
     }
 }
 
+#[inline]
+const fn align_down(addr: usize, size: usize) -> usize {
+    addr & !(size - 1)
+}
+
+enum Lookup {
+    Leaf(&'static PageLevel, *mut PTE),
+    Hole(&'static PageLevel),
+}
+
+/// Iterator over the mapped leaves of a page table, see `PageTable::leaves`
+pub struct Leaves<'a, T: PagingSchema> {
+    table: &'a PageTable<T>,
+    va: usize,
+    end: usize,
+}
+
+impl<'a, T: PagingSchema + 'static> Iterator for Leaves<'a, T> {
+    type Item = (VirtAddr, &'a PTE, PageWidth);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.va < self.end {
+            match self.table.lookup(VirtAddr::from(self.va)) {
+                Lookup::Hole(pl) => {
+                    self.va = align_down(self.va, pl.page_size()) + pl.page_size();
+                }
+                Lookup::Leaf(pl, pte) => {
+                    let base = align_down(self.va, pl.page_size());
+                    self.va = base + pl.page_size();
+                    return Some((VirtAddr::from(base), unsafe { &*pte }, pl.page_width()));
+                }
+            }
+        }
+        None
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PageTableError {
     InvalidPageTable,
@@ -440,4 +643,5 @@ pub enum PageTableError {
     AllocFailed,
     InvalidMapSize,
     DuplicateMapping(usize, PTE),
+    MisalignedMapping,
 }
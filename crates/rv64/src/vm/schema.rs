@@ -1,12 +1,15 @@
+mod dynamic;
 mod sv39;
 mod sv48;
 mod sv57;
 
+pub use dynamic::Dynamic;
 pub use sv39::Sv39;
 pub use sv48::Sv48;
 pub use sv57::Sv57;
 
-use super::VirtAddr;
+use super::{PageWidth, VirtAddr};
+use crate::reg::SatpMode;
 use crate::BitFlag;
 
 #[derive(Clone, Copy, Debug)]
@@ -26,6 +29,17 @@ impl PageLevel {
             page_offset: BitFlag::new(pa_ppn.shift(), 0),
         }
     }
+
+    /// Size of the pages mapped by a leaf PTE at this level
+    #[inline]
+    pub const fn page_size(&self) -> usize {
+        1 << self.page_offset.width()
+    }
+
+    #[inline]
+    pub fn page_width(&self) -> PageWidth {
+        PageWidth::try_from(self.page_offset.width()).expect("invalid page width")
+    }
 }
 
 pub unsafe trait PagingSchema {
@@ -34,4 +48,7 @@ pub unsafe trait PagingSchema {
 
     /// The mask for page address
     fn page_levels() -> &'static [PageLevel];
+
+    /// The `satp` mode that turns on this schema
+    fn satp_mode() -> SatpMode;
 }
@@ -0,0 +1,51 @@
+use super::{PageLevel, PagingSchema, Sv39, Sv48, Sv57};
+use crate::reg::SatpMode;
+use crate::vm::VirtAddr;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+static MODE: AtomicU8 = AtomicU8::new(SatpMode::Sv39 as u8);
+
+/// One of `Sv39`, `Sv48` or `Sv57`, chosen at run time with `select`
+/// before any page table of this schema is built. Sv39 by default.
+pub struct Dynamic;
+
+impl Dynamic {
+    pub fn select(mode: SatpMode) {
+        assert!(
+            matches!(mode, SatpMode::Sv39 | SatpMode::Sv48 | SatpMode::Sv57),
+            "unsupported paging mode {:?}",
+            mode
+        );
+        MODE.store(mode as u8, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn mode() -> SatpMode {
+        SatpMode::try_from(MODE.load(Ordering::Relaxed)).unwrap()
+    }
+}
+
+unsafe impl PagingSchema for Dynamic {
+    #[inline]
+    fn max_va() -> VirtAddr {
+        match Self::mode() {
+            SatpMode::Sv48 => Sv48::max_va(),
+            SatpMode::Sv57 => Sv57::max_va(),
+            _ => Sv39::max_va(),
+        }
+    }
+
+    #[inline]
+    fn page_levels() -> &'static [PageLevel] {
+        match Self::mode() {
+            SatpMode::Sv48 => Sv48::page_levels(),
+            SatpMode::Sv57 => Sv57::page_levels(),
+            _ => Sv39::page_levels(),
+        }
+    }
+
+    #[inline]
+    fn satp_mode() -> SatpMode {
+        Self::mode()
+    }
+}
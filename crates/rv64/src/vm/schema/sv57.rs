@@ -1,4 +1,5 @@
 use super::{PageLevel, PagingSchema};
+use crate::reg::SatpMode;
 use crate::vm::{VirtAddr, PAGE_OFFSET, PTE, VPN_WIDTH};
 use crate::BitFlag;
 
@@ -45,4 +46,9 @@ unsafe impl PagingSchema for Sv57 {
     fn page_levels() -> &'static [PageLevel] {
         &PAGE_LEVELS
     }
+
+    #[inline]
+    fn satp_mode() -> SatpMode {
+        SatpMode::Sv57
+    }
 }
@@ -1,9 +1,11 @@
 use rv64::reg::{self, RegisterRW};
 
+pub mod asid;
 pub mod def;
 pub mod interrupt;
 pub mod trampoline;
 pub mod trap;
+pub mod uaccess;
 pub mod vm;
 
 #[inline]
@@ -28,6 +30,27 @@ pub fn halt() -> ! {
     }
 }
 
+/// Lets the kernel load and store through user mappings while it
+/// lives, by setting `sstatus.SUM`.
+/// `kernel_trap` restores `sstatus`, so it survives being preempted.
+pub struct UserAccess(bool);
+
+#[inline]
+pub fn user_access() -> UserAccess {
+    let was_on = reg::sstatus.read().sum();
+    unsafe { reg::sstatus.set_sum() };
+    UserAccess(was_on)
+}
+
+impl Drop for UserAccess {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.0 {
+            unsafe { reg::sstatus.clear_sum() };
+        }
+    }
+}
+
 /// Must be called with interrupts disabled,
 /// to prevent race with process being moved
 /// to a different CPU.
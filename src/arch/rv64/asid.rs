@@ -0,0 +1,161 @@
+//! Address space identifiers.
+//!
+//! Each user page table runs with its own ASID, so its TLB entries
+//! survive traps into the kernel and switches to other processes,
+//! and a change to it only needs to flush the entries it touched.
+//! ASIDs are handed out in generations: once a generation runs out,
+//! a new one starts, and each hart flushes its whole TLB before it
+//! next runs with an ASID of the new generation.
+//! ASID 0 is the kernel's. Without ASIDs, user page tables run with
+//! ASID 0 too, and the trampoline flushes the TLB on every switch.
+
+use super::cpuid;
+use crate::{proc::CPU, spinlock::Mutex, NCPU};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rv64::{
+    insn,
+    reg::{self, SatpMode},
+};
+
+/// Bits of an ASID in `Asid::id`, above them is its generation
+const ASID_BITS: usize = 16;
+
+/// Flush a whole address space rather than more pages than this
+const FLUSH_PAGES_MAX: usize = 32;
+
+/// Largest ASID of the harts, 0 if they have none
+static MAX_ASID: AtomicUsize = AtomicUsize::new(0);
+/// Current generation, only changed with `NEXT` held
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+/// Generation each hart last flushed its TLB for
+static HART_GENERATION: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+/// Next free ASID of the current generation
+static NEXT: Mutex<usize> = Mutex::new(1, "asid");
+
+#[inline]
+fn asid_of(id: usize) -> usize {
+    id & ((1 << ASID_BITS) - 1)
+}
+
+#[inline]
+fn generation_of(id: usize) -> usize {
+    id >> ASID_BITS
+}
+
+/// Find out how many ASID bits the hart implements, by writing
+/// all ones to the ASID field of `satp` and reading it back.
+///
+/// # Safety
+///
+/// Must be called in machine mode, where `satp` doesn't translate.
+pub unsafe fn probe(mode: SatpMode) {
+    reg::satp.set(mode, usize::MAX, 0);
+    let max = reg::satp.asid();
+    reg::satp.set(SatpMode::Bare, 0, 0);
+    MAX_ASID.store(max, Ordering::Relaxed);
+}
+
+/// Largest ASID, 0 if there are none
+pub fn max_asid() -> usize {
+    MAX_ASID.load(Ordering::Relaxed)
+}
+
+/// ASID of a user page table, and the harts its TLB entries may be on
+#[derive(Debug)]
+pub struct Asid {
+    /// Generation and ASID it last ran with, generation 0 if never
+    id: AtomicUsize,
+    /// Harts that ran it with `id`
+    harts: AtomicUsize,
+    /// Harts that must flush its entries before they run it again
+    stale: AtomicUsize,
+}
+
+impl Default for Asid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Asid {
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicUsize::new(0),
+            harts: AtomicUsize::new(0),
+            stale: AtomicUsize::new(0),
+        }
+    }
+
+    /// The ASID to run this address space with on this hart.
+    /// Takes a new one if its generation is over, and flushes what
+    /// the TLB may still hold from an earlier use of it.
+    /// Must be called with interrupts off.
+    pub fn activate(&self) -> usize {
+        let max = max_asid();
+        if max == 0 {
+            return 0;
+        }
+
+        let hart = cpuid();
+        let mut id = self.id.load(Ordering::Relaxed);
+        let generation = GENERATION.load(Ordering::Acquire);
+        if generation_of(id) != generation
+            || HART_GENERATION[hart].load(Ordering::Relaxed) != generation
+        {
+            let mut next = NEXT.lock();
+            let mut generation = GENERATION.load(Ordering::Relaxed);
+            if generation_of(id) != generation {
+                if *next > max {
+                    // out of ASIDs, start over in a new generation.
+                    generation += 1;
+                    GENERATION.store(generation, Ordering::Release);
+                    *next = 1;
+                }
+                id = (generation << ASID_BITS) | *next;
+                *next += 1;
+                self.id.store(id, Ordering::Relaxed);
+                self.harts.store(0, Ordering::Relaxed);
+                self.stale.store(0, Ordering::Relaxed);
+            }
+            if HART_GENERATION[hart].load(Ordering::Relaxed) != generation {
+                // the TLB may hold entries of an earlier
+                // generation's owner of any ASID.
+                insn::sfence_vma();
+                HART_GENERATION[hart].store(generation, Ordering::Relaxed);
+            }
+        }
+
+        let bit = 1 << hart;
+        self.harts.fetch_or(bit, Ordering::Relaxed);
+        if self.stale.fetch_and(!bit, Ordering::Relaxed) & bit != 0 {
+            insn::sfence_vma_asid(asid_of(id));
+        }
+        asid_of(id)
+    }
+
+    /// Flush the TLB entries of `[va, va + size)` after a change to the
+    /// page table: now on this hart, and on the other harts that ran
+    /// it before they run it again.
+    pub fn flush(&self, va: usize, size: usize) {
+        if max_asid() == 0 {
+            // nothing of it outlives a switch to the kernel.
+            return;
+        }
+
+        let _int_lock = unsafe { CPU::push_off() };
+        let bit = 1 << cpuid();
+        let harts = self.harts.load(Ordering::Relaxed);
+        if harts & bit != 0 {
+            let asid = asid_of(self.id.load(Ordering::Relaxed));
+            let npages = size.div_ceil(super::def::PG_SIZE);
+            if npages > FLUSH_PAGES_MAX {
+                insn::sfence_vma_asid(asid);
+            } else {
+                for i in 0..npages {
+                    insn::sfence_vma_va_asid(va + i * super::def::PG_SIZE, asid);
+                }
+            }
+        }
+        self.stale.fetch_or(harts & !bit, Ordering::Relaxed);
+    }
+}
@@ -46,7 +46,18 @@ pub const fn px(level: usize, va: usize) -> usize {
     ((va) >> pxshift(level)) & PX_MASK
 }
 
-pub const MAX_VA: usize = 1 << (9 + 9 + 9 + 12 - 1);
+/// Paging schema of the kernel and user page tables,
+/// Sv39, Sv48 or Sv57 as selected at boot by `vm::select_paging_mode()`.
+pub type Schema = rv64::vm::Dynamic;
+
+/// one beyond the highest possible virtual address.
+/// MAXVA is actually one bit less than the max allowed by
+/// the paging schema, to avoid having to sign-extend virtual
+/// addresses that have the high bit set.
+#[inline(always)]
+pub fn max_va() -> usize {
+    (usize::from(<Schema as rv64::vm::PagingSchema>::max_va()) + 1) >> 1
+}
 
 /// Physical memory layout
 
@@ -118,15 +129,23 @@ pub const fn plic_sclaim(hart: usize) -> usize {
 
 /// map the trampoline page to the highest address,
 /// in both user and kernel space.
-pub const TRAMPOLINE: usize = MAX_VA - PG_SIZE;
+#[inline(always)]
+pub fn trampoline() -> usize {
+    max_va() - PG_SIZE
+}
 
 /// map kernel stacks beneath the trampoline,
 /// each surrounded by invalid guard pages.
 #[inline(always)]
-pub const fn kstack(p: usize) -> usize {
-    TRAMPOLINE - (p + 1) * 2 * PG_SIZE
+pub fn kstack(p: usize) -> usize {
+    trampoline() - (p + 1) * 2 * PG_SIZE
 }
 
+/// top of user memory, except for mapped areas.
+/// a process's kernel page table maps the user memory below
+/// it too, at the same addresses, beneath the devices.
+pub const USER_TOP: usize = PLIC;
+
 /// User memory layout.
 /// Address zero first:
 ///   text
@@ -136,4 +155,7 @@ pub const fn kstack(p: usize) -> usize {
 ///   ...
 ///   TRAPFRAME (p->trapframe, used by the trampoline)
 ///   TRAMPOLINE (the same page as in the kernel)
-pub const TRAP_FRAME: usize = TRAMPOLINE - PG_SIZE;
+#[inline(always)]
+pub fn trap_frame() -> usize {
+    trampoline() - PG_SIZE
+}
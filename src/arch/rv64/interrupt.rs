@@ -1,7 +1,7 @@
 //! the riscv Platform Level Interrupt Controller (PLIC).
 
 use crate::arch::def;
-use crate::io::{BaseIO, ScratchIO};
+use crate::io::{uart, virtio, BaseIO, ScratchIO};
 use crate::{arch, println, proc};
 use rv64::reg::{self, RegisterRW};
 
@@ -57,14 +57,11 @@ pub fn dev_intr() -> Source {
         // Interrupt
         use reg::ScauseInterrupt;
 
-        let irq = plic_claim(hart);
         match scause.interrupt() {
             ScauseInterrupt::SupervisorSoftwareInterrupt => {
                 // Software interrupt from a machine-mode timer interrupt,
                 // forwarded by timervec in kernelvec.S.
-                if hart == 0 {
-                    proc::timer_interrupt();
-                }
+                proc::timer_interrupt();
 
                 // Acknowledge the software interrupt by clearing
                 // the SSIP bit in sip.
@@ -75,14 +72,17 @@ pub fn dev_intr() -> Source {
             ScauseInterrupt::SupervisorExternalInterrupt => {
                 // This is a supervisor external interrupt, via PLIC.
                 // irq indicates which device interrupted.
+                let irq = plic_claim(hart);
 
-                #[allow(unused_variables)]
                 match irq as usize {
                     def::UART0_IRQ => {
-                        // TODO: uartintr();
+                        uart::intr();
                     }
                     def::VIRTIO0_IRQ => {
-                        // TODO: virtio_disk_intr();
+                        virtio::disk::intr();
+                    }
+                    0 => {
+                        // Another hart claimed it first.
                     }
                     irq => {
                         println!("unexpected interrupt irq={}", irq);
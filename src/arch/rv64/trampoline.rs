@@ -77,8 +77,15 @@ uservec:
 
         # restore kernel page table from p->trapframe->kernel_satp
         ld t1, 0(a0)
-        csrw satp, t1
+        csrrw t2, satp, t1
+
+        # the user page table has an ASID of its own, unless the
+        # hart has none, and then its TLB entries must go.
+        slli t2, t2, 4
+        srli t2, t2, 48
+        bnez t2, 1f
         sfence.vma zero, zero
+1:
 
         # a0 is no longer valid, since the kernel page
         # table does not specially map p->tf.
@@ -95,8 +102,14 @@ userret:
         # a1: user page table, for satp.
 
         # switch to the user page table.
+        # usertrapret() flushed what's stale of its ASID,
+        # unless it runs with the kernel's ASID 0.
         csrw satp, a1
+        slli t0, a1, 4
+        srli t0, t0, 48
+        bnez t0, 1f
         sfence.vma zero, zero
+1:
 
         # put the saved user a0 in sscratch, so we
         # can swap it with our a0 (TRAPFRAME) in the last step.
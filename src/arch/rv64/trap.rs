@@ -1,7 +1,6 @@
-use super::def::{TRAMPOLINE, TRAP_FRAME};
-use super::{def, interrupt, intr_off, vm};
+use super::{def, interrupt, intr_off, uaccess, vm};
 use crate::proc::{State, CPU};
-use crate::{arch, println};
+use crate::{arch, println, syscall};
 use core::{arch::global_asm, panic};
 use rv64::reg::{self, RegisterRO, RegisterRW};
 use rv64::BitFlagOps;
@@ -168,7 +167,7 @@ global_asm!(
 
 #[no_mangle]
 extern "C" fn kernel_trap() {
-    let sepc_v = reg::sepc.read();
+    let mut sepc_v = reg::sepc.read();
     let sstatus_v = reg::sstatus.read();
 
     assert!(
@@ -180,12 +179,23 @@ extern "C" fn kernel_trap() {
     use interrupt::Source;
     match interrupt::dev_intr() {
         Source::Unknown(scause) => {
-            panic!(
-                "kernel_trap: scause: {:x?}, sep: {:x?}, stval: {:x?}",
-                scause,
-                reg::sepc.read(),
-                reg::stval.read()
-            );
+            let scause_v: usize = scause.into();
+            match uaccess::fixup(sepc_v) {
+                // load or store page fault in an access to user memory:
+                // fault the page in and retry, or make the access fail.
+                Some(fixup) if matches!(scause_v, 13 | 15) => {
+                    let p = unsafe { CPU::this_proc_ref() };
+                    if !p.user_access_fault(reg::stval.read(), scause_v == 15) {
+                        sepc_v = fixup;
+                    }
+                }
+                _ => panic!(
+                    "kernel_trap: scause: {:x?}, sep: {:x?}, stval: {:x?}",
+                    scause,
+                    reg::sepc.read(),
+                    reg::stval.read()
+                ),
+            }
         }
         Source::Timer => {
             // give up the CPU if this is a timer interrupt.
@@ -214,7 +224,7 @@ extern "C" fn kernel_trap() {
 /// called from trampoline.S
 extern "C" fn user_trap() {
     extern "C" {
-        fn kernelvec();
+        fn kernel_vec();
     }
 
     assert_eq!(
@@ -228,7 +238,7 @@ extern "C" fn user_trap() {
     unsafe {
         // send interrupts and exceptions to kerneltrap(),
         // since we're now in the kernel.
-        reg::stvec.write((kernelvec as usize).into());
+        reg::stvec.write((kernel_vec as usize).into());
 
         let p = CPU::this_proc_ref();
         let trapframe = p.trapframe().unwrap_unchecked().as_mut();
@@ -249,7 +259,20 @@ extern "C" fn user_trap() {
                 // so don't enable until done with those registers.
                 arch::intr_on();
 
-                // TODO: syscall()
+                syscall::syscall();
+            }
+            // load or store page fault
+            scause_v @ (13 | 15) => {
+                let va = reg::stval.read();
+                if p.page_fault(va, scause_v == 15).is_err() {
+                    println!(
+                        "user_trap: page fault at {:#x}, scause={:x?}, pid={}",
+                        va,
+                        scause_v,
+                        p.pid().unwrap()
+                    );
+                    p.set_killed(true);
+                }
             }
             scause_v => {
                 which_dev = interrupt::dev_intr();
@@ -291,14 +314,15 @@ pub extern "C" fn user_trap_ret() {
 
     unsafe {
         // send syscalls, interrupts, and exceptions to trampoline.S
-        reg::stvec.write((TRAMPOLINE + (vm::uservec() - vm::trampoline())).into());
+        reg::stvec.write((def::trampoline() + (vm::uservec() - vm::trampoline())).into());
 
         // set up trapframe values that uservec will need when
         // the process next re-enters the kernel.
         let p = CPU::this_proc_ref();
+        let pagetable = p.pagetable();
         let trapframe = p.trapframe().unwrap_unchecked().as_mut();
-        trapframe.kernel_satp = reg::satp.read(); // kernel page table
-        trapframe.kernel_sp = p.kstack(); // process's kernel stack
+        trapframe.kernel_satp = pagetable.kernel_satp(); // process's kernel page table
+        trapframe.kernel_sp = p.kstack() + def::PG_SIZE; // process's kernel stack
         trapframe.kernel_trap = user_trap as usize;
         trapframe.kernel_hartid = arch::cpuid(); // hartid for cpuid()
 
@@ -310,6 +334,7 @@ pub extern "C" fn user_trap_ret() {
             reg::sstatus
                 .read()
                 .clear_mask(&reg::sstatus::SPP) // clear SPP to 0 for user mode
+                .clear_mask(&reg::sstatus::SUM) // no user access in the kernel by default
                 .set_mask(&reg::sstatus::SPIE) // enable interrupts in user mode
                 .into(),
         );
@@ -317,14 +342,16 @@ pub extern "C" fn user_trap_ret() {
         // set S Exception Program Counter to the saved user pc.
         reg::sepc.write(trapframe.epc);
 
-        // tell trampoline.S the user page table to switch to.
-        let satp_v = reg::satp.make(reg::SatpMode::Sv39, 0, p.pagetable().into());
+        // tell trampoline.S the user page table to switch to,
+        // and the ASID to run it with.
+        let asid = pagetable.asid().activate();
+        let satp_v = reg::satp.make(def::Schema::mode(), asid, pagetable.into());
 
         // jump to trampoline.S at the top of memory, which
         // switches to the user page table, restores user registers,
         // and switches to user mode with sret.
         let func: extern "C" fn(usize, usize) =
-            core::mem::transmute(TRAMPOLINE + (vm::userret() - vm::trampoline()));
-        func(TRAP_FRAME, satp_v.into());
+            core::mem::transmute(def::trampoline() + (vm::userret() - vm::trampoline()));
+        func(def::trap_frame(), satp_v.into());
     }
 }
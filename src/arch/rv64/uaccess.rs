@@ -0,0 +1,145 @@
+//! Copies between kernel and user memory through the process's
+//! kernel page table, that can fault.
+//!
+//! Each load or store of user memory in these routines has an entry
+//! in the fixup table, with where to resume if it faults. `kernel_trap`
+//! looks up the faulting pc there, and when the page can't be faulted
+//! in, resumes at the fixup, which makes the copy fail.
+
+use super::user_access;
+use core::ptr::addr_of;
+
+core::arch::global_asm!(
+    "
+        .section .text.uaccess
+        .balign 4
+
+        # __copy_user(dst, src, len) -> bytes not copied
+        # one of dst and src is a user address.
+.globl __copy_user
+__copy_user:
+        # 8 bytes at a time while both are aligned.
+        or t1, a0, a1
+        andi t1, t1, 7
+        bnez t1, 2f
+1:
+        li t1, 8
+        bltu a2, t1, 2f
+.Lcopy_user_ld:
+        ld t0, 0(a1)
+.Lcopy_user_sd:
+        sd t0, 0(a0)
+        addi a0, a0, 8
+        addi a1, a1, 8
+        addi a2, a2, -8
+        j 1b
+2:
+        beqz a2, .Lcopy_user_fault
+.Lcopy_user_lb:
+        lbu t0, 0(a1)
+.Lcopy_user_sb:
+        sb t0, 0(a0)
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+        j 2b
+.Lcopy_user_fault:
+        mv a0, a2
+        ret
+
+        # __copy_user_str(dst, src, max) -> length, or -1 on a fault
+        # copy from user src up to and including a '\\0', at most max
+        # bytes. returns the length before the '\\0', max if none.
+.globl __copy_user_str
+__copy_user_str:
+        mv t1, a2
+3:
+        beqz a2, 4f
+.Lcopy_user_str_lb:
+        lbu t0, 0(a1)
+        sb t0, 0(a0)
+        beqz t0, 4f
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+        j 3b
+4:
+        sub a0, t1, a2
+        ret
+.Lcopy_user_str_fault:
+        li a0, -1
+        ret
+
+        # the fixup table: faulting instruction, where to resume.
+        .pushsection .rodata.uaccess, \"a\"
+        .balign 8
+.globl __uaccess_fixups_start
+__uaccess_fixups_start:
+        .dword .Lcopy_user_ld, .Lcopy_user_fault
+        .dword .Lcopy_user_sd, .Lcopy_user_fault
+        .dword .Lcopy_user_lb, .Lcopy_user_fault
+        .dword .Lcopy_user_sb, .Lcopy_user_fault
+        .dword .Lcopy_user_str_lb, .Lcopy_user_str_fault
+.globl __uaccess_fixups_end
+__uaccess_fixups_end:
+        .popsection
+"
+);
+
+#[repr(C)]
+struct Fixup {
+    insn: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    static __uaccess_fixups_start: Fixup;
+    static __uaccess_fixups_end: Fixup;
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_user_str(dst: *mut u8, src: *const u8, max: usize) -> isize;
+}
+
+/// A user access faulted on a page the kernel couldn't fault in
+#[derive(Debug, Clone, Copy)]
+pub struct UserFault;
+
+/// Where to resume after a fault at `pc`, if it's in a user access
+pub fn fixup(pc: usize) -> Option<usize> {
+    let fixups = unsafe {
+        let start = addr_of!(__uaccess_fixups_start);
+        let end = addr_of!(__uaccess_fixups_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    fixups.iter().find(|f| f.insn == pc).map(|f| f.fixup)
+}
+
+/// Copy `len` bytes from `src` to `dst`, one of them in user memory
+/// mapped by the process's kernel page table.
+///
+/// # Safety
+///
+/// That page table must be in use, and the user side below
+/// `USER_TOP`. The kernel side must be valid for `len` bytes: a fault
+/// on it would be taken for one on the user side.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UserFault> {
+    let _user = user_access();
+    match __copy_user(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// Copy the string at user address `src` to `dst`, with its '\0' if
+/// there's one in the first `max` bytes. Returns its length, or `max`
+/// if it's longer.
+///
+/// # Safety
+///
+/// Like `copy_user`, with `dst` valid for `max` bytes.
+pub unsafe fn copy_user_str(dst: *mut u8, src: *const u8, max: usize) -> Result<usize, UserFault> {
+    let _user = user_access();
+    match __copy_user_str(dst, src, max) {
+        -1 => Err(UserFault),
+        len => Ok(len as usize),
+    }
+}
@@ -1,9 +1,13 @@
 use super::def;
-use crate::{mem::alloc::ALLOCATOR, println, proc};
+use crate::{
+    mem::alloc::{kalloc, kfree, Kalloc, ALLOCATOR},
+    println, proc,
+};
 use core::ptr::addr_of;
 use rv64::{
-    insn, reg,
-    vm::{PageLevel, PageTableError, PhysAddr, PteFlags, VirtAddr, PTE},
+    insn,
+    reg::{self, RegisterRW, SatpMode},
+    vm::{PageLevel, PageTableError, PagingSchema, PhysAddr, PteFlags, VirtAddr, PTE},
 };
 
 macro_rules! addr_reader {
@@ -28,7 +32,7 @@ addr_reader!(trampoline, trampoline);
 addr_reader!(uservec, uservec);
 addr_reader!(userret, userret);
 
-pub type PageTable = rv64::vm::PageTable<rv64::vm::Sv39>;
+pub type PageTable = rv64::vm::PageTable<def::Schema>;
 
 static mut KPGTBL: *mut PageTable = core::ptr::null_mut();
 
@@ -58,10 +62,115 @@ pub unsafe fn map_pages(
     (*KPGTBL).map_pages(va, size, pa, perm.into(), alloc)
 }
 
+/// Free a page table allocated with `Kalloc`, like the user ones.
 pub unsafe fn free_pagetable(tbl: *mut PageTable) {
-    let alloc = &*addr_of!(ALLOCATOR);
-    (*tbl).free_walk(alloc);
-    ALLOCATOR.kfree(tbl);
+    (*tbl).free_walk(&Kalloc);
+    kfree(tbl);
+}
+
+/// Level-1 table of a page table, mapping the first 2M pages.
+/// `None` if it has none.
+unsafe fn first_level1(tbl: *mut PageTable) -> Option<*mut PageTable> {
+    let (pl, pte) = (*tbl).walk(0, 1, None::<&Kalloc>).ok()?;
+    (pl.page_size() == def::Schema::page_levels()[1].page_size())
+        .then_some(pte as *mut PTE as *mut PageTable)
+}
+
+/// Number of level-1 PTEs mapping user memory in a
+/// process's kernel page table, below `USER_TOP`
+fn user_level1_ptes() -> usize {
+    def::USER_TOP / def::Schema::page_levels()[1].page_size()
+}
+
+/// Create a kernel page table for a process. It shares all of the
+/// kernel page table's pages, but the ones leading to the first
+/// level-1 table, which it copies so that the user part of that
+/// one can be filled in by `sync_user_mappings`.
+pub fn proc_kernel_pagetable() -> Option<*mut PageTable> {
+    unsafe {
+        let mut src = KPGTBL;
+        let mut tables = [core::ptr::null_mut::<PageTable>(); 5];
+        let depth = def::Schema::page_levels().len() - 1;
+        for i in 0..depth {
+            if i > 0 {
+                // the devices are mapped in the first 1G,
+                // so there's always a table below.
+                src = (&*src)[0].addr().as_mut_ptr::<PageTable>();
+            }
+            let Some(page) = kalloc(false) else {
+                tables[..i].iter().for_each(|&t| kfree(t));
+                return None;
+            };
+            let table = page.as_mut_ptr::<PageTable>();
+            core::ptr::copy_nonoverlapping(src, table, 1);
+            if i > 0 {
+                let flags = (&*tables[i - 1])[0].flags();
+                (&mut *tables[i - 1])[0] = PTE::new(page, flags);
+            }
+            tables[i] = table;
+        }
+        let user = &mut *tables[depth - 1];
+        (0..user_level1_ptes()).for_each(|i| user[i] = PTE::new_invalid());
+        Some(tables[0])
+    }
+}
+
+/// Copy the level-1 PTEs of user memory below `USER_TOP` from a
+/// user page table to the process's kernel page table, after the
+/// user page table got or dropped a table or a superpage there.
+/// The pages of the tables they point to are shared by both.
+///
+/// # Safety
+///
+/// `kpt` must come from `proc_kernel_pagetable`, and `upt` be the
+/// user page table of the same process.
+pub unsafe fn sync_user_mappings(kpt: *mut PageTable, upt: *mut PageTable) {
+    let kernel = first_level1(kpt).expect("sync_user_mappings: no level-1 table");
+    let user = first_level1(upt);
+    for i in 0..user_level1_ptes() {
+        (&mut *kernel)[i] = match user {
+            Some(user) => (&*user)[i],
+            None => PTE::new_invalid(),
+        };
+    }
+}
+
+/// Free a page table from `proc_kernel_pagetable`, but not the
+/// user tables it shares, which belong to the user page table.
+///
+/// # Safety
+///
+/// No hart may be using `kpt` anymore, and it's not used after.
+pub unsafe fn free_proc_kernel_pagetable(kpt: *mut PageTable) {
+    let depth = def::Schema::page_levels().len() - 1;
+    let mut table = kpt;
+    for _ in 0..depth {
+        let next = (&*table)[0].addr().as_mut_ptr::<PageTable>();
+        kfree(table);
+        table = next;
+    }
+}
+
+/// Switch this hart to the page table of `satp_v`.
+/// Without ASIDs, page tables run with the kernel's ASID 0,
+/// and nothing in the TLB can be trusted across the switch.
+///
+/// # Safety
+///
+/// `satp_v` must be a page table that maps the kernel like the
+/// kernel page table, at least the code and stack in use, with an
+/// ASID from `Asid::activate` on this hart, or 0.
+pub unsafe fn switch_pagetable(satp_v: usize) {
+    reg::satp.write(satp_v);
+    if reg::satp::ASID.read(satp_v) == 0 {
+        insn::sfence_vma();
+    }
+}
+
+/// The page table this hart is using
+pub fn current_pagetable() -> *mut PageTable {
+    let ppn = reg::satp::PPN.read(reg::satp.read());
+    (ppn << def::PG_SHIFT) as *mut PageTable
 }
 
 pub fn init_mapping() {
@@ -86,7 +195,7 @@ pub fn init_mapping() {
             PhysAddr::from(stack_start),
         );
 
-        let page = ALLOCATOR.kalloc(true).expect("kalloc kpgtbl failed");
+        let page = kalloc(true).expect("kalloc kpgtbl failed");
         let kpt = page.as_mut::<PageTable>().unwrap();
 
         // Erase the static attribute from ALLOCATOR
@@ -155,7 +264,7 @@ pub fn init_mapping() {
         // the highest virtual address in the kernel.
         map_pages_log(
             "trampoline",
-            def::TRAMPOLINE,
+            def::trampoline(),
             def::PG_SIZE,
             trampoline,
             perm_rx,
@@ -170,7 +279,7 @@ pub fn init_mapping() {
                 "kstack",
                 va,
                 def::PG_SIZE,
-                ALLOCATOR.kalloc(true).expect("kalloc stack failed").into(),
+                kalloc(true).expect("kalloc stack failed").into(),
                 perm_rw,
                 "map stack failed",
             );
@@ -180,6 +289,40 @@ pub fn init_mapping() {
     }
 }
 
+/// Pick the paging mode of the kernel and user page tables:
+/// the one forced by the `sv39`, `sv48` or `sv57` feature,
+/// otherwise the widest one the hart supports.
+/// A hart ignores writes of unsupported modes to `satp`,
+/// so try each mode and read it back. Then probe its ASIDs.
+///
+/// # Safety
+///
+/// Must be called in machine mode, where `satp` doesn't translate,
+/// before any page table is built.
+pub unsafe fn select_paging_mode() {
+    let supported = |mode: SatpMode| {
+        reg::satp.set(mode, 0, 0);
+        let ok = reg::satp.mode() == Some(mode);
+        reg::satp.set(SatpMode::Bare, 0, 0);
+        ok
+    };
+    let mode = if cfg!(feature = "sv39") {
+        SatpMode::Sv39
+    } else if cfg!(feature = "sv48") {
+        SatpMode::Sv48
+    } else if cfg!(feature = "sv57") {
+        SatpMode::Sv57
+    } else {
+        [SatpMode::Sv57, SatpMode::Sv48]
+            .into_iter()
+            .find(|&m| supported(m))
+            .unwrap_or(SatpMode::Sv39)
+    };
+    assert!(supported(mode), "paging mode {:?} not supported", mode);
+    def::Schema::select(mode);
+    super::asid::probe(mode);
+}
+
 pub unsafe fn enable_paging() {
     let addr = unsafe { KPGTBL as usize };
     // make sure for RAM mapping is working
@@ -192,10 +335,12 @@ pub unsafe fn enable_paging() {
         })
     );
     println!(
-        "enable kernel page table at 0x{:x} on hart {}",
+        "enable {:?} kernel page table at 0x{:x} on hart {}, {} ASIDs",
+        def::Schema::mode(),
         addr,
-        super::cpuid()
+        super::cpuid(),
+        super::asid::max_asid()
     );
-    unsafe { reg::satp.set(reg::SatpMode::Sv39, 0, addr) }
+    unsafe { reg::satp.set(def::Schema::mode(), 0, addr) }
     insn::sfence_vma();
 }
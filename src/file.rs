@@ -0,0 +1,301 @@
+//! Open files: inodes, devices and pipe ends.
+//!
+//! Every open file is an entry in the global file table,
+//! shared by all file descriptors that refer to it, from
+//! `dup()` or `fork()`. A `FileRef` is a counted reference
+//! to an entry: cloning it is xv6's `filedup()`, dropping it
+//! is `fileclose()`.
+
+use crate::{
+    fs::{log, FsError, Inode, Stat, BSIZE, MAXOPBLOCKS},
+    io::device::{self, DeviceError},
+    pipe::{PipeError, PipeRef},
+    spinlock::Mutex,
+    NFILE,
+};
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// The file wasn't opened for reading
+    NotReadable,
+    /// The file wasn't opened for writing
+    NotWritable,
+    /// Failed to copy from or to the given address
+    BadAddress,
+    /// No driver for the device's major number
+    NoDevice,
+    /// The operation doesn't apply to this kind of file
+    Unsupported,
+    Device(DeviceError),
+    Fs(FsError),
+    Pipe(PipeError),
+}
+
+impl From<DeviceError> for FileError {
+    fn from(err: DeviceError) -> FileError {
+        FileError::Device(err)
+    }
+}
+
+impl From<FsError> for FileError {
+    fn from(err: FsError) -> FileError {
+        FileError::Fs(err)
+    }
+}
+
+impl From<PipeError> for FileError {
+    fn from(err: PipeError) -> FileError {
+        FileError::Pipe(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum FileType {
+    Pipe { pipe: PipeRef, writable: bool },
+    Inode { ip: Inode },
+    Device { ip: Inode, major: i16, minor: i16 },
+}
+
+#[derive(Debug)]
+pub struct File {
+    type_: FileType,
+    readable: bool,
+    writable: bool,
+    /// Offset of an inode file, only changed with the inode locked,
+    /// or of a device file
+    off: AtomicU32,
+}
+
+/// Reference counts of the file table entries
+static FTABLE: Mutex<[usize; NFILE]> = Mutex::new([0; NFILE], "ftable");
+
+/// The file table entries, only changed when their count goes
+/// from or to zero, with `FTABLE` held
+static FILES: [Mutex<Option<File>>; NFILE] = [const { Mutex::new(None, "file") }; NFILE];
+
+/// A counted reference to an open file.
+#[derive(Debug)]
+pub struct FileRef {
+    index: usize,
+}
+
+/// Allocate a file structure.
+/// Return `None` if the file table is full.
+pub fn alloc(type_: FileType, readable: bool, writable: bool) -> Option<FileRef> {
+    let mut ftable = FTABLE.lock();
+    let index = ftable.iter().position(|&refcnt| refcnt == 0)?;
+    ftable[index] = 1;
+    // Nobody else refers to a free entry.
+    unsafe {
+        *FILES[index].get_mut() = Some(File {
+            type_,
+            readable,
+            writable,
+            off: AtomicU32::new(0),
+        })
+    };
+    Some(FileRef { index })
+}
+
+impl Clone for FileRef {
+    /// Increment ref count for file f.
+    fn clone(&self) -> FileRef {
+        let mut ftable = FTABLE.lock();
+        assert!(ftable[self.index] > 0, "filedup");
+        ftable[self.index] += 1;
+        FileRef { index: self.index }
+    }
+}
+
+impl Drop for FileRef {
+    /// Close file f. (Decrement ref count, close when reaches 0.)
+    fn drop(&mut self) {
+        let mut ftable = FTABLE.lock();
+        assert!(ftable[self.index] > 0, "fileclose");
+        ftable[self.index] -= 1;
+        if ftable[self.index] > 0 {
+            return;
+        }
+        let file = unsafe { FILES[self.index].get_mut().take() };
+        drop(ftable);
+
+        match file.map(|f| f.type_) {
+            Some(FileType::Pipe { pipe, writable }) => pipe.close(writable),
+            Some(FileType::Inode { ip }) | Some(FileType::Device { ip, .. }) => {
+                log::begin_op();
+                drop(ip);
+                log::end_op();
+            }
+            None => {}
+        }
+    }
+}
+
+impl Deref for FileRef {
+    type Target = File;
+    fn deref(&self) -> &File {
+        // The entry stays put while we hold a reference to it.
+        unsafe { FILES[self.index].get() }
+            .as_ref()
+            .expect("file: free entry")
+    }
+}
+
+impl File {
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Get metadata about file f.
+    pub fn stat(&self) -> Result<Stat, FileError> {
+        match &self.type_ {
+            FileType::Inode { ip } | FileType::Device { ip, .. } => {
+                let guard = ip.lock();
+                Ok(Stat {
+                    dev: ip.dev() as i32,
+                    ino: ip.inum(),
+                    type_: guard.type_,
+                    nlink: guard.nlink,
+                    pad: 0,
+                    size: guard.size as u64,
+                })
+            }
+            FileType::Pipe { .. } => Err(FileError::Unsupported),
+        }
+    }
+
+    /// Read from file f.
+    /// addr is a user virtual address.
+    pub fn read(&self, addr: usize, n: usize) -> Result<usize, FileError> {
+        if !self.readable {
+            return Err(FileError::NotReadable);
+        }
+
+        match &self.type_ {
+            FileType::Pipe { pipe, .. } => Ok(pipe.read(addr, n)?),
+            FileType::Device { major, minor, .. } => {
+                let dev = device::get(*major).ok_or(FileError::NoDevice)?;
+                let off = self.off.load(Ordering::Relaxed);
+                let r = dev.read(*minor, true, addr, off as usize, n)?;
+                // streams like the console don't care where it wraps.
+                self.off
+                    .store(off.wrapping_add(r as u32), Ordering::Relaxed);
+                Ok(r)
+            }
+            FileType::Inode { ip } => {
+                let guard = ip.lock();
+                let off = self.off.load(Ordering::Relaxed);
+                let r = guard.read(true, addr, off, n as u32)?;
+                self.off.store(off + r as u32, Ordering::Relaxed);
+                Ok(r)
+            }
+        }
+    }
+
+    /// Write to file f.
+    /// addr is a user virtual address.
+    pub fn write(&self, addr: usize, n: usize) -> Result<usize, FileError> {
+        if !self.writable {
+            return Err(FileError::NotWritable);
+        }
+
+        match &self.type_ {
+            FileType::Pipe { pipe, .. } => Ok(pipe.write(addr, n)?),
+            FileType::Device { major, minor, .. } => {
+                let dev = device::get(*major).ok_or(FileError::NoDevice)?;
+                Ok(dev.write(*minor, true, addr, n)?)
+            }
+            FileType::Inode { ip } => self.write_inode(ip, true, addr, None, n),
+        }
+    }
+
+    /// Write `n` bytes from `src` to the inode at offset `off`,
+    /// or at the file offset if `off` is `None`, advancing it.
+    fn write_inode(
+        &self,
+        ip: &Inode,
+        user_src: bool,
+        src: usize,
+        off: Option<u32>,
+        n: usize,
+    ) -> Result<usize, FileError> {
+        // write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size, including
+        // i-node, indirect block, allocation blocks,
+        // and 2 blocks of slop for non-aligned writes.
+        let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+        let mut i = 0;
+        while i < n {
+            let n1 = (n - i).min(max);
+
+            log::begin_op();
+            let r = {
+                let mut guard = ip.lock();
+                let pos = match off {
+                    Some(off) => off + i as u32,
+                    None => self.off.load(Ordering::Relaxed),
+                };
+                let r = guard.write(user_src, src + i, pos, n1 as u32);
+                if let (Ok(r), None) = (r, off) {
+                    self.off.store(pos + r as u32, Ordering::Relaxed);
+                }
+                r
+            };
+            log::end_op();
+
+            let r = r?;
+            if r != n1 {
+                // error from InodeGuard::write
+                break;
+            }
+            i += r;
+        }
+        if i == n {
+            Ok(n)
+        } else {
+            Err(FileError::Fs(FsError::NoSpace))
+        }
+    }
+
+    /// Is it a file in the file system, rather than a device or pipe?
+    pub fn is_inode(&self) -> bool {
+        matches!(self.type_, FileType::Inode { .. })
+    }
+
+    /// Read up to `n` bytes at offset `off` of an inode file into
+    /// the kernel address `dst`, leaving the file offset alone.
+    pub fn read_at(&self, dst: usize, off: usize, n: usize) -> Result<usize, FileError> {
+        match &self.type_ {
+            FileType::Inode { ip } => Ok(ip.lock().read(false, dst, off as u32, n as u32)?),
+            _ => Err(FileError::Unsupported),
+        }
+    }
+
+    /// Write `n` bytes from the kernel address `src` at offset `off`
+    /// of an inode file, leaving the file offset alone.
+    pub fn write_at(&self, src: usize, off: usize, n: usize) -> Result<usize, FileError> {
+        match &self.type_ {
+            FileType::Inode { ip } => self.write_inode(ip, false, src, Some(off as u32), n),
+            _ => Err(FileError::Unsupported),
+        }
+    }
+
+    /// Device specific control request, for device files only.
+    pub fn ioctl(&self, request: usize, arg: usize) -> Result<usize, FileError> {
+        match &self.type_ {
+            FileType::Device { major, minor, .. } => {
+                let dev = device::get(*major).ok_or(FileError::NoDevice)?;
+                Ok(dev.ioctl(*minor, request, arg)?)
+            }
+            FileType::Pipe { .. } | FileType::Inode { .. } => Err(FileError::Unsupported),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! File system implementation. Five layers:
+//!   + Blocks: allocator for raw disk blocks.
+//!   + Log: crash recovery for multi-step updates.
+//!   + Files: inode allocator, reading, writing, metadata.
+//!   + Directories: inode with special contents (list of other inodes!)
+//!   + Names: paths like /usr/rtm/xv6/fs.c for convenient naming.
+//!
+//! This file contains the low-level file system manipulation
+//! routines. The (higher-level) system call implementations
+//! are in `syscall`.
+
+pub mod bio;
+pub mod dir;
+pub mod fcntl;
+pub mod inode;
+mod layout;
+pub mod log;
+mod stat;
+
+pub use dir::{namei, namei_parent};
+pub use inode::{ialloc, iget, Inode, InodeGuard};
+pub use layout::*;
+pub use stat::Stat;
+
+use bio::bread;
+use core::ptr::{addr_of, addr_of_mut};
+
+// all of Stat's padding is explicit.
+unsafe impl crate::mem::uptr::UserData for Stat {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// Failed to copy from or to the given address
+    BadAddress,
+    /// Offset is past the end of the file
+    BadOffset,
+    /// Write would grow the file past `MAXFILE` blocks
+    FileTooLarge,
+    /// A directory entry with the name already exists
+    Exists,
+    /// Ran out of disk blocks or inodes
+    NoSpace,
+}
+
+/// There should be one superblock per disk device, but we run with
+/// only one device
+static mut SB: SuperBlock = SuperBlock {
+    magic: 0,
+    size: 0,
+    nblocks: 0,
+    ninodes: 0,
+    nlog: 0,
+    logstart: 0,
+    inodestart: 0,
+    bmapstart: 0,
+};
+
+pub fn superblock() -> &'static SuperBlock {
+    unsafe { &*addr_of!(SB) }
+}
+
+/// Read the super block.
+fn read_superblock(dev: u32) -> SuperBlock {
+    let bp = bread(dev, 1);
+    SuperBlock::read_from(bp.data())
+}
+
+/// Init fs.
+/// Must run in the context of a regular process, because it sleeps.
+pub fn init(dev: u32) {
+    let sb = read_superblock(dev);
+    assert_eq!(sb.magic, FSMAGIC, "invalid file system");
+    unsafe { *addr_of_mut!(SB) = sb };
+    log::init(dev, superblock());
+}
@@ -0,0 +1,188 @@
+//! Buffer cache.
+//!
+//! The buffer cache holds cached copies of disk block contents.
+//! Caching disk blocks in memory reduces the number of disk reads
+//! and also provides a synchronization point for disk blocks
+//! used by multiple processes.
+//!
+//! Interface:
+//! * To get a buffer for a particular disk block, call `bread`.
+//! * After changing buffer data, call `Buf::write` to write it to disk.
+//! * When done with the buffer, drop it.
+//! * Only one process at a time can use a buffer,
+//!   so do not keep them longer than necessary.
+
+use super::BSIZE;
+use crate::{
+    io::block,
+    sleeplock::{SleepMutex, SleepMutexGuard},
+    spinlock::Mutex,
+    NBUF,
+};
+
+/// Identity of a buffer, protected by `BCACHE`
+#[derive(Debug, Clone, Copy)]
+struct BufMeta {
+    dev: u32,
+    blockno: u32,
+    refcnt: usize,
+    /// When the buffer was last released, to recycle the least recently used one
+    last_used: usize,
+}
+
+#[derive(Debug)]
+struct BCache {
+    bufs: [BufMeta; NBUF],
+    clock: usize,
+}
+
+/// Contents of a buffer, protected by its sleep lock
+#[derive(Debug)]
+struct BufData {
+    valid: bool, // has data been read from disk?
+    data: [u8; BSIZE],
+}
+
+static BCACHE: Mutex<BCache> = Mutex::new(
+    BCache {
+        bufs: [BufMeta {
+            dev: 0,
+            blockno: 0,
+            refcnt: 0,
+            last_used: 0,
+        }; NBUF],
+        clock: 0,
+    },
+    "bcache",
+);
+
+static BUFS: [SleepMutex<BufData>; NBUF] = [const {
+    SleepMutex::new(
+        BufData {
+            valid: false,
+            data: [0; BSIZE],
+        },
+        "buffer",
+    )
+}; NBUF];
+
+/// A locked buffer for one disk block, released on drop.
+#[derive(Debug)]
+pub struct Buf {
+    index: usize,
+    dev: u32,
+    blockno: u32,
+    data: Option<SleepMutexGuard<'static, BufData>>,
+}
+
+/// Look through buffer cache for block on device dev.
+/// If not found, allocate a buffer.
+/// In either case, return locked buffer.
+fn bget(dev: u32, blockno: u32) -> Buf {
+    let index = {
+        let mut bcache = BCACHE.lock();
+
+        // Is the block already cached?
+        let cached = bcache
+            .bufs
+            .iter()
+            .position(|b| b.dev == dev && b.blockno == blockno);
+
+        match cached {
+            Some(i) => {
+                bcache.bufs[i].refcnt += 1;
+                i
+            }
+            None => {
+                // Not cached.
+                // Recycle the least recently used (LRU) unused buffer.
+                let i = bcache
+                    .bufs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| b.refcnt == 0)
+                    .min_by_key(|(_, b)| b.last_used)
+                    .map(|(i, _)| i)
+                    .expect("bget: no buffers");
+                let b = &mut bcache.bufs[i];
+                b.dev = dev;
+                b.blockno = blockno;
+                b.refcnt = 1;
+                // Nobody holds an unused buffer, so it's safe to
+                // touch its contents without the sleep lock.
+                unsafe { BUFS[i].get_mut().valid = false };
+                i
+            }
+        }
+    };
+
+    Buf {
+        index,
+        dev,
+        blockno,
+        data: Some(BUFS[index].lock()),
+    }
+}
+
+/// Return a locked buf with the contents of the indicated block.
+pub fn bread(dev: u32, blockno: u32) -> Buf {
+    let mut b = bget(dev, blockno);
+    let data = b.data.as_mut().unwrap();
+    if !data.valid {
+        block::device(dev).read(blockno, &mut data.data);
+        data.valid = true;
+    }
+    b
+}
+
+impl Buf {
+    pub fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    pub fn blockno(&self) -> u32 {
+        self.blockno
+    }
+
+    pub fn data(&self) -> &[u8; BSIZE] {
+        &self.data.as_ref().unwrap().data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8; BSIZE] {
+        &mut self.data.as_mut().unwrap().data
+    }
+
+    /// Write the buffer's contents to disk.
+    pub fn write(&mut self) {
+        block::device(self.dev).write(self.blockno, self.data());
+    }
+
+    /// Keep the buffer in the cache after it is released,
+    /// used by the log until the block is installed.
+    pub fn pin(&self) {
+        BCACHE.lock().bufs[self.index].refcnt += 1;
+    }
+
+    pub fn unpin(&self) {
+        BCACHE.lock().bufs[self.index].refcnt -= 1;
+    }
+}
+
+impl Drop for Buf {
+    /// Release a locked buffer, and remember when it was last used.
+    fn drop(&mut self) {
+        // Unlock before dropping the reference, so an unused
+        // buffer is never locked.
+        self.data = None;
+
+        let mut bcache = BCACHE.lock();
+        bcache.clock += 1;
+        let clock = bcache.clock;
+        let b = &mut bcache.bufs[self.index];
+        b.refcnt -= 1;
+        if b.refcnt == 0 {
+            // no one is waiting for it.
+            b.last_used = clock;
+        }
+    }
+}
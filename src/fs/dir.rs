@@ -0,0 +1,136 @@
+//! Directories and path names.
+
+use super::{
+    inode::{iget, Inode, InodeGuard},
+    Dirent, FsError, ROOTINO, T_DIR,
+};
+use crate::{proc::CPU, ROOTDEV};
+use core::{mem::size_of, ptr::addr_of, ptr::addr_of_mut};
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+impl InodeGuard<'_> {
+    /// Read the directory entry at offset `off`.
+    fn dirent(&self, off: u32) -> Dirent {
+        let mut de = Dirent::default();
+        let n = self
+            .read(false, addr_of_mut!(de) as usize, off, DIRENT_SIZE)
+            .expect("dirent: read");
+        assert_eq!(n, DIRENT_SIZE as usize, "dirent: short read");
+        de
+    }
+
+    /// Look for a directory entry in a directory.
+    /// If found, return the inode and the byte offset of the entry.
+    pub fn lookup(&self, name: &str) -> Option<(Inode, u32)> {
+        assert_eq!(self.type_, T_DIR, "dirlookup not DIR");
+
+        (0..self.size)
+            .step_by(DIRENT_SIZE as usize)
+            .map(|off| (off, self.dirent(off)))
+            .find(|(_, de)| de.inum != 0 && de.name_eq(name.as_bytes()))
+            // entry matches path element
+            .map(|(off, de)| (iget(self.inode().dev(), de.inum as u32), off))
+    }
+
+    /// Write a new directory entry (name, inum) into the directory.
+    pub fn link(&mut self, name: &str, inum: u32) -> Result<(), FsError> {
+        // Check that name is not present.
+        if self.lookup(name).is_some() {
+            return Err(FsError::Exists);
+        }
+
+        // Look for an empty dirent.
+        let off = (0..self.size)
+            .step_by(DIRENT_SIZE as usize)
+            .find(|&off| self.dirent(off).inum == 0)
+            .unwrap_or(self.size);
+
+        let de = Dirent::new(inum as u16, name.as_bytes());
+        let n = self.write(false, addr_of!(de) as usize, off, DIRENT_SIZE)?;
+        if n != DIRENT_SIZE as usize {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// Is the directory empty except for "." and ".." ?
+    pub fn is_dir_empty(&self) -> bool {
+        (2 * DIRENT_SIZE..self.size)
+            .step_by(DIRENT_SIZE as usize)
+            .all(|off| self.dirent(off).inum == 0)
+    }
+}
+
+/// Copy the next path element from path into name.
+/// Return the element and the rest of the path,
+/// with no leading slashes, so the caller can check
+/// whether the element is the last one.
+/// If no name to remove, return `None`.
+///
+/// Examples:
+///   skip_elem("a/bb/c") = Some(("a", "bb/c"))
+///   skip_elem("///a//bb") = Some(("a", "bb"))
+///   skip_elem("a") = Some(("a", ""))
+///   skip_elem("") = skip_elem("////") = None
+fn skip_elem(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+    let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+    Some((name, rest.trim_start_matches('/')))
+}
+
+/// Look up and return the inode for a path name.
+/// If parent is true, return the inode for the parent and
+/// the final path element.
+/// Must be called inside a transaction since it drops inodes.
+fn namex(path: &str, parent: bool) -> Option<(Inode, &str)> {
+    let mut ip = if path.starts_with('/') {
+        iget(ROOTDEV, ROOTINO)
+    } else {
+        unsafe { CPU::this_proc_ref() }
+            .cwd()
+            .expect("namex: no cwd")
+            .clone()
+    };
+
+    let mut path = path;
+    let mut name = "";
+    while let Some((elem, rest)) = skip_elem(path) {
+        name = elem;
+        path = rest;
+
+        let next = {
+            let dir = ip.lock();
+            if dir.type_ != T_DIR {
+                return None;
+            }
+            if parent && path.is_empty() {
+                // Stop one level early.
+                None
+            } else {
+                Some(dir.lookup(name)?.0)
+            }
+        };
+        match next {
+            Some(next) => ip = next,
+            None => return Some((ip, name)),
+        }
+    }
+
+    if parent {
+        return None;
+    }
+    Some((ip, name))
+}
+
+pub fn namei(path: &str) -> Option<Inode> {
+    namex(path, false).map(|(ip, _)| ip)
+}
+
+/// Return the parent directory of `path` and the final path element.
+pub fn namei_parent(path: &str) -> Option<(Inode, &str)> {
+    namex(path, true)
+}
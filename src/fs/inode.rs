@@ -0,0 +1,524 @@
+//! Inodes.
+//!
+//! An inode describes a single unnamed file.
+//! The inode disk structure holds metadata: the file's type,
+//! its size, the number of links referring to it, and the
+//! list of blocks holding the file's content.
+//!
+//! The inodes are laid out sequentially on disk at block
+//! sb.inodestart. Each inode has a number, indicating its
+//! position on the disk.
+//!
+//! The kernel keeps a table of in-use inodes in memory
+//! to provide a place for synchronizing access
+//! to inodes used by multiple processes. The in-memory
+//! inodes include book-keeping information that is
+//! not stored on disk: refcnt and valid.
+//!
+//! An inode and its in-memory representation go through a
+//! sequence of states before they can be used by the
+//! rest of the file system code.
+//!
+//! * Allocation: an inode is allocated if its type (on disk)
+//!   is non-zero. `ialloc()` allocates, and dropping the last
+//!   `Inode` frees if the reference and link counts have fallen to zero.
+//!
+//! * Referencing in table: an entry in the inode table
+//!   is free if refcnt is zero. Otherwise refcnt tracks
+//!   the number of `Inode` handles to the entry (open
+//!   files and current directories). `iget()` finds or
+//!   creates a table entry and increments its ref; cloning
+//!   an `Inode` increments it too, dropping one decrements it.
+//!
+//! * Valid: the information (type, size, &c) in an inode
+//!   table entry is only correct when valid is true.
+//!   `Inode::lock()` reads the inode from
+//!   the disk and sets valid, while dropping the last
+//!   reference clears valid if refcnt has fallen to zero.
+//!
+//! * Locked: file system code may only examine and modify
+//!   the information in an inode and its content if it
+//!   has first locked the inode.
+//!
+//! Thus a typical sequence is:
+//!   let ip = iget(dev, inum);
+//!   let guard = ip.lock();
+//!   ... examine and modify guard.xxx ...
+//!   drop(guard);
+//!   drop(ip);
+//!
+//! `Inode::lock()` is separate from `iget()` so that system calls can
+//! get a long-term reference to an inode (as for an open file)
+//! and only lock it for short periods (e.g., in read()).
+//! The separation also helps avoid deadlock and races during
+//! pathname lookup. `iget()` increments refcnt so that the inode
+//! stays in the table and handles to it remain valid.
+//!
+//! Dropping an `Inode` may free it on disk, so every handle
+//! must be dropped inside a transaction.
+
+use super::{
+    bio::bread, bmap_bit, log::log_write, superblock, DInode, FsError, OnDisk, BPB, BSIZE, MAXFILE,
+    NDIRECT, NINDIRECT,
+};
+use crate::{
+    println,
+    proc::{either_copy_in, either_copy_out},
+    sleeplock::{SleepMutex, SleepMutexGuard},
+    spinlock::Mutex,
+    NINODE,
+};
+use core::{
+    mem::size_of,
+    ops::{Deref, DerefMut},
+};
+
+/// Identity of an inode table entry, protected by `ITABLE`
+#[derive(Debug, Clone, Copy)]
+struct InodeMeta {
+    dev: u32,
+    inum: u32,
+    refcnt: usize,
+}
+
+/// Copy of a disk inode, protected by its sleep lock
+#[derive(Debug)]
+pub struct InodeData {
+    valid: bool, // inode has been read from disk?
+    pub type_: i16,
+    pub major: i16,
+    pub minor: i16,
+    pub nlink: i16,
+    pub size: u32,
+    pub addrs: [u32; NDIRECT + 1],
+}
+
+static ITABLE: Mutex<[InodeMeta; NINODE]> = Mutex::new(
+    [InodeMeta {
+        dev: 0,
+        inum: 0,
+        refcnt: 0,
+    }; NINODE],
+    "itable",
+);
+
+static INODES: [SleepMutex<InodeData>; NINODE] = [const {
+    SleepMutex::new(
+        InodeData {
+            valid: false,
+            type_: 0,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT + 1],
+        },
+        "inode",
+    )
+}; NINODE];
+
+/// A counted reference to an in-memory inode.
+/// Cloning it is xv6's `idup()`, dropping it is `iput()`.
+#[derive(Debug)]
+pub struct Inode {
+    index: usize,
+    dev: u32,
+    inum: u32,
+}
+
+/// A locked inode, unlocked on drop.
+#[derive(Debug)]
+pub struct InodeGuard<'a> {
+    inode: &'a Inode,
+    data: SleepMutexGuard<'static, InodeData>,
+}
+
+/// Zero a block.
+fn bzero(dev: u32, bno: u32) {
+    let mut bp = bread(dev, bno);
+    bp.data_mut().fill(0);
+    log_write(&bp);
+}
+
+/// Allocate a zeroed disk block.
+/// Return `None` if out of disk space.
+fn balloc(dev: u32) -> Option<u32> {
+    let sb = superblock();
+    for b in (0..sb.size).step_by(BPB) {
+        let mut bp = bread(dev, sb.bblock(b));
+        for bi in 0..(BPB as u32).min(sb.size - b) {
+            let (i, m) = bmap_bit(bi);
+            let byte = &mut bp.data_mut()[i];
+            if *byte & m == 0 {
+                // Is block free?
+                *byte |= m; // Mark block in use.
+                log_write(&bp);
+                drop(bp);
+                bzero(dev, b + bi);
+                return Some(b + bi);
+            }
+        }
+    }
+    println!("balloc: out of blocks");
+    None
+}
+
+/// Free a disk block.
+fn bfree(dev: u32, b: u32) {
+    let mut bp = bread(dev, superblock().bblock(b));
+    let (i, m) = bmap_bit(b);
+    let byte = &mut bp.data_mut()[i];
+    assert!(*byte & m != 0, "freeing free block");
+    *byte &= !m;
+    log_write(&bp);
+}
+
+/// Allocate an inode on device dev.
+/// Mark it as allocated by giving it type `type_`.
+/// Return an unlocked but allocated and referenced inode,
+/// or `None` if there is no free inode.
+pub fn ialloc(dev: u32, type_: i16) -> Option<Inode> {
+    let sb = superblock();
+    for inum in 1..sb.ninodes {
+        let mut bp = bread(dev, sb.iblock(inum));
+        let offset = super::ioffset(inum);
+        let dip = DInode::read_from(&bp.data()[offset..]);
+        if dip.type_ == 0 {
+            // a free inode
+            let dip = DInode {
+                type_,
+                ..Default::default()
+            };
+            dip.write_to(&mut bp.data_mut()[offset..]);
+            log_write(&bp); // mark it allocated on the disk
+            drop(bp);
+            return Some(iget(dev, inum));
+        }
+    }
+    println!("ialloc: no inodes");
+    None
+}
+
+/// Find the inode with number inum on device dev
+/// and return the in-memory copy. Does not lock
+/// the inode and does not read it from disk.
+pub fn iget(dev: u32, inum: u32) -> Inode {
+    let mut itable = ITABLE.lock();
+
+    // Is the inode already in the table?
+    let mut empty = None;
+    for (i, ip) in itable.iter_mut().enumerate() {
+        if ip.refcnt > 0 && ip.dev == dev && ip.inum == inum {
+            ip.refcnt += 1;
+            return Inode {
+                index: i,
+                dev,
+                inum,
+            };
+        }
+        if empty.is_none() && ip.refcnt == 0 {
+            // Remember empty slot.
+            empty = Some(i);
+        }
+    }
+
+    // Recycle an inode entry.
+    let i = empty.expect("iget: no inodes");
+    itable[i] = InodeMeta {
+        dev,
+        inum,
+        refcnt: 1,
+    };
+    // Nobody holds an unreferenced inode, so it's safe to
+    // touch its contents without the sleep lock.
+    unsafe { INODES[i].get_mut().valid = false };
+    Inode {
+        index: i,
+        dev,
+        inum,
+    }
+}
+
+impl Inode {
+    pub fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    pub fn inum(&self) -> u32 {
+        self.inum
+    }
+
+    /// Lock the inode.
+    /// Reads the inode from disk if necessary.
+    pub fn lock(&self) -> InodeGuard<'_> {
+        let mut data = INODES[self.index].lock();
+        if !data.valid {
+            let bp = bread(self.dev, superblock().iblock(self.inum));
+            let dip = DInode::read_from(&bp.data()[super::ioffset(self.inum)..]);
+            data.type_ = dip.type_;
+            data.major = dip.major;
+            data.minor = dip.minor;
+            data.nlink = dip.nlink;
+            data.size = dip.size;
+            data.addrs = dip.addrs;
+            data.valid = true;
+            assert!(data.type_ != 0, "ilock: no type");
+        }
+        InodeGuard { inode: self, data }
+    }
+}
+
+impl Clone for Inode {
+    /// Increment reference count for ip.
+    fn clone(&self) -> Inode {
+        ITABLE.lock()[self.index].refcnt += 1;
+        Inode {
+            index: self.index,
+            dev: self.dev,
+            inum: self.inum,
+        }
+    }
+}
+
+impl Drop for Inode {
+    /// Drop a reference to an in-memory inode.
+    /// If that was the last reference, the inode table entry can
+    /// be recycled.
+    /// If that was the last reference and the inode has no links
+    /// to it, free the inode (and its content) on disk.
+    /// All calls to iput() must be inside a transaction in
+    /// case it has to free the inode.
+    fn drop(&mut self) {
+        let mut itable = ITABLE.lock();
+
+        if itable[self.index].refcnt == 1 {
+            // refcnt == 1 means no other process can have the inode locked,
+            // so this lock() won't block (or deadlock).
+            let data = unsafe { INODES[self.index].get() };
+            if data.valid && data.nlink == 0 {
+                // inode has no links and no other references: truncate and free.
+                let mut ip = InodeGuard {
+                    inode: self,
+                    data: INODES[self.index].lock(),
+                };
+                drop(itable);
+
+                ip.trunc();
+                ip.type_ = 0;
+                ip.update();
+                ip.data.valid = false;
+
+                drop(ip);
+                itable = ITABLE.lock();
+            }
+        }
+
+        itable[self.index].refcnt -= 1;
+    }
+}
+
+impl InodeGuard<'_> {
+    pub fn inode(&self) -> &Inode {
+        self.inode
+    }
+
+    /// Copy a modified in-memory inode to disk.
+    /// Must be called after every change to a field
+    /// that lives on disk.
+    /// Caller must be inside a transaction.
+    pub fn update(&mut self) {
+        let (dev, inum) = (self.inode.dev, self.inode.inum);
+        let mut bp = bread(dev, superblock().iblock(inum));
+        let dip = DInode {
+            type_: self.type_,
+            major: self.major,
+            minor: self.minor,
+            nlink: self.nlink,
+            size: self.size,
+            addrs: self.addrs,
+        };
+        dip.write_to(&mut bp.data_mut()[super::ioffset(inum)..]);
+        log_write(&bp);
+    }
+
+    /// Truncate inode (discard contents).
+    /// Caller must be inside a transaction.
+    pub fn trunc(&mut self) {
+        let dev = self.inode.dev;
+
+        for addr in &mut self.addrs[..NDIRECT] {
+            if *addr != 0 {
+                bfree(dev, *addr);
+                *addr = 0;
+            }
+        }
+
+        if self.addrs[NDIRECT] != 0 {
+            let bp = bread(dev, self.addrs[NDIRECT]);
+            for j in 0..NINDIRECT {
+                let a = u32::read_from(&bp.data()[j * size_of::<u32>()..]);
+                if a != 0 {
+                    bfree(dev, a);
+                }
+            }
+            drop(bp);
+            bfree(dev, self.addrs[NDIRECT]);
+            self.addrs[NDIRECT] = 0;
+        }
+
+        self.size = 0;
+        self.update();
+    }
+
+    /// The content (data) associated with each inode is stored
+    /// in blocks on the disk. The first NDIRECT block numbers
+    /// are listed in addrs[]. The next NINDIRECT blocks are
+    /// listed in block addrs[NDIRECT].
+    ///
+    /// Return the disk block address of the nth block in the inode,
+    /// allocating it if there is no such block.
+    /// Return `None` if out of disk space.
+    fn bmap(&mut self, bn: usize) -> Option<u32> {
+        let dev = self.inode.dev;
+
+        if bn < NDIRECT {
+            if self.addrs[bn] == 0 {
+                self.addrs[bn] = balloc(dev)?;
+            }
+            return Some(self.addrs[bn]);
+        }
+
+        let bn = bn - NDIRECT;
+        assert!(bn < NINDIRECT, "bmap: out of range");
+
+        // Load indirect block, allocating if necessary.
+        if self.addrs[NDIRECT] == 0 {
+            self.addrs[NDIRECT] = balloc(dev)?;
+        }
+        let mut bp = bread(dev, self.addrs[NDIRECT]);
+        let offset = bn * size_of::<u32>();
+        let mut addr = u32::read_from(&bp.data()[offset..]);
+        if addr == 0 {
+            addr = balloc(dev)?;
+            addr.write_to(&mut bp.data_mut()[offset..]);
+            log_write(&bp);
+        }
+        Some(addr)
+    }
+
+    /// Like `bmap`, but don't allocate.
+    /// Return `None` for a hole in the file.
+    fn block(&self, bn: usize) -> Option<u32> {
+        let addr = if bn < NDIRECT {
+            self.addrs[bn]
+        } else {
+            let indirect = self.addrs[NDIRECT];
+            if indirect == 0 {
+                return None;
+            }
+            let bp = bread(self.inode.dev, indirect);
+            u32::read_from(&bp.data()[(bn - NDIRECT) * size_of::<u32>()..])
+        };
+        Some(addr).filter(|&addr| addr != 0)
+    }
+
+    /// Read data from inode.
+    /// If user_dst is true, then dst is a user virtual address;
+    /// otherwise, dst is a kernel address.
+    /// Return the number of bytes read, which is less than `n`
+    /// only at the end of the file.
+    pub fn read(&self, user_dst: bool, dst: usize, off: u32, n: u32) -> Result<usize, FsError> {
+        let size = self.size;
+        if off > size || off.checked_add(n).is_none() {
+            return Ok(0);
+        }
+        let n = n.min(size - off) as usize;
+        let off = off as usize;
+
+        let zeros = [0u8; 64];
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let m = (n - tot).min(BSIZE - pos % BSIZE);
+            let copied = match self.block(pos / BSIZE) {
+                Some(addr) => {
+                    let bp = bread(self.inode.dev, addr);
+                    let src = &bp.data()[pos % BSIZE..pos % BSIZE + m];
+                    either_copy_out(user_dst, dst + tot, src)
+                }
+                // Holes read as zeros.
+                None => (0..m).step_by(zeros.len()).try_for_each(|i| {
+                    let len = (m - i).min(zeros.len());
+                    either_copy_out(user_dst, dst + tot + i, &zeros[..len])
+                }),
+            };
+            copied.map_err(|_| FsError::BadAddress)?;
+            tot += m;
+        }
+        Ok(tot)
+    }
+
+    /// Write data to inode.
+    /// Caller must be inside a transaction.
+    /// If user_src is true, then src is a user virtual address;
+    /// otherwise, src is a kernel address.
+    /// Returns the number of bytes successfully written.
+    /// If the return value is less than the requested n,
+    /// there was an error of some kind.
+    pub fn write(
+        &mut self,
+        user_src: bool,
+        src: usize,
+        off: u32,
+        n: u32,
+    ) -> Result<usize, FsError> {
+        let end = off.checked_add(n).ok_or(FsError::FileTooLarge)?;
+        if off > self.size {
+            return Err(FsError::BadOffset);
+        }
+        if end as usize > MAXFILE * BSIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        let (off, n) = (off as usize, n as usize);
+
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let Some(addr) = self.bmap(pos / BSIZE) else {
+                break;
+            };
+            let m = (n - tot).min(BSIZE - pos % BSIZE);
+            let mut bp = bread(self.inode.dev, addr);
+            let dst = &mut bp.data_mut()[pos % BSIZE..pos % BSIZE + m];
+            if either_copy_in(user_src, dst, src + tot).is_err() {
+                break;
+            }
+            log_write(&bp);
+            tot += m;
+        }
+
+        if off + tot > self.size as usize {
+            self.size = (off + tot) as u32;
+        }
+
+        // write the i-node back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to self.addrs.
+        self.update();
+
+        Ok(tot)
+    }
+}
+
+impl Deref for InodeGuard<'_> {
+    type Target = InodeData;
+    fn deref(&self) -> &InodeData {
+        &self.data
+    }
+}
+
+impl DerefMut for InodeGuard<'_> {
+    fn deref_mut(&mut self) -> &mut InodeData {
+        &mut self.data
+    }
+}
@@ -0,0 +1,258 @@
+//! On-disk file system format.
+//! Both the kernel and `mkfs` (run by `build.rs`) use this file,
+//! so it must only depend on `core`.
+//!
+//! Disk layout:
+//! [ boot block | super block | log | inode blocks |
+//!                                          free bit map | data blocks]
+//!
+//! mkfs computes the super block and builds an initial file system.
+//! The super block describes the disk layout.
+
+use core::mem::size_of;
+
+/// Block size
+pub const BSIZE: usize = 1024;
+/// Size of file system in blocks
+pub const FSSIZE: usize = 2000;
+/// Max # of blocks any FS op writes
+pub const MAXOPBLOCKS: usize = 10;
+/// Max data blocks in on-disk log
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
+/// Number of inodes created by mkfs
+pub const NINODES: usize = 200;
+
+/// Root i-number
+pub const ROOTINO: u32 = 1;
+/// Must equal `SuperBlock::magic`
+pub const FSMAGIC: u32 = 0x10203040;
+
+pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+
+/// Inodes per block.
+pub const IPB: usize = BSIZE / size_of::<DInode>();
+/// Bitmap bits per block
+pub const BPB: usize = BSIZE * 8;
+
+/// Directory is a file containing a sequence of dirent structures.
+pub const DIRSIZ: usize = 14;
+
+// Inode types
+pub const T_DIR: i16 = 1; // Directory
+pub const T_FILE: i16 = 2; // File
+pub const T_DEVICE: i16 = 3; // Device
+
+/// On-disk structures are plain old data,
+/// and can be copied from and to any offset of a block.
+///
+/// # Safety
+///
+/// Only implement this for `repr(C)` types, or primitive integers,
+/// without padding, for which every bit pattern is a valid value:
+/// `read_from` makes one out of whatever is on disk, and `write_to`
+/// copies all of its bytes out.
+pub unsafe trait OnDisk: Copy {
+    fn read_from(buf: &[u8]) -> Self {
+        assert!(buf.len() >= size_of::<Self>(), "read_from: short buffer");
+        unsafe { (buf.as_ptr() as *const Self).read_unaligned() }
+    }
+
+    fn write_to(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= size_of::<Self>(), "write_to: short buffer");
+        unsafe { (buf.as_mut_ptr() as *mut Self).write_unaligned(*self) }
+    }
+}
+
+unsafe impl OnDisk for u32 {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SuperBlock {
+    pub magic: u32,      // Must be FSMAGIC
+    pub size: u32,       // Size of file system image (blocks)
+    pub nblocks: u32,    // Number of data blocks
+    pub ninodes: u32,    // Number of inodes.
+    pub nlog: u32,       // Number of log blocks
+    pub logstart: u32,   // Block number of first log block
+    pub inodestart: u32, // Block number of first inode block
+    pub bmapstart: u32,  // Block number of first free map block
+}
+
+unsafe impl OnDisk for SuperBlock {}
+
+impl SuperBlock {
+    /// Block containing inode i
+    pub const fn iblock(&self, inum: u32) -> u32 {
+        inum / IPB as u32 + self.inodestart
+    }
+
+    /// Block of free map containing bit for block b
+    pub const fn bblock(&self, b: u32) -> u32 {
+        b / BPB as u32 + self.bmapstart
+    }
+}
+
+/// Offset of inode i in its block
+pub const fn ioffset(inum: u32) -> usize {
+    inum as usize % IPB * size_of::<DInode>()
+}
+
+/// Byte of block b's bit in its free map block, and the bit's mask
+pub const fn bmap_bit(b: u32) -> (usize, u8) {
+    let bi = b as usize % BPB;
+    (bi / 8, 1 << (bi % 8))
+}
+
+/// On-disk inode structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DInode {
+    pub type_: i16,                // File type
+    pub major: i16,                // Major device number (T_DEVICE only)
+    pub minor: i16,                // Minor device number (T_DEVICE only)
+    pub nlink: i16,                // Number of links to inode in file system
+    pub size: u32,                 // Size of file (bytes)
+    pub addrs: [u32; NDIRECT + 1], // Data block addresses
+}
+
+unsafe impl OnDisk for DInode {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+unsafe impl OnDisk for Dirent {}
+
+impl Dirent {
+    pub fn new(inum: u16, name: &[u8]) -> Dirent {
+        let mut de = Dirent {
+            inum,
+            name: [0; DIRSIZ],
+        };
+        let len = name.len().min(DIRSIZ);
+        de.name[..len].copy_from_slice(&name[..len]);
+        de
+    }
+
+    /// Name without the trailing NULs
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+
+    /// Compare with a path element, which is truncated to `DIRSIZ` like on disk.
+    pub fn name_eq(&self, name: &[u8]) -> bool {
+        self.name() == &name[..name.len().min(DIRSIZ)]
+    }
+}
+
+// Run on the host with `just test-layout`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: OnDisk>(value: T, off: usize) -> T {
+        let mut buf = [0u8; BSIZE];
+        value.write_to(&mut buf[off..]);
+        T::read_from(&buf[off..])
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size_of::<SuperBlock>(), 32);
+        assert_eq!(size_of::<DInode>(), 64);
+        assert_eq!(size_of::<Dirent>(), 16);
+        assert_eq!(BSIZE % size_of::<DInode>(), 0);
+        assert_eq!(BSIZE % size_of::<Dirent>(), 0);
+    }
+
+    #[test]
+    fn on_disk_round_trip() {
+        let sb = SuperBlock {
+            magic: FSMAGIC,
+            size: FSSIZE as u32,
+            nblocks: 1954,
+            ninodes: NINODES as u32,
+            nlog: LOGSIZE as u32 + 1,
+            logstart: 2,
+            inodestart: 33,
+            bmapstart: 46,
+        };
+        let got = round_trip(sb, 0);
+        assert_eq!(
+            (got.magic, got.size, got.ninodes, got.bmapstart),
+            (sb.magic, sb.size, sb.ninodes, sb.bmapstart)
+        );
+
+        let mut di = DInode {
+            type_: T_DEVICE,
+            major: 1,
+            minor: -1,
+            nlink: 2,
+            size: 12345,
+            ..Default::default()
+        };
+        di.addrs[NDIRECT] = 0xdeadbeef;
+        // unaligned, like at any offset of a block
+        let got = round_trip(di, 3);
+        assert_eq!((got.type_, got.minor, got.size), (T_DEVICE, -1, 12345));
+        assert_eq!(got.addrs, di.addrs);
+
+        assert_eq!(round_trip(0x01020304u32, BSIZE - 4), 0x01020304);
+    }
+
+    #[test]
+    #[should_panic]
+    fn read_from_short_buffer() {
+        DInode::read_from(&[0u8; 8]);
+    }
+
+    #[test]
+    fn inode_position() {
+        let sb = SuperBlock {
+            inodestart: 33,
+            ..Default::default()
+        };
+        assert_eq!(sb.iblock(0), 33);
+        assert_eq!(sb.iblock(IPB as u32 - 1), 33);
+        assert_eq!(sb.iblock(IPB as u32), 34);
+        assert_eq!(ioffset(0), 0);
+        assert_eq!(ioffset(1), size_of::<DInode>());
+        assert_eq!(ioffset(IPB as u32 + 2), 2 * size_of::<DInode>());
+    }
+
+    #[test]
+    fn bitmap_position() {
+        let sb = SuperBlock {
+            bmapstart: 46,
+            ..Default::default()
+        };
+        assert_eq!(sb.bblock(0), 46);
+        assert_eq!(sb.bblock(BPB as u32 - 1), 46);
+        assert_eq!(sb.bblock(BPB as u32), 47);
+        assert_eq!(bmap_bit(0), (0, 1));
+        assert_eq!(bmap_bit(7), (0, 0x80));
+        assert_eq!(bmap_bit(9), (1, 2));
+        assert_eq!(bmap_bit(BPB as u32 + 9), (1, 2));
+        assert_eq!(bmap_bit(BPB as u32 - 1), (BSIZE - 1, 0x80));
+    }
+
+    #[test]
+    fn dirent_name() {
+        let de = Dirent::new(7, b"console");
+        assert_eq!(de.inum, 7);
+        assert_eq!(de.name(), b"console");
+        assert!(de.name_eq(b"console"));
+        assert!(!de.name_eq(b"cons"));
+
+        let long = b"a_name_longer_than_dirsiz";
+        let de = round_trip(Dirent::new(1, long), 5);
+        assert_eq!(de.name(), &long[..DIRSIZ]);
+        assert!(de.name_eq(long));
+    }
+}
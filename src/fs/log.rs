@@ -0,0 +1,230 @@
+//! Simple logging that allows concurrent FS system calls.
+//!
+//! A log transaction contains the updates of multiple FS system
+//! calls. The logging system only commits when there are
+//! no FS system calls active. Thus there is never
+//! any reasoning required about whether a commit might
+//! write an uncommitted system call's updates to disk.
+//!
+//! A system call should call `begin_op()`/`end_op()` to mark
+//! its start and end. Usually `begin_op()` just increments
+//! the count of in-progress FS system calls and returns.
+//! But if it thinks the log is close to running out, it
+//! sleeps until the last outstanding `end_op()` commits.
+//!
+//! The log is a physical re-do log containing disk blocks.
+//! The on-disk log format:
+//!   header block, containing block #s for block A, B, C, ...
+//!   block A
+//!   block B
+//!   block C
+//!   ...
+//! Log appends are synchronous.
+
+use super::{
+    bio::{bread, Buf},
+    OnDisk, SuperBlock, BSIZE, LOGSIZE, MAXOPBLOCKS,
+};
+use crate::{
+    proc::{Proc, CPU},
+    spinlock::Mutex,
+};
+use core::{mem::size_of, ptr::addr_of};
+
+/// Contents of the header block, used for both the on-disk header block
+/// and to keep track in memory of logged block# before commit.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+unsafe impl OnDisk for LogHeader {}
+
+#[derive(Debug)]
+struct Log {
+    start: u32,
+    size: u32,
+    outstanding: usize, // how many FS sys calls are executing.
+    committing: bool,   // in commit(), please wait.
+    dev: u32,
+    lh: LogHeader,
+}
+
+static LOG: Mutex<Log> = Mutex::new(
+    Log {
+        start: 0,
+        size: 0,
+        outstanding: 0,
+        committing: false,
+        dev: 0,
+        lh: LogHeader {
+            n: 0,
+            block: [0; LOGSIZE],
+        },
+    },
+    "log",
+);
+
+/// The channel `begin_op()` sleeps on
+fn chan() -> usize {
+    addr_of!(LOG) as usize
+}
+
+pub fn init(dev: u32, sb: &SuperBlock) {
+    assert!(size_of::<LogHeader>() < BSIZE, "initlog: too big logheader");
+
+    {
+        let mut log = LOG.lock();
+        log.start = sb.logstart;
+        log.size = sb.nlog;
+        log.dev = dev;
+    }
+    recover_from_log();
+}
+
+/// Copy committed blocks from log to their home location
+fn install_trans(recovering: bool) {
+    let (dev, start, lh) = {
+        let log = LOG.lock();
+        (log.dev, log.start, log.lh)
+    };
+    for tail in 0..lh.n {
+        let lbuf = bread(dev, start + tail + 1); // read log block
+        let mut dbuf = bread(dev, lh.block[tail as usize]); // read dst
+        dbuf.data_mut().copy_from_slice(lbuf.data()); // copy block to dst
+        dbuf.write(); // write dst to disk
+        if !recovering {
+            dbuf.unpin();
+        }
+    }
+}
+
+/// Read the log header from disk into the in-memory log header
+fn read_head() {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    let buf = bread(dev, start);
+    let lh = LogHeader::read_from(buf.data());
+    LOG.lock().lh = lh;
+}
+
+/// Write in-memory log header to disk.
+/// This is the true point at which the
+/// current transaction commits.
+fn write_head() {
+    let (dev, start, lh) = {
+        let log = LOG.lock();
+        (log.dev, log.start, log.lh)
+    };
+    let mut buf = bread(dev, start);
+    lh.write_to(buf.data_mut());
+    buf.write();
+}
+
+fn recover_from_log() {
+    read_head();
+    install_trans(true); // if committed, copy from log to disk
+    LOG.lock().lh.n = 0;
+    write_head(); // clear the log
+}
+
+/// Called at the start of each FS system call.
+pub fn begin_op() {
+    let p = unsafe { CPU::this_proc_ref() };
+    let mut log = LOG.lock();
+    loop {
+        if log.committing {
+            log = p.sleep(chan(), log);
+        } else if log.lh.n as usize + (log.outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+            // this op might exhaust log space; wait for commit.
+            log = p.sleep(chan(), log);
+        } else {
+            log.outstanding += 1;
+            break;
+        }
+    }
+}
+
+/// Called at the end of each FS system call.
+/// Commits if this was the last outstanding operation.
+pub fn end_op() {
+    let do_commit = {
+        let mut log = LOG.lock();
+        log.outstanding -= 1;
+        assert!(!log.committing, "log.committing");
+        if log.outstanding == 0 {
+            log.committing = true;
+            true
+        } else {
+            // begin_op() may be waiting for log space,
+            // and decrementing log.outstanding has decreased
+            // the amount of reserved space.
+            Proc::wake_up(chan());
+            false
+        }
+    };
+
+    if do_commit {
+        // call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        commit();
+        let mut log = LOG.lock();
+        log.committing = false;
+        Proc::wake_up(chan());
+    }
+}
+
+/// Copy modified blocks from cache to log.
+fn write_log() {
+    let (dev, start, lh) = {
+        let log = LOG.lock();
+        (log.dev, log.start, log.lh)
+    };
+    for tail in 0..lh.n {
+        let mut to = bread(dev, start + tail + 1); // log block
+        let from = bread(dev, lh.block[tail as usize]); // cache block
+        to.data_mut().copy_from_slice(from.data());
+        to.write(); // write the log
+    }
+}
+
+fn commit() {
+    if LOG.lock().lh.n > 0 {
+        write_log(); // Write modified blocks from cache to log
+        write_head(); // Write header to disk -- the real commit
+        install_trans(false); // Now install writes to home locations
+        LOG.lock().lh.n = 0;
+        write_head(); // Erase the transaction from the log
+    }
+}
+
+/// Caller has modified b->data and is done with the buffer.
+/// Record the block number and pin in the cache by increasing refcnt.
+/// commit()/write_log() will do the disk write.
+///
+/// log_write() replaces bwrite(); a typical use is:
+///   bp = bread(...)
+///   modify bp->data[]
+///   log_write(bp)
+///   brelse(bp)
+pub fn log_write(b: &Buf) {
+    let mut log = LOG.lock();
+    let n = log.lh.n as usize;
+    assert!(
+        n < LOGSIZE && n + 1 < log.size as usize,
+        "too big a transaction"
+    );
+    assert!(log.outstanding >= 1, "log_write outside of trans");
+
+    // log absorption
+    if !log.lh.block[..n].contains(&b.blockno()) {
+        // Add new block to log
+        log.lh.block[n] = b.blockno();
+        b.pin();
+        log.lh.n += 1;
+    }
+}
@@ -0,0 +1,12 @@
+//! File status returned by `fstat`, shared by the kernel and user programs.
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub dev: i32,   // File system's disk device
+    pub ino: u32,   // Inode number
+    pub type_: i16, // Type of file
+    pub nlink: i16, // Number of links to file
+    pub pad: u32,   // Explicit padding, always 0
+    pub size: u64,  // Size of file in bytes
+}
@@ -1,5 +1,10 @@
+pub mod block;
 pub mod console;
+pub mod device;
+pub mod ramdisk;
+pub mod stats;
 pub mod uart;
+pub mod virtio;
 
 pub struct BaseIO<T> {
     base: usize,
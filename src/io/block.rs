@@ -0,0 +1,37 @@
+//! Block devices, which the file system reads and writes a block at a time.
+//! A driver registers its device with a device number,
+//! and the buffer cache looks it up for each disk operation.
+
+use crate::{fs::BSIZE, spinlock::Mutex, NDEV};
+
+pub trait BlockDevice: Sync {
+    /// Read block `blockno` into `data`,
+    /// may sleep until the device is done.
+    fn read(&self, blockno: u32, data: &mut [u8; BSIZE]);
+
+    /// Write `data` to block `blockno`,
+    /// may sleep until the device is done.
+    fn write(&self, blockno: u32, data: &[u8; BSIZE]);
+}
+
+static DEVICES: Mutex<[Option<&'static dyn BlockDevice>; NDEV]> =
+    Mutex::new([None; NDEV], "block_devices");
+
+/// Make `disk` the block device of device number `dev`.
+pub fn register(dev: u32, disk: &'static dyn BlockDevice) {
+    let mut devices = DEVICES.lock();
+    let slot = devices
+        .get_mut(dev as usize)
+        .expect("block::register: bad device number");
+    *slot = Some(disk);
+}
+
+/// The block device with device number `dev`.
+pub fn device(dev: u32) -> &'static dyn BlockDevice {
+    DEVICES
+        .lock()
+        .get(dev as usize)
+        .copied()
+        .flatten()
+        .expect("block::device: no such device")
+}
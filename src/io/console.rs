@@ -1,5 +1,233 @@
-use super::uart;
+//! Console input and output, to the uart.
+//! Reads are line at a time.
+//! Implements special input characters:
+//!   newline -- end of line
+//!   control-h -- backspace
+//!   control-u -- kill line
+//!   control-d -- end of file
+//!   control-p -- print process list
+//!
+//! In raw mode, set with the `CONSOLE_SET_RAW` ioctl, input bytes are
+//! passed to readers as they arrive, without editing or echo.
+
+use super::{
+    device::{self, CharDevice, DeviceError, CONSOLE},
+    uart,
+};
+use crate::{
+    proc::{either_copy_in, either_copy_out, Proc, CPU},
+    spinlock::Mutex,
+};
+use core::ptr::addr_of;
+
+/// `ioctl` request to turn raw mode on (`arg` != 0) or off (`arg` == 0)
+pub const CONSOLE_SET_RAW: usize = 1;
+
+const INPUT_BUF_SIZE: usize = 128;
+
+/// Control-x
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+struct Console {
+    buf: [u8; INPUT_BUF_SIZE],
+    r: usize, // Read index
+    w: usize, // Write index
+    e: usize, // Edit index
+    raw: bool,
+}
+
+static CONS: Mutex<Console> = Mutex::new(
+    Console {
+        buf: [0; INPUT_BUF_SIZE],
+        r: 0,
+        w: 0,
+        e: 0,
+        raw: false,
+    },
+    "cons",
+);
+
+/// What readers sleep on until input arrives
+fn chan() -> usize {
+    addr_of!(CONS) as usize
+}
+
+/// Overwrite the last echoed character with a space.
+fn erase() {
+    uart::putc_sync(b'\x08');
+    uart::putc_sync(b' ');
+    uart::putc_sync(b'\x08');
+}
+
+impl Console {
+    /// Make everything typed so far available to read().
+    fn commit(&mut self) {
+        self.w = self.e;
+        Proc::wake_up(chan());
+    }
+}
+
+struct ConsoleDevice;
+
+static CONSOLE_DEVICE: ConsoleDevice = ConsoleDevice;
+
+impl CharDevice for ConsoleDevice {
+    /// User read()s from the console go here.
+    /// Copy (up to) a whole input line to dst.
+    /// In raw mode, return as soon as some input has been copied.
+    fn read(
+        &self,
+        _minor: i16,
+        user_dst: bool,
+        dst: usize,
+        _off: usize,
+        n: usize,
+    ) -> Result<usize, DeviceError> {
+        let p = unsafe { CPU::this_proc_ref() };
+        let mut cons = CONS.lock();
+        let mut got = 0;
+        while got < n {
+            // wait until interrupt handler has put some
+            // input into cons.buf.
+            while cons.r == cons.w {
+                if cons.raw && got > 0 {
+                    return Ok(got);
+                }
+                if p.killed() {
+                    return Err(DeviceError::Interrupted);
+                }
+                cons = p.sleep(chan(), cons);
+            }
+
+            let c = cons.buf[cons.r % INPUT_BUF_SIZE];
+            cons.r += 1;
+
+            if c == ctrl(b'D') && !cons.raw {
+                // end-of-file
+                if got > 0 {
+                    // Save ^D for next time, to make sure
+                    // caller gets a 0-byte result.
+                    cons.r -= 1;
+                }
+                break;
+            }
+
+            // copy the input byte to the user-space buffer.
+            if either_copy_out(user_dst, dst + got, &[c]).is_err() {
+                break;
+            }
+            got += 1;
+
+            if c == b'\n' && !cons.raw {
+                // a whole line has arrived, return to
+                // the user-level read().
+                break;
+            }
+        }
+        Ok(got)
+    }
+
+    /// User write()s to the console go here.
+    fn write(
+        &self,
+        _minor: i16,
+        user_src: bool,
+        src: usize,
+        n: usize,
+    ) -> Result<usize, DeviceError> {
+        let mut buf = [0u8; 32];
+        let mut written = 0;
+        while written < n {
+            let m = (n - written).min(buf.len());
+            if either_copy_in(user_src, &mut buf[..m], src + written).is_err() {
+                break;
+            }
+            uart::write(&buf[..m]);
+            written += m;
+        }
+        Ok(written)
+    }
+
+    fn ioctl(&self, _minor: i16, request: usize, arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            CONSOLE_SET_RAW => {
+                set_raw(arg != 0);
+                Ok(0)
+            }
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
+}
+
+/// Turn raw mode on or off.
+/// A partly typed line becomes readable as is.
+pub fn set_raw(raw: bool) {
+    let mut cons = CONS.lock();
+    cons.raw = raw;
+    cons.commit();
+}
+
+/// The console input interrupt handler.
+/// uart::intr() calls this for input character.
+/// Do erase/kill processing, append to cons.buf,
+/// wake up read() if a whole line has arrived.
+pub fn intr(c: u8) {
+    let mut cons = CONS.lock();
+
+    if cons.raw {
+        if cons.e - cons.r < INPUT_BUF_SIZE {
+            let e = cons.e;
+            cons.buf[e % INPUT_BUF_SIZE] = c;
+            cons.e += 1;
+            cons.commit();
+        }
+        return;
+    }
+
+    match c {
+        c if c == ctrl(b'P') => {
+            // Print process list.
+            Proc::dump();
+        }
+        c if c == ctrl(b'U') => {
+            // Kill line.
+            while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF_SIZE] != b'\n' {
+                cons.e -= 1;
+                erase();
+            }
+        }
+        b'\x08' | b'\x7f' => {
+            // Backspace, Delete key
+            if cons.e != cons.w {
+                cons.e -= 1;
+                erase();
+            }
+        }
+        0 => {}
+        c if cons.e - cons.r < INPUT_BUF_SIZE => {
+            let c = if c == b'\r' { b'\n' } else { c };
+
+            // echo back to the user.
+            uart::putc_sync(c);
+
+            // store for consumption by read().
+            let e = cons.e;
+            cons.buf[e % INPUT_BUF_SIZE] = c;
+            cons.e += 1;
+
+            if c == b'\n' || c == ctrl(b'D') || cons.e - cons.r == INPUT_BUF_SIZE {
+                // wake up read() if a whole line (or end-of-file)
+                // has arrived.
+                cons.commit();
+            }
+        }
+        _ => {}
+    }
+}
 
 pub fn init() {
     uart::init();
+    device::register(CONSOLE, &CONSOLE_DEVICE);
 }
@@ -0,0 +1,68 @@
+//! Character devices, like the console.
+//! A device file (an inode of type `T_DEVICE`) refers to its driver
+//! by major device number, and the driver gets the minor number
+//! to tell its devices apart.
+
+use crate::{spinlock::Mutex, NDEV};
+
+/// Major device number of the console
+pub const CONSOLE: i16 = 1;
+/// Major device number of the kernel statistics
+pub const STATS: i16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// Failed to copy from or to the given address
+    BadAddress,
+    /// The process was killed while waiting
+    Interrupted,
+    /// The device doesn't support the request
+    Unsupported,
+}
+
+pub trait CharDevice: Sync {
+    /// Read up to `n` bytes into `dst`, which is a user virtual address
+    /// if `user_dst` is true, a kernel address otherwise. `off` is the
+    /// offset of the file, for devices that aren't streams.
+    /// Return the number of bytes read.
+    fn read(
+        &self,
+        minor: i16,
+        user_dst: bool,
+        dst: usize,
+        off: usize,
+        n: usize,
+    ) -> Result<usize, DeviceError>;
+
+    /// Write `n` bytes from `src`, which is a user virtual address
+    /// if `user_src` is true, a kernel address otherwise.
+    /// Return the number of bytes written.
+    fn write(&self, minor: i16, user_src: bool, src: usize, n: usize)
+        -> Result<usize, DeviceError>;
+
+    /// Device specific control request.
+    fn ioctl(&self, _minor: i16, _request: usize, _arg: usize) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+}
+
+/// Map major device number to device functions.
+static DEVSW: Mutex<[Option<&'static dyn CharDevice>; NDEV]> = Mutex::new([None; NDEV], "devsw");
+
+/// Make `dev` the driver of major device number `major`.
+pub fn register(major: i16, dev: &'static dyn CharDevice) {
+    let mut devsw = DEVSW.lock();
+    let slot = usize::try_from(major)
+        .ok()
+        .and_then(|major| devsw.get_mut(major))
+        .expect("device::register: bad major number");
+    *slot = Some(dev);
+}
+
+/// The driver of major device number `major`, if any.
+pub fn get(major: i16) -> Option<&'static dyn CharDevice> {
+    let devsw = DEVSW.lock();
+    usize::try_from(major)
+        .ok()
+        .and_then(|major| devsw.get(major).copied().flatten())
+}
@@ -0,0 +1,60 @@
+//! A block device backed by memory.
+//! The kernel boots with the file system image built by `build.rs`
+//! as its root device.
+
+use super::block::{self, BlockDevice};
+use crate::{
+    fs::{BSIZE, FSSIZE},
+    spinlock::Mutex,
+    ROOTDEV,
+};
+use core::ptr::addr_of_mut;
+
+static mut FS_IMAGE: [u8; FSSIZE * BSIZE] = *include_bytes!(env!("FS_IMG"));
+
+static ROOT_DISK: RamDisk = RamDisk::new(FSSIZE);
+
+pub struct RamDisk {
+    /// Start of the disk, set by `init`
+    disk: Mutex<*mut u8>,
+    nblocks: usize,
+}
+
+impl RamDisk {
+    pub const fn new(nblocks: usize) -> RamDisk {
+        RamDisk {
+            disk: Mutex::new(core::ptr::null_mut(), "ramdisk"),
+            nblocks,
+        }
+    }
+
+    /// Address of block `blockno`, with the disk lock held.
+    fn block(&self, disk: *mut u8, blockno: u32) -> *mut u8 {
+        assert!(!disk.is_null(), "ramdisk: not initialized");
+        assert!(
+            (blockno as usize) < self.nblocks,
+            "ramdisk: blockno too big"
+        );
+        unsafe { disk.add(blockno as usize * BSIZE) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read(&self, blockno: u32, data: &mut [u8; BSIZE]) {
+        let disk = self.disk.lock();
+        let src = self.block(*disk, blockno);
+        unsafe { core::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), BSIZE) };
+    }
+
+    fn write(&self, blockno: u32, data: &[u8; BSIZE]) {
+        let disk = self.disk.lock();
+        let dst = self.block(*disk, blockno);
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, BSIZE) };
+    }
+}
+
+/// Use the embedded file system image as the root device.
+pub fn init() {
+    *ROOT_DISK.disk.lock() = addr_of_mut!(FS_IMAGE) as *mut u8;
+    block::register(ROOTDEV, &ROOT_DISK);
+}
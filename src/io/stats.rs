@@ -0,0 +1,94 @@
+//! Kernel statistics, read from a device file.
+//! Every read formats a fresh snapshot of the page allocator's
+//! free pages and lock contention counters, one lock per line:
+//!   <name> <acquires> <spins>
+//! followed by the kernel heap's caches, 0 for large allocations:
+//!   heap <size> <pages> <inuse> <allocs> <frees>
+//! and the run queues of the online CPUs, with the processes moved
+//! to and from each by load balancing:
+//!   cpu <id> <queued> <migrations in> <migrations out>
+//! and returns its bytes from the file offset on, none once the
+//! offset is past its end, so it can be read to end of file.
+
+use super::device::{self, CharDevice, DeviceError, STATS};
+use crate::{
+    mem::{alloc, slab},
+    proc::{either_copy_out, CPU},
+};
+use core::fmt::{self, Write};
+
+const STATS_BUF_SIZE: usize = 1024;
+
+/// Formats into a fixed buffer, dropping what doesn't fit.
+struct Buf {
+    data: [u8; STATS_BUF_SIZE],
+    len: usize,
+}
+
+impl Write for Buf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+struct StatsDevice;
+
+static STATS_DEVICE: StatsDevice = StatsDevice;
+
+impl CharDevice for StatsDevice {
+    fn read(
+        &self,
+        _minor: i16,
+        user_dst: bool,
+        dst: usize,
+        off: usize,
+        n: usize,
+    ) -> Result<usize, DeviceError> {
+        let mut buf = Buf {
+            data: [0; STATS_BUF_SIZE],
+            len: 0,
+        };
+        let _ = writeln!(buf, "free pages {}", alloc::free_pages());
+        for stats in alloc::lock_stats() {
+            let _ = writeln!(buf, "{} {} {}", stats.name, stats.acquires, stats.spins);
+        }
+        for s in slab::stats() {
+            let _ = writeln!(
+                buf,
+                "heap {} {} {} {} {}",
+                s.size, s.pages, s.inuse, s.allocs, s.frees
+            );
+        }
+        for c in CPU::stats() {
+            let _ = writeln!(
+                buf,
+                "cpu {} {} {} {}",
+                c.id, c.queued, c.migrations_in, c.migrations_out
+            );
+        }
+
+        // read from where the last read of the file stopped, so a
+        // reader sees the end of it.
+        let data = &buf.data[off.min(buf.len)..buf.len];
+        let n = n.min(data.len());
+        either_copy_out(user_dst, dst, &data[..n]).map_err(|_| DeviceError::BadAddress)?;
+        Ok(n)
+    }
+
+    fn write(
+        &self,
+        _minor: i16,
+        _user_src: bool,
+        _src: usize,
+        _n: usize,
+    ) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+}
+
+pub fn init() {
+    device::register(STATS, &STATS_DEVICE);
+}
@@ -1,7 +1,12 @@
-use super::{BaseIO, IO};
-use crate::{arch, proc::CPU, spinlock::Mutex};
+use super::{console, BaseIO, IO};
+use crate::{
+    arch,
+    proc::{Proc, CPU},
+    spinlock::{Mutex, MutexGuard},
+};
 use core::{
     fmt::{Arguments, Write},
+    ptr::addr_of,
     sync::atomic::{AtomicBool, Ordering},
 };
 
@@ -12,7 +17,6 @@ use core::{
 /// address of one of the registers.
 use crate::arch::def::UART0;
 
-static WRITER: Mutex<Writer> = Mutex::new(Writer, "uart0");
 static PANICED: AtomicBool = AtomicBool::new(false);
 
 // the UART control registers.
@@ -61,29 +65,175 @@ pub fn init() {
     IER.write(IER_TX_ENABLE | IER_RX_ENABLE);
 }
 
-struct Writer;
+/// Size of the transmit buffer
+const TX_BUF_SIZE: usize = 32;
+
+/// The transmit output buffer, drained by the UART interrupt.
+struct TxBuf {
+    buf: [u8; TX_BUF_SIZE],
+    w: usize, // write next to buf[w % TX_BUF_SIZE]
+    r: usize, // read next from buf[r % TX_BUF_SIZE]
+}
+
+impl TxBuf {
+    fn is_full(&self) -> bool {
+        self.w == self.r + TX_BUF_SIZE
+    }
 
-impl Writer {
-    fn write_byte(&self, c: u8) {
-        let _guard = unsafe { CPU::push_off() };
-        while (LSR.read() & LSR_TX_IDLE) == 0 {}
-        THR.write(c);
+    /// If the UART is idle, and a character is waiting
+    /// in the transmit buffer, send it.
+    /// Caller must hold the lock.
+    /// Called from both the top- and bottom-half.
+    fn start(&mut self) {
+        loop {
+            if self.w == self.r {
+                // transmit buffer is empty.
+                return;
+            }
+
+            if (LSR.read() & LSR_TX_IDLE) == 0 {
+                // the UART transmit holding register is full,
+                // so we cannot give it another byte.
+                // it will interrupt when it's ready for a new byte.
+                return;
+            }
+
+            let c = self.buf[self.r % TX_BUF_SIZE];
+            self.r += 1;
+
+            // maybe a writer is waiting for space in the buffer.
+            Proc::wake_up(tx_chan());
+
+            THR.write(c);
+        }
     }
 }
 
-impl core::fmt::Write for Writer {
+static TX: Mutex<TxBuf> = Mutex::new(
+    TxBuf {
+        buf: [0; TX_BUF_SIZE],
+        w: 0,
+        r: 0,
+    },
+    "uart_tx",
+);
+
+/// What writers sleep on while the transmit buffer is full
+fn tx_chan() -> usize {
+    addr_of!(TX) as usize
+}
+
+/// Writes through the transmit buffer, holding its lock.
+struct Writer<'a> {
+    tx: Option<MutexGuard<'a, TxBuf>>,
+}
+
+impl Writer<'_> {
+    /// Add a character to the output buffer and tell the
+    /// UART to start sending if it isn't already.
+    /// Blocks if the output buffer is full: by sleeping when called
+    /// from a process that holds no other lock and had interrupts on,
+    /// and by spinning otherwise, e.g. in the scheduler or a trap handler.
+    fn write_byte(&mut self, c: u8) {
+        let mut tx = self.tx.take().unwrap();
+        while tx.is_full() {
+            // buffer is full.
+            // wait for start() to open up space in the buffer.
+            match CPU::this_proc() {
+                Some(p) if can_sleep() => {
+                    tx = unsafe { p.as_ref() }.sleep(tx_chan(), tx);
+                }
+                _ => {
+                    tx.start();
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        let w = tx.w;
+        tx.buf[w % TX_BUF_SIZE] = c;
+        tx.w += 1;
+        tx.start();
+        self.tx = Some(tx);
+    }
+}
+
+/// Can the current process sleep while holding only the transmit lock?
+fn can_sleep() -> bool {
+    unsafe {
+        let c = CPU::this();
+        (*c).get_noff() == 1 && (*c).get_interrupt_enabled()
+    }
+}
+
+impl core::fmt::Write for Writer<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         s.as_bytes().iter().for_each(|c| self.write_byte(*c));
         Ok(())
     }
 }
 
+/// Writes straight to the UART, without interrupts or the transmit buffer.
+struct SyncWriter;
+
+impl core::fmt::Write for SyncWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.as_bytes().iter().for_each(|c| putc_sync(*c));
+        Ok(())
+    }
+}
+
+/// Alternate version of the buffered output that doesn't
+/// use interrupts, for use by kernel echo and panic.
+/// It spins waiting for the uart's output register to be empty.
+pub fn putc_sync(c: u8) {
+    let _guard = unsafe { CPU::push_off() };
+    while (LSR.read() & LSR_TX_IDLE) == 0 {}
+    THR.write(c);
+}
+
+/// Write bytes through the transmit buffer,
+/// blocking while it is full.
+pub fn write(bytes: &[u8]) {
+    let mut writer = Writer {
+        tx: Some(TX.lock()),
+    };
+    bytes.iter().for_each(|&c| writer.write_byte(c));
+}
+
+/// Read one input character from the UART.
+/// Return `None` if none is waiting.
+pub fn getc() -> Option<u8> {
+    if (LSR.read() & LSR_RX_READY) != 0 {
+        // input data is ready.
+        Some(RHR.read())
+    } else {
+        None
+    }
+}
+
+/// Handle a uart interrupt, raised because input has
+/// arrived, or the uart is ready for more output, or
+/// both. Called from dev_intr().
+pub fn intr() {
+    // read and process incoming characters.
+    while let Some(c) = getc() {
+        console::intr(c);
+    }
+
+    // send buffered characters.
+    TX.lock().start();
+}
+
 /// Print to UART console with keeping order
 pub fn print(args: Arguments) {
     if PANICED.load(Ordering::Relaxed) {
         arch::halt();
     }
-    WRITER.lock().write_fmt(args).unwrap();
+    Writer {
+        tx: Some(TX.lock()),
+    }
+    .write_fmt(args)
+    .unwrap();
 }
 
 /// Called only from panic handler, can only be called ONCE
@@ -92,7 +242,7 @@ pub fn panic(args: Arguments) -> ! {
         .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
         .is_ok()
     {
-        Writer.write_fmt(args).unwrap();
+        SyncWriter.write_fmt(args).unwrap();
     }
     arch::halt();
 }
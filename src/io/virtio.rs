@@ -0,0 +1,122 @@
+//! virtio device definitions.
+//! for both the mmio interface, and virtio descriptors.
+//! only tested with qemu.
+//!
+//! the virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+pub mod disk;
+
+use super::{BaseIO, IO};
+use crate::arch::def::VIRTIO0;
+
+// virtio mmio control registers, mapped starting at 0x10001000.
+// from qemu virtio_mmio.h
+const VIRTIO0_BASE: BaseIO<u32> = BaseIO::new(VIRTIO0);
+const MAGIC_VALUE: IO<u32> = VIRTIO0_BASE.offset(0x000); // 0x74726976
+const VERSION: IO<u32> = VIRTIO0_BASE.offset(0x004); // 1 is legacy, 2 is modern
+const DEVICE_ID: IO<u32> = VIRTIO0_BASE.offset(0x008); // device type; 1 is net, 2 is disk
+const VENDOR_ID: IO<u32> = VIRTIO0_BASE.offset(0x00c); // 0x554d4551
+const DEVICE_FEATURES: IO<u32> = VIRTIO0_BASE.offset(0x010);
+const DEVICE_FEATURES_SEL: IO<u32> = VIRTIO0_BASE.offset(0x014);
+const DRIVER_FEATURES: IO<u32> = VIRTIO0_BASE.offset(0x020);
+const DRIVER_FEATURES_SEL: IO<u32> = VIRTIO0_BASE.offset(0x024);
+const GUEST_PAGE_SIZE: IO<u32> = VIRTIO0_BASE.offset(0x028); // legacy only, page size for PFN
+const QUEUE_SEL: IO<u32> = VIRTIO0_BASE.offset(0x030); // select queue, write-only
+const QUEUE_NUM_MAX: IO<u32> = VIRTIO0_BASE.offset(0x034); // max size of current queue, read-only
+const QUEUE_NUM: IO<u32> = VIRTIO0_BASE.offset(0x038); // size of current queue, write-only
+const QUEUE_ALIGN: IO<u32> = VIRTIO0_BASE.offset(0x03c); // legacy only, used ring alignment
+const QUEUE_PFN: IO<u32> = VIRTIO0_BASE.offset(0x040); // legacy only, physical page number of queue
+const QUEUE_READY: IO<u32> = VIRTIO0_BASE.offset(0x044); // modern only, ready bit
+const QUEUE_NOTIFY: IO<u32> = VIRTIO0_BASE.offset(0x050); // write-only
+const INTERRUPT_STATUS: IO<u32> = VIRTIO0_BASE.offset(0x060); // read-only
+const INTERRUPT_ACK: IO<u32> = VIRTIO0_BASE.offset(0x064); // write-only
+const STATUS: IO<u32> = VIRTIO0_BASE.offset(0x070); // read/write
+const QUEUE_DESC_LOW: IO<u32> = VIRTIO0_BASE.offset(0x080); // physical address for descriptor table, write-only
+const QUEUE_DESC_HIGH: IO<u32> = VIRTIO0_BASE.offset(0x084);
+const DRIVER_DESC_LOW: IO<u32> = VIRTIO0_BASE.offset(0x090); // physical address for available ring, write-only
+const DRIVER_DESC_HIGH: IO<u32> = VIRTIO0_BASE.offset(0x094);
+const DEVICE_DESC_LOW: IO<u32> = VIRTIO0_BASE.offset(0x0a0); // physical address for used ring, write-only
+const DEVICE_DESC_HIGH: IO<u32> = VIRTIO0_BASE.offset(0x0a4);
+
+const MAGIC: u32 = 0x74726976;
+const VENDOR_QEMU: u32 = 0x554d4551;
+const VERSION_LEGACY: u32 = 1;
+const VERSION_MODERN: u32 = 2;
+
+// status register bits, from qemu virtio_config.h
+const CONFIG_S_ACKNOWLEDGE: u32 = 1;
+const CONFIG_S_DRIVER: u32 = 2;
+const CONFIG_S_DRIVER_OK: u32 = 4;
+const CONFIG_S_FEATURES_OK: u32 = 8;
+
+// device feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5; // Disk is read-only
+const VIRTIO_BLK_F_SCSI: u64 = 1 << 7; // Supports scsi command passthru
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11; // Writeback mode available in config
+const VIRTIO_BLK_F_MQ: u64 = 1 << 12; // support more than one vq
+const VIRTIO_F_ANY_LAYOUT: u64 = 1 << 27;
+const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32; // required by modern devices
+
+/// this many virtio descriptors.
+/// must be a power of two.
+pub const NUM: usize = 8;
+
+/// a single descriptor, from the spec.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+const VRING_DESC_F_NEXT: u16 = 1; // chained with another descriptor
+const VRING_DESC_F_WRITE: u16 = 2; // device writes (vs read)
+
+/// the (entire) avail ring, from the spec.
+#[repr(C)]
+#[derive(Debug)]
+struct VirtqAvail {
+    flags: u16,       // always zero
+    idx: u16,         // driver will write ring[idx] next
+    ring: [u16; NUM], // descriptor numbers of chain heads
+    unused: u16,
+}
+
+/// one entry in the "used" ring, with which the
+/// device tells the driver about completed requests.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqUsedElem {
+    id: u32, // index of start of completed descriptor chain
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct VirtqUsed {
+    flags: u16, // always zero
+    idx: u16,   // device increments when it adds a ring[] entry
+    ring: [VirtqUsedElem; NUM],
+}
+
+// these are specific to virtio block devices, e.g. disks,
+// described in Section 5.2 of the spec.
+
+const VIRTIO_BLK_T_IN: u32 = 0; // read the disk
+const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
+
+/// the format of the first descriptor in a disk request.
+/// to be followed by two more descriptors containing
+/// the block, and a one-byte status.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtioBlkReq {
+    type_: u32, // VIRTIO_BLK_T_IN or ..._OUT
+    reserved: u32,
+    sector: u64,
+}
@@ -0,0 +1,430 @@
+//! driver for qemu's virtio disk device.
+//! uses qemu's mmio interface to virtio.
+//!
+//! qemu ... -drive file=fs.img,if=none,format=raw,id=x0 -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+//!
+//! Both the legacy (version 1) interface, qemu's default,
+//! and the modern (version 2) one with `-global virtio-mmio.force-legacy=false` work.
+
+use super::*;
+use crate::{
+    arch::{
+        def::{PG_SHIFT, PG_SIZE},
+        vm,
+    },
+    fs::BSIZE,
+    io::block::{self, BlockDevice},
+    mem::alloc::kalloc,
+    proc::{Proc, CPU},
+    spinlock::Mutex,
+    ROOTDEV,
+};
+use core::{
+    mem::size_of,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+};
+
+// The queue lives in a single page:
+// [ descriptors | avail ring | .. | used ring ]
+const AVAIL_OFFSET: usize = NUM * size_of::<VirtqDesc>();
+/// Alignment of the used ring, a legacy device is told with `QUEUE_ALIGN`
+const USED_ALIGN: usize = 256;
+const USED_OFFSET: usize =
+    (AVAIL_OFFSET + size_of::<VirtqAvail>() + USED_ALIGN - 1) & !(USED_ALIGN - 1);
+const _: () = assert!(USED_OFFSET + size_of::<VirtqUsed>() <= PG_SIZE);
+
+#[derive(Debug, Clone, Copy)]
+struct Info {
+    /// What the process waiting for the request sleeps on
+    chan: usize,
+    /// Set by the interrupt handler when the device is done
+    done: bool,
+    /// Written by the device, 0 on success
+    status: u8,
+}
+
+#[derive(Debug)]
+struct Disk {
+    /// a set (not a ring) of DMA descriptors, with which the
+    /// driver tells the device where to read and write individual
+    /// disk operations. there are NUM descriptors.
+    /// most commands consist of a "chain" (a linked list) of a couple of
+    /// these descriptors.
+    desc: *mut [VirtqDesc; NUM],
+
+    /// a ring in which the driver writes descriptor numbers
+    /// that the driver would like the device to process.  it only
+    /// includes the head descriptor of each chain. the ring has
+    /// NUM elements.
+    avail: *mut VirtqAvail,
+
+    /// a ring in which the device writes descriptor numbers that
+    /// the device has finished processing (just the head of each chain).
+    /// there are NUM used ring entries.
+    used: *mut VirtqUsed,
+
+    // our own book-keeping.
+    free: [bool; NUM], // is a descriptor free?
+    used_idx: u16,     // we've looked this far in used[2..NUM].
+
+    /// track info about in-flight operations,
+    /// for use when completion interrupt arrives.
+    /// indexed by first descriptor index of chain.
+    info: [Info; NUM],
+
+    /// disk command headers.
+    /// one-for-one with descriptors, for convenience.
+    ops: [VirtioBlkReq; NUM],
+}
+
+pub struct VirtioDisk {
+    disk: Mutex<Disk>,
+}
+
+static DISK: VirtioDisk = VirtioDisk {
+    disk: Mutex::new(
+        Disk {
+            desc: core::ptr::null_mut(),
+            avail: core::ptr::null_mut(),
+            used: core::ptr::null_mut(),
+            free: [false; NUM],
+            used_idx: 0,
+            info: [Info {
+                chan: 0,
+                done: false,
+                status: 0,
+            }; NUM],
+            ops: [VirtioBlkReq {
+                type_: 0,
+                reserved: 0,
+                sector: 0,
+            }; NUM],
+        },
+        "virtio_disk",
+    ),
+};
+
+/// Physical address of a kernel address, for the device to DMA to or from.
+fn dma_addr<T>(p: *const T) -> u64 {
+    vm::virt_to_phys(p as usize).expect("virtio disk: address not mapped") as u64
+}
+
+fn read_features() -> u64 {
+    DEVICE_FEATURES_SEL.write(0);
+    let low = DEVICE_FEATURES.read() as u64;
+    DEVICE_FEATURES_SEL.write(1);
+    let high = DEVICE_FEATURES.read() as u64;
+    high << 32 | low
+}
+
+fn write_features(features: u64) {
+    DRIVER_FEATURES_SEL.write(0);
+    DRIVER_FEATURES.write(features as u32);
+    DRIVER_FEATURES_SEL.write(1);
+    DRIVER_FEATURES.write((features >> 32) as u32);
+}
+
+/// Find and set up the virtio disk, and make it the root device.
+/// Return `false` if there is no virtio disk.
+pub fn init() -> bool {
+    let version = VERSION.read();
+    if MAGIC_VALUE.read() != MAGIC
+        || (version != VERSION_LEGACY && version != VERSION_MODERN)
+        || DEVICE_ID.read() != 2
+        || VENDOR_ID.read() != VENDOR_QEMU
+    {
+        return false;
+    }
+    let legacy = version == VERSION_LEGACY;
+
+    let mut status = 0;
+
+    // reset device
+    STATUS.write(status);
+
+    // set ACKNOWLEDGE status bit
+    status |= CONFIG_S_ACKNOWLEDGE;
+    STATUS.write(status);
+
+    // set DRIVER status bit
+    status |= CONFIG_S_DRIVER;
+    STATUS.write(status);
+
+    // negotiate features
+    let features = read_features()
+        & !(VIRTIO_BLK_F_RO
+            | VIRTIO_BLK_F_SCSI
+            | VIRTIO_BLK_F_CONFIG_WCE
+            | VIRTIO_BLK_F_MQ
+            | VIRTIO_F_ANY_LAYOUT
+            | VIRTIO_RING_F_EVENT_IDX
+            | VIRTIO_RING_F_INDIRECT_DESC);
+    write_features(features);
+
+    if !legacy {
+        assert!(
+            features & VIRTIO_F_VERSION_1 != 0,
+            "virtio disk: no VIRTIO_F_VERSION_1"
+        );
+
+        // tell device that feature negotiation is complete.
+        status |= CONFIG_S_FEATURES_OK;
+        STATUS.write(status);
+
+        // re-read status to ensure FEATURES_OK is set.
+        assert!(
+            STATUS.read() & CONFIG_S_FEATURES_OK != 0,
+            "virtio disk FEATURES_OK unset"
+        );
+    }
+
+    // initialize queue 0.
+    QUEUE_SEL.write(0);
+
+    // ensure queue 0 is not in use.
+    let in_use = if legacy {
+        QUEUE_PFN.read()
+    } else {
+        QUEUE_READY.read()
+    };
+    assert!(in_use == 0, "virtio disk should not be ready");
+
+    // check maximum queue size.
+    let max = QUEUE_NUM_MAX.read() as usize;
+    assert!(max != 0, "virtio disk has no queue 0");
+    assert!(max >= NUM, "virtio disk max queue too short");
+
+    // allocate and zero queue memory.
+    let page = kalloc(true).expect("virtio disk kalloc");
+    let base = page.as_mut_ptr::<u8>();
+    let pa = dma_addr(base);
+
+    // set queue size.
+    QUEUE_NUM.write(NUM as u32);
+
+    // write physical addresses.
+    if legacy {
+        GUEST_PAGE_SIZE.write(PG_SIZE as u32);
+        QUEUE_ALIGN.write(USED_ALIGN as u32);
+        QUEUE_PFN.write((pa >> PG_SHIFT) as u32);
+    } else {
+        let avail = pa + AVAIL_OFFSET as u64;
+        let used = pa + USED_OFFSET as u64;
+        QUEUE_DESC_LOW.write(pa as u32);
+        QUEUE_DESC_HIGH.write((pa >> 32) as u32);
+        DRIVER_DESC_LOW.write(avail as u32);
+        DRIVER_DESC_HIGH.write((avail >> 32) as u32);
+        DEVICE_DESC_LOW.write(used as u32);
+        DEVICE_DESC_HIGH.write((used >> 32) as u32);
+
+        // queue is ready.
+        QUEUE_READY.write(1);
+    }
+
+    {
+        let mut disk = DISK.disk.lock();
+        disk.desc = base as *mut [VirtqDesc; NUM];
+        disk.avail = unsafe { base.add(AVAIL_OFFSET) } as *mut VirtqAvail;
+        disk.used = unsafe { base.add(USED_OFFSET) } as *mut VirtqUsed;
+
+        // all NUM descriptors start out unused.
+        disk.free = [true; NUM];
+        disk.used_idx = 0;
+    }
+
+    // tell device we're completely ready.
+    status |= CONFIG_S_DRIVER_OK;
+    STATUS.write(status);
+
+    block::register(ROOTDEV, &DISK);
+    true
+}
+
+impl Disk {
+    /// find a free descriptor, mark it non-free, return its index.
+    fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    /// mark a descriptor as free.
+    fn free_desc(&mut self, i: usize) {
+        assert!(i < NUM, "free_desc 1");
+        assert!(!self.free[i], "free_desc 2");
+        unsafe {
+            (*self.desc)[i] = VirtqDesc {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: 0,
+            };
+        }
+        self.free[i] = true;
+    }
+
+    /// free a chain of descriptors.
+    fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let VirtqDesc { flags, next, .. } = unsafe { (*self.desc)[i] };
+            self.free_desc(i);
+            if flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = next as usize;
+        }
+    }
+
+    /// allocate three descriptors (they need not be contiguous).
+    /// disk transfers always use three descriptors.
+    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
+        let mut idx = [0; 3];
+        for i in 0..3 {
+            match self.alloc_desc() {
+                Some(d) => idx[i] = d,
+                None => {
+                    idx[..i].iter().for_each(|&d| self.free_desc(d));
+                    return None;
+                }
+            }
+        }
+        Some(idx)
+    }
+
+    /// What processes waiting for free descriptors sleep on
+    fn free_chan(&self) -> usize {
+        addr_of!(self.free) as usize
+    }
+}
+
+impl VirtioDisk {
+    fn rw(&self, blockno: u32, data: *mut u8, write: bool) {
+        let sector = blockno as u64 * (BSIZE / 512) as u64;
+        let p = unsafe { CPU::this_proc_ref() };
+
+        let mut disk = self.disk.lock();
+
+        // the spec's Section 5.2 says that legacy block operations use
+        // three descriptors: one for type/reserved/sector, one for the
+        // data, one for a 1-byte status result.
+
+        // allocate the three descriptors.
+        let idx = loop {
+            if let Some(idx) = disk.alloc3_desc() {
+                break idx;
+            }
+            let chan = disk.free_chan();
+            disk = p.sleep(chan, disk);
+        };
+
+        // format the three descriptors.
+        // qemu's virtio-blk.c reads them.
+        let d = &mut *disk;
+        d.ops[idx[0]] = VirtioBlkReq {
+            type_: if write {
+                VIRTIO_BLK_T_OUT // write the disk
+            } else {
+                VIRTIO_BLK_T_IN // read the disk
+            },
+            reserved: 0,
+            sector,
+        };
+        d.info[idx[0]] = Info {
+            chan: data as usize,
+            done: false,
+            status: 0xff, // device writes 0 on success
+        };
+
+        let desc = unsafe { &mut *d.desc };
+        desc[idx[0]] = VirtqDesc {
+            addr: dma_addr(addr_of!(d.ops[idx[0]])),
+            len: size_of::<VirtioBlkReq>() as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        };
+        desc[idx[1]] = VirtqDesc {
+            addr: dma_addr(data),
+            len: BSIZE as u32,
+            flags: if write {
+                VRING_DESC_F_NEXT // device reads data
+            } else {
+                VRING_DESC_F_NEXT | VRING_DESC_F_WRITE // device writes data
+            },
+            next: idx[2] as u16,
+        };
+        desc[idx[2]] = VirtqDesc {
+            addr: dma_addr(addr_of!(d.info[idx[0]].status)),
+            len: 1,
+            flags: VRING_DESC_F_WRITE, // device writes the status
+            next: 0,
+        };
+
+        // tell the device the first index in our chain of descriptors.
+        unsafe {
+            let avail = &mut *d.avail;
+            avail.ring[avail.idx as usize % NUM] = idx[0] as u16;
+
+            fence(Ordering::SeqCst);
+
+            // tell the device another avail ring entry is available.
+            addr_of_mut!(avail.idx).write_volatile(avail.idx.wrapping_add(1));
+        }
+
+        fence(Ordering::SeqCst);
+
+        QUEUE_NOTIFY.write(0); // value is queue number
+
+        // Wait for intr() to say request has finished.
+        while !disk.info[idx[0]].done {
+            disk = p.sleep(data as usize, disk);
+        }
+
+        disk.info[idx[0]].chan = 0;
+        disk.free_chain(idx[0]);
+        let chan = disk.free_chan();
+        Proc::wake_up(chan);
+    }
+}
+
+impl BlockDevice for VirtioDisk {
+    fn read(&self, blockno: u32, data: &mut [u8; BSIZE]) {
+        self.rw(blockno, data.as_mut_ptr(), false);
+    }
+
+    fn write(&self, blockno: u32, data: &[u8; BSIZE]) {
+        self.rw(blockno, data.as_ptr() as *mut u8, true);
+    }
+}
+
+/// Handle a virtio disk interrupt: wake up the processes
+/// whose requests have completed.
+pub fn intr() {
+    let mut disk = DISK.disk.lock();
+
+    // the device won't raise another interrupt until we tell it
+    // we've seen this interrupt, which the following line does.
+    // this may race with the device writing new entries to
+    // the "used" ring, in which case we may process the new
+    // completion entries in this interrupt, and have nothing to do
+    // in the next interrupt, which is harmless.
+    INTERRUPT_ACK.write(INTERRUPT_STATUS.read() & 0x3);
+
+    fence(Ordering::SeqCst);
+
+    // the device increments disk.used->idx when it
+    // adds an entry to the used ring.
+    while disk.used_idx != unsafe { addr_of!((*disk.used).idx).read_volatile() } {
+        fence(Ordering::SeqCst);
+        let id = unsafe { (*disk.used).ring[disk.used_idx as usize % NUM].id } as usize;
+
+        let info = &mut disk.info[id];
+        let status = unsafe { addr_of!(info.status).read_volatile() };
+        assert!(status == 0, "virtio_disk_intr status");
+
+        info.done = true; // disk is done with the request
+        Proc::wake_up(info.chan);
+
+        disk.used_idx = disk.used_idx.wrapping_add(1);
+    }
+}
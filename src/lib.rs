@@ -4,13 +4,19 @@
 #![feature(const_refs_to_static)]
 #![allow(dead_code)]
 
+extern crate alloc;
+
 pub mod arch;
+pub mod file;
+pub mod fs;
 pub mod io;
 pub mod mem;
+pub mod pipe;
 pub mod print;
 pub mod proc;
 pub mod sleeplock;
 pub mod spinlock;
+pub mod syscall;
 
 /// Should be equal to _max_hart_id
 pub const NCPU: usize = 8;
@@ -18,4 +24,31 @@ pub const NCPU: usize = 8;
 /// Maximum supported number of processes
 pub const NPROC: usize = NCPU * 2; // TODO: increase latter
 
+/// Max exec arguments
+pub const MAXARG: usize = 32;
+
+/// Maximum file path name
+pub const MAXPATH: usize = 128;
+
+/// Open files per process
+pub const NOFILE: usize = 16;
+
+/// Memory areas mapped with `mmap` per process
+pub const NVMA: usize = 16;
+
+/// Open files per system
+pub const NFILE: usize = 100;
+
+/// Maximum number of active i-nodes
+pub const NINODE: usize = 50;
+
+/// Maximum major device number
+pub const NDEV: usize = 10;
+
+/// Device number of file system root disk
+pub const ROOTDEV: u32 = 1;
+
+/// Size of disk block cache
+pub const NBUF: usize = fs::MAXOPBLOCKS * 3;
+
 // TODO: detect and set `NCPU` and `NPROC`
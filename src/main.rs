@@ -96,6 +96,9 @@ fn start() -> ! {
     unsafe {
         // Disable paging for now.
         reg::satp.set(reg::SatpMode::Bare, 0, 0);
+        if hart_id == 0 {
+            arch::vm::select_paging_mode();
+        }
 
         // Delegate all interrupts and exceptions to supervisor mode.
         reg::medeleg.write(0xffff);
@@ -129,6 +132,7 @@ extern "C" fn main() -> ! {
     let cpu_id = arch::cpuid();
     if cpu_id == 0 {
         io::console::init();
+        io::stats::init();
         println!(
             "\nxv6 kernel is booting, max {} harts supported\n",
             unsafe { read_linker_symbol!(_max_hart_id) }
@@ -140,6 +144,11 @@ extern "C" fn main() -> ! {
             trap::init_hart();
             interrupt::init();
             interrupt::init_hart();
+            // root disk, the built-in file system image if there is no virtio disk
+            if !io::virtio::disk::init() {
+                io::ramdisk::init();
+            }
+            proc::user_init(); // first user process
             compiler_fence(Ordering::SeqCst);
             STARTED.store(true, Ordering::SeqCst)
         }
@@ -1,4 +1,8 @@
 pub mod alloc;
+pub mod buddy;
+pub mod mman;
+pub mod slab;
+pub mod uptr;
 pub mod uvm;
 
 pub fn init() {
@@ -1,171 +1,256 @@
 use crate::arch;
+use crate::arch::def::KERNEL_BASE;
+use crate::mem::buddy::{block_size, width_order, BuddyAllocator, NORDER, NPAGES};
 use crate::println;
-use crate::spinlock::Mutex;
-use rv64::vm::PageAllocator;
-use rv64::vm::PageWidth;
-use rv64::vm::{PhysAddr, PAGE_SIZE};
+use crate::proc::CPU;
+use crate::spinlock::{LockStats, Mutex};
+use crate::NCPU;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicU16, Ordering};
+use rv64::vm::{PageAllocator, PageWidth, PhysAddr, PAGE_SIZE};
 
-pub static mut ALLOCATOR: LinkListAllocator = LinkListAllocator::default();
+pub static mut ALLOCATOR: BuddyAllocator = BuddyAllocator::default();
 
-#[inline]
-pub const fn page_size() -> usize {
-    unsafe { ALLOCATOR.page_size() }
+/// Reference counts of allocated pages, indexed by page number from
+/// `KERNEL_BASE`. Pages shared copy-on-write by several processes
+/// are only freed when the last reference goes away.
+static PAGE_REFS: [AtomicU16; NPAGES] = [const { AtomicU16::new(0) }; NPAGES];
+
+/// Most pages a CPU keeps in its cache, before giving some back
+const CACHE_MAX: usize = 64;
+/// Number of pages moved at once between a CPU's cache and the buddy allocator
+const CACHE_BATCH: usize = 16;
+
+#[repr(C)]
+struct FreePage {
+    next: *mut FreePage,
 }
 
-#[inline]
-pub fn free_pages() -> usize {
-    unsafe { ALLOCATOR.free_pages() }
+/// Free single pages kept by a CPU, so that most `kalloc`s and `kfree`s
+/// only take that CPU's lock instead of the buddy allocator's.
+struct PageCache {
+    head: *mut FreePage,
+    len: usize,
 }
 
-#[inline]
-pub fn kalloc(zeroed: bool) -> Option<PhysAddr> {
-    unsafe { ALLOCATOR.kalloc(zeroed) }
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, page: PhysAddr) {
+        let page = page.as_mut_ptr::<FreePage>();
+        (*page).next = self.head;
+        self.head = page;
+        self.len += 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<PhysAddr> {
+        if self.head.is_null() {
+            return None;
+        }
+        let page = self.head;
+        self.head = (*page).next;
+        self.len -= 1;
+        Some(PhysAddr::from(page as usize))
+    }
 }
 
+static CACHES: [Mutex<PageCache>; NCPU] = [const { Mutex::new(PageCache::new(), "kmem") }; NCPU];
+
 #[inline]
-pub unsafe fn kfree(addr: impl Into<PhysAddr>) {
-    ALLOCATOR.kfree(addr);
+fn allocator() -> &'static BuddyAllocator {
+    unsafe { &*addr_of!(ALLOCATOR) }
 }
 
 #[inline]
-pub unsafe fn kfree_range(start: impl Into<PhysAddr>, end: impl Into<PhysAddr>) {
-    ALLOCATOR.kfree_range(start, end);
+fn ref_index(page: PhysAddr) -> usize {
+    (usize::from(page) - KERNEL_BASE) / PAGE_SIZE
 }
 
-pub fn init_heap() {
-    let start = arch::vm::heap_start();
-    let end = arch::vm::heap_end();
-    println!("init heap: 0x{:x} - 0x{:x}", start, end);
-    let pages = unsafe {
-        ALLOCATOR = LinkListAllocator::new(start, end, PAGE_SIZE);
-        ALLOCATOR.kfree_range(start, end);
-        ALLOCATOR.free_pages()
-    };
-    println!(
-        "{} pages ({} KiB) available",
-        pages,
-        pages * PAGE_SIZE / 1024
-    );
+#[inline]
+pub const fn page_size() -> usize {
+    PAGE_SIZE
 }
 
-#[repr(C)]
-pub struct FreePage {
-    next: *mut FreePage,
+/// Number of free pages, in the buddy allocator and the CPU caches.
+pub fn free_pages() -> usize {
+    let cached: usize = CACHES.iter().map(|c| c.lock().len).sum();
+    allocator().free_pages() + cached
 }
 
-impl FreePage {
-    pub fn new(addr: impl Into<usize>) -> *mut Self {
-        addr.into() as *mut FreePage
-    }
+/// Number of free blocks of each order in the buddy allocator.
+#[inline]
+pub fn free_counts() -> [usize; NORDER] {
+    allocator().free_counts()
 }
 
-pub struct LinkListAllocator {
-    heap_start: PhysAddr,
-    heap_end: PhysAddr,
-    page_size: usize,
-    free_pages: Mutex<usize>,
-    free_list: Mutex<*mut FreePage>,
+/// Contention counters of the buddy allocator's lock
+/// and of each CPU's page cache lock.
+pub fn lock_stats() -> impl Iterator<Item = LockStats> {
+    core::iter::once(allocator().lock_stats()).chain(CACHES.iter().map(|c| c.stats()))
 }
 
-impl LinkListAllocator {
-    const fn default() -> Self {
-        Self {
-            heap_start: PhysAddr::null(),
-            heap_end: PhysAddr::null(),
-            page_size: 0,
-            free_pages: Mutex::new(0, "free_pages"),
-            free_list: Mutex::new(0 as *mut FreePage, "free_list"),
-        }
+/// Take a free page from this CPU's cache. Refill an empty cache
+/// from the buddy allocator, or else steal from other CPUs.
+fn cache_alloc() -> Option<PhysAddr> {
+    // Stay on this CPU.
+    let _int_lock = unsafe { CPU::push_off() };
+    let id = arch::cpuid();
+    let mut cache = CACHES[id].lock();
+    if let Some(page) = unsafe { cache.pop() } {
+        return Some(page);
     }
 
-    pub fn new(
-        heap_start: impl Into<PhysAddr>,
-        heap_end: impl Into<PhysAddr>,
-        page_size: usize,
-    ) -> Self {
-        Self {
-            heap_start: heap_start.into(),
-            heap_end: heap_end.into(),
-            page_size,
-            free_pages: Mutex::new(0, "init_free_pages"),
-            free_list: Mutex::new(0 as *mut FreePage, "init_free_list"),
+    for _ in 0..CACHE_BATCH {
+        match allocator().alloc(0) {
+            Some(page) => unsafe { cache.push(page) },
+            None => break,
         }
     }
-
-    pub const fn page_size(&self) -> usize {
-        self.page_size
+    if let Some(page) = unsafe { cache.pop() } {
+        return Some(page);
     }
+    // Never hold two caches' locks at once.
+    drop(cache);
 
-    pub fn free_pages(&self) -> usize {
-        *self.free_pages.lock()
+    let (mut stolen, mut n) = (PageCache::new(), 0);
+    for other in (1..NCPU).map(|i| (id + i) % NCPU) {
+        let mut victim = CACHES[other].lock();
+        n = victim.len.div_ceil(2);
+        for _ in 0..n {
+            unsafe { stolen.push(victim.pop().unwrap()) };
+        }
+        if n > 0 {
+            break;
+        }
     }
+    let page = unsafe { stolen.pop() }?;
+    if n > 1 {
+        let mut cache = CACHES[id].lock();
+        while let Some(p) = unsafe { stolen.pop() } {
+            unsafe { cache.push(p) };
+        }
+    }
+    Some(page)
+}
 
-    /// Allocate one 4096-byte page of physical memory.
-    /// Returns a pointer that the kernel can use.
-    /// Returns 0 if the memory cannot be allocated.
-    pub unsafe fn kalloc(&self, zeroed: bool) -> Option<PhysAddr> {
-        let mut free_list = self.free_list.lock();
-        let page = *free_list;
-        if page.is_null() {
-            return None;
+/// Put a free page in this CPU's cache,
+/// giving a batch back to the buddy allocator when it's full.
+unsafe fn cache_free(page: PhysAddr) {
+    page.memset(0xFFFF_FFFF_FFFF_FFFF_usize, PAGE_SIZE);
+    let _int_lock = CPU::push_off();
+    let mut cache = CACHES[arch::cpuid()].lock();
+    cache.push(page);
+    if cache.len > CACHE_MAX {
+        for _ in 0..CACHE_BATCH {
+            allocator().free(cache.pop().unwrap());
         }
-        *free_list = (*page).next;
-        *self.free_pages.lock() -= 1;
+    }
+}
+
+/// Allocate one 4096-byte page of physical memory.
+/// Returns a pointer that the kernel can use.
+/// Returns `None` if the memory cannot be allocated.
+#[inline]
+pub fn kalloc(zeroed: bool) -> Option<PhysAddr> {
+    kalloc_order(0, zeroed)
+}
 
-        let page = PhysAddr::from(page as usize);
+/// Allocate 2^order contiguous pages of physical memory,
+/// aligned to their size. `kfree` frees them all.
+pub fn kalloc_order(order: usize, zeroed: bool) -> Option<PhysAddr> {
+    let page = if order == 0 {
+        cache_alloc()
+    } else {
+        allocator().alloc(order)
+    }?;
+    PAGE_REFS[ref_index(page)].store(1, Ordering::Relaxed);
+    unsafe {
         page.memset(
             if zeroed {
                 0usize
             } else {
                 0xAAAA_AAAA_AAAA_AAAA_usize
             },
-            self.page_size,
+            block_size(order),
         );
-
-        Some(page)
     }
+    Some(page)
+}
 
-    /// Free the page of physical memory pointed at by v,
-    /// which normally should have been returned by a
-    /// call to kalloc().  (The exception is when
-    /// initializing the allocator; see kinit above.)
-    pub unsafe fn kfree(&self, addr: impl Into<PhysAddr>) {
-        let page = addr.into();
-        if page.page_offset() != 0 || page < self.heap_start || page > self.heap_end {
-            panic!("kfree: invalid page: {:?}", page);
-        }
-
-        unsafe {
-            page.memset(0xFFFF_FFFF_FFFF_FFFF_usize, self.page_size);
-            let page = FreePage::new(page);
-            let mut free_list = self.free_list.lock();
-            (*page).next = *free_list;
-            *free_list = page;
-            *self.free_pages.lock() += 1;
-        }
+/// Drop a reference to the pages of physical memory at `addr`,
+/// and free them if that was the last one. The pages normally should
+/// have been returned by a call to kalloc().
+pub unsafe fn kfree(addr: impl Into<PhysAddr>) {
+    let page = addr.into();
+    let order = allocator().order(page);
+    let refs = PAGE_REFS[ref_index(page)].update(Ordering::AcqRel, Ordering::Acquire, |r| {
+        // The buddy allocator can't catch a double free of a page
+        // that went into a CPU's cache.
+        assert!(r > 0, "kfree: double free: {:?}", page);
+        r - 1
+    });
+    if refs > 1 {
+        // Still shared.
+        return;
     }
-
-    pub unsafe fn kfree_range(&self, start: impl Into<PhysAddr>, end: impl Into<PhysAddr>) {
-        let start = start.into();
-        let end = end.into();
-        for page in (usize::from(start.page_roundup())..=usize::from(end)).step_by(self.page_size) {
-            self.kfree(PhysAddr::from(page));
-        }
+    if order == 0 {
+        cache_free(page);
+    } else {
+        allocator().free(page);
     }
 }
 
-unsafe impl PageAllocator for LinkListAllocator {
+/// Add a reference to an allocated page, which then
+/// takes one more `kfree` to be freed.
+pub fn kref(addr: impl Into<PhysAddr>) {
+    let page = addr.into();
+    PAGE_REFS[ref_index(page)].update(Ordering::AcqRel, Ordering::Acquire, |r| {
+        assert!(r > 0, "kref: free page: {:?}", page);
+        r.checked_add(1).expect("kref: too many references")
+    });
+}
+
+/// Number of references to an allocated page.
+pub fn ref_count(addr: impl Into<PhysAddr>) -> usize {
+    PAGE_REFS[ref_index(addr.into())].load(Ordering::Acquire) as usize
+}
+
+/// `kalloc` and `kfree` as a `PageAllocator`, for page tables whose
+/// pages are reference counted, like the user ones.
+pub struct Kalloc;
+
+unsafe impl PageAllocator for Kalloc {
     unsafe fn palloc(&self, page_width: PageWidth) -> Option<PhysAddr> {
-        if page_width != PageWidth::W4K {
-            return None;
-        }
-        self.kalloc(true)
+        kalloc_order(width_order(page_width), true)
     }
 
     unsafe fn pfree(&self, page: PhysAddr) {
-        self.kfree(page)
+        kfree(page)
     }
 }
 
-unsafe impl Sync for LinkListAllocator {}
-unsafe impl Send for LinkListAllocator {}
+pub fn init_heap() {
+    let start = arch::vm::heap_start();
+    let end = arch::vm::heap_end();
+    println!("init heap: 0x{:x} - 0x{:x}", start, end);
+    let pages = unsafe {
+        ALLOCATOR = BuddyAllocator::new(start, end);
+        ALLOCATOR.free_range(start, end);
+        ALLOCATOR.free_pages()
+    };
+    println!(
+        "{} pages ({} KiB) available",
+        pages,
+        pages * PAGE_SIZE / 1024
+    );
+    for (order, n) in free_counts().into_iter().enumerate() {
+        if n > 0 {
+            println!("  {} free block(s) of {} KiB", n, block_size(order) / 1024);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Buddy allocator of physical pages.
+//!
+//! Free memory is kept in blocks of 2^order pages, aligned to their
+//! size in physical memory, with one free list per order. Allocating
+//! splits a larger block in halves until it has the order asked for.
+//! Freeing merges a block with its buddy, the other half of the block
+//! they were split from, for as long as the buddy is free too.
+//! Blocks of order 9 and 18 are 2 MiB and 1 GiB superpages.
+
+use crate::arch::def::{KERNEL_BASE, PHY_STOP};
+use crate::spinlock::{LockStats, Mutex};
+use core::{
+    ptr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use rv64::vm::{PageAllocator, PageWidth, PhysAddr, PAGE_OFFSET, PAGE_SIZE};
+
+/// Largest block order, 1 GiB
+pub const MAX_ORDER: usize = 18;
+/// Number of block orders
+pub const NORDER: usize = MAX_ORDER + 1;
+
+/// Number of physical pages in RAM
+pub const NPAGES: usize = (PHY_STOP - KERNEL_BASE) / PAGE_SIZE;
+
+/// `BuddyAllocator::order` of a page that doesn't start a block
+const NOT_HEAD: u8 = 0xff;
+/// Set in `BuddyAllocator::order` of the first page of a free block
+const FREE: u8 = 0x80;
+
+/// Number of bytes in a block of `order`
+#[inline]
+pub const fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// Order of the blocks mapped by a PTE of `width`
+#[inline]
+pub const fn width_order(width: PageWidth) -> usize {
+    width as usize - PAGE_OFFSET.width()
+}
+
+#[inline]
+fn page_index(page: usize) -> usize {
+    (page - KERNEL_BASE) / PAGE_SIZE
+}
+
+/// Link in the first page of a free block
+#[repr(C)]
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct BuddyState {
+    free_lists: [*mut FreeBlock; NORDER],
+    nfree: [usize; NORDER],
+}
+
+pub struct BuddyAllocator {
+    heap_start: PhysAddr,
+    heap_end: PhysAddr,
+    state: Mutex<BuddyState>,
+    /// Order of the block starting at each page, with `FREE` if it's
+    /// free, or `NOT_HEAD` if no block starts there. Only changed with
+    /// `state` held, but the entry of an allocated block belongs to
+    /// its owner, who can read it without the lock.
+    order: [AtomicU8; NPAGES],
+}
+
+impl BuddyAllocator {
+    pub const fn default() -> Self {
+        Self {
+            heap_start: PhysAddr::null(),
+            heap_end: PhysAddr::null(),
+            state: Mutex::new(
+                BuddyState {
+                    free_lists: [ptr::null_mut(); NORDER],
+                    nfree: [0; NORDER],
+                },
+                "buddy",
+            ),
+            order: [const { AtomicU8::new(NOT_HEAD) }; NPAGES],
+        }
+    }
+
+    pub fn new(heap_start: impl Into<PhysAddr>, heap_end: impl Into<PhysAddr>) -> Self {
+        Self {
+            heap_start: heap_start.into(),
+            heap_end: heap_end.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Number of free pages
+    pub fn free_pages(&self) -> usize {
+        let state = self.state.lock();
+        (0..NORDER).map(|o| state.nfree[o] << o).sum()
+    }
+
+    /// Number of free blocks of each order
+    pub fn free_counts(&self) -> [usize; NORDER] {
+        self.state.lock().nfree
+    }
+
+    pub fn lock_stats(&self) -> LockStats {
+        self.state.stats()
+    }
+
+    fn set_order(&self, addr: usize, order: u8) {
+        self.order[page_index(addr)].store(order, Ordering::Relaxed);
+    }
+
+    unsafe fn push(&self, state: &mut BuddyState, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let head = state.free_lists[order];
+        (*block).next = head;
+        (*block).prev = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev = block;
+        }
+        state.free_lists[order] = block;
+        state.nfree[order] += 1;
+        self.set_order(addr, FREE | order as u8);
+    }
+
+    unsafe fn remove(&self, state: &mut BuddyState, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let (next, prev) = ((*block).next, (*block).prev);
+        if prev.is_null() {
+            state.free_lists[order] = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        state.nfree[order] -= 1;
+        self.set_order(addr, NOT_HEAD);
+    }
+
+    /// Allocate a block of 2^order contiguous pages, aligned to its size.
+    /// The content of the block is undefined.
+    /// Returns `None` if there's no free block that large.
+    pub fn alloc(&self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut state = self.state.lock();
+        let mut o = (order..NORDER).find(|&o| !state.free_lists[o].is_null())?;
+        let block = state.free_lists[o] as usize;
+        unsafe { self.remove(&mut state, block, o) };
+        // Split, and give back the upper halves.
+        while o > order {
+            o -= 1;
+            unsafe { self.push(&mut state, block + block_size(o), o) };
+        }
+        self.set_order(block, order as u8);
+        Some(PhysAddr::from(block))
+    }
+
+    /// Order of the allocated block starting at `page`.
+    pub fn order(&self, page: impl Into<PhysAddr>) -> usize {
+        let page = page.into();
+        self.check(page);
+        let order = self.order[page_index(usize::from(page))].load(Ordering::Relaxed);
+        if order == NOT_HEAD || order & FREE != 0 {
+            panic!("buddy: not an allocated block: {:?}", page);
+        }
+        order as usize
+    }
+
+    /// Free the block starting at `page`, merging it with its free buddies.
+    ///
+    /// # Safety
+    ///
+    /// `page` must be the start of a block this allocator handed out,
+    /// and nothing may use the block any more: it's overwritten, and
+    /// handed out again.
+    pub unsafe fn free(&self, page: impl Into<PhysAddr>) {
+        let page = page.into();
+        let order = self.order(page);
+        page.memset(0xFFFF_FFFF_FFFF_FFFF_usize, block_size(order));
+
+        let mut state = self.state.lock();
+        let mut addr = usize::from(page);
+        self.set_order(addr, NOT_HEAD);
+        let mut o = order;
+        while o < MAX_ORDER {
+            let buddy = addr ^ block_size(o);
+            if buddy < usize::from(self.heap_start)
+                || buddy + block_size(o) > usize::from(self.heap_end)
+                || self.order[page_index(buddy)].load(Ordering::Relaxed) != FREE | o as u8
+            {
+                break;
+            }
+            self.remove(&mut state, buddy, o);
+            addr = addr.min(buddy);
+            o += 1;
+        }
+        self.push(&mut state, addr, o);
+    }
+
+    /// Add the pages of `[start, end)` to the free lists,
+    /// in the largest blocks they can make.
+    ///
+    /// # Safety
+    ///
+    /// `[start, end)` must be in the heap, not on the free lists
+    /// already, and unused: it's overwritten, and handed out.
+    pub unsafe fn free_range(&self, start: impl Into<PhysAddr>, end: impl Into<PhysAddr>) {
+        let mut addr = usize::from(start.into().page_roundup());
+        let end = usize::from(end.into());
+        let mut state = self.state.lock();
+        while addr + PAGE_SIZE <= end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| addr % block_size(o) == 0 && addr + block_size(o) <= end)
+                .unwrap();
+            PhysAddr::from(addr).memset(0xFFFF_FFFF_FFFF_FFFF_usize, block_size(order));
+            self.push(&mut state, addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Panic unless `page` is a page of the heap.
+    pub fn check(&self, page: PhysAddr) {
+        if page.page_offset() != 0 || page < self.heap_start || page >= self.heap_end {
+            panic!("buddy: invalid page: {:?}", page);
+        }
+    }
+}
+
+unsafe impl PageAllocator for BuddyAllocator {
+    unsafe fn palloc(&self, page_width: PageWidth) -> Option<PhysAddr> {
+        let order = width_order(page_width);
+        let page = self.alloc(order)?;
+        page.memset(0usize, block_size(order));
+        Some(page)
+    }
+
+    unsafe fn pfree(&self, page: PhysAddr) {
+        self.free(page)
+    }
+}
+
+unsafe impl Sync for BuddyAllocator {}
+unsafe impl Send for BuddyAllocator {}
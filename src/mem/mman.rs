@@ -0,0 +1,12 @@
+//! Flags for `mmap`, shared by the kernel and user programs.
+
+// Protection of mapped pages
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+// Kind of mapping, exactly one of `MAP_SHARED` and `MAP_PRIVATE`
+pub const MAP_SHARED: usize = 0x01; // stores go to the file
+pub const MAP_PRIVATE: usize = 0x02; // stores stay in this process
+pub const MAP_ANONYMOUS: usize = 0x20; // zeroed memory, not backed by a file
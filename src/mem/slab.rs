@@ -0,0 +1,266 @@
+//! Kernel heap, the global allocator of the `alloc` crate.
+//!
+//! Small objects come from slab caches, one per power of two size
+//! class: a slab is a page cut into objects of its class, with a
+//! header at the start of the page. A cache keeps its slabs with
+//! free objects on a list, and gives a slab's page back once all
+//! of its objects are freed. Larger objects get whole blocks of
+//! pages from the page allocator.
+
+use super::{
+    alloc::{free_pages, kalloc, kalloc_order, kfree},
+    buddy::{block_size, MAX_ORDER},
+};
+use crate::{arch::def::pgrounddown, println, spinlock::Mutex};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rv64::vm::{PhysAddr, PAGE_SIZE};
+
+/// Smallest size class
+const MIN_SIZE: usize = 16;
+/// Number of size classes, up to 2 KiB
+const NCLASS: usize = 8;
+
+#[global_allocator]
+static HEAP: SlabAllocator = SlabAllocator;
+
+/// Link in a free object
+#[repr(C)]
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of a slab's page
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    inuse: usize,
+}
+
+struct SlabCache {
+    size: usize,
+    /// Slabs with free objects
+    partial: *mut Slab,
+    slabs: usize,
+    inuse: usize,
+    allocs: usize,
+    frees: usize,
+}
+
+/// Statistics of a slab cache, or of the large allocations for `size` 0
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    /// Object size
+    pub size: usize,
+    /// Number of pages held
+    pub pages: usize,
+    /// Objects allocated now
+    pub inuse: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+static CACHES: [Mutex<SlabCache>; NCLASS] = {
+    let mut caches = [const { Mutex::new(SlabCache::new(0), "slab") }; NCLASS];
+    let mut i = 0;
+    while i < NCLASS {
+        caches[i] = Mutex::new(SlabCache::new(MIN_SIZE << i), "slab");
+        i += 1;
+    }
+    caches
+};
+
+/// Pages and counters of allocations too large for the slabs
+static LARGE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static LARGE_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static LARGE_FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// Size class of `layout`, `None` if it's too large for the slabs
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_SIZE);
+    let class = size.next_power_of_two().trailing_zeros() - MIN_SIZE.trailing_zeros();
+    (class < NCLASS as u32).then_some(class as usize)
+}
+
+/// Block order of a large allocation of `layout`
+fn large_order(layout: Layout) -> usize {
+    let size = layout.size().max(layout.align());
+    (0..=MAX_ORDER)
+        .find(|&o| block_size(o) >= size)
+        .unwrap_or(MAX_ORDER + 1)
+}
+
+impl SlabCache {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            partial: null_mut(),
+            slabs: 0,
+            inuse: 0,
+            allocs: 0,
+            frees: 0,
+        }
+    }
+
+    /// Offset of the first object in a slab, after the header
+    fn first_object(&self) -> usize {
+        size_of::<Slab>().next_multiple_of(self.size)
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let (next, prev) = ((*slab).next, (*slab).prev);
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    /// Cut a new page into objects.
+    unsafe fn grow(&mut self) -> Option<()> {
+        let page = kalloc(false)?;
+        let slab = page.as_mut_ptr::<Slab>();
+        (*slab).free = null_mut();
+        (*slab).inuse = 0;
+        for off in (self.first_object()..PAGE_SIZE).step_by(self.size).rev() {
+            let obj = (usize::from(page) + off) as *mut FreeObject;
+            (*obj).next = (*slab).free;
+            (*slab).free = obj;
+        }
+        self.link(slab);
+        self.slabs += 1;
+        Some(())
+    }
+
+    unsafe fn alloc(&mut self) -> *mut u8 {
+        if self.partial.is_null() && self.grow().is_none() {
+            return null_mut();
+        }
+        let slab = self.partial;
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).inuse += 1;
+        if (*slab).free.is_null() {
+            // Full, until one of its objects is freed.
+            self.unlink(slab);
+        }
+        self.inuse += 1;
+        self.allocs += 1;
+        obj as *mut u8
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8) {
+        let slab = pgrounddown(ptr as usize) as *mut Slab;
+        let obj = ptr as *mut FreeObject;
+        if (*slab).free.is_null() {
+            self.link(slab);
+        }
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).inuse -= 1;
+        self.inuse -= 1;
+        self.frees += 1;
+
+        // Give back an empty slab, unless it's the last one with room.
+        if (*slab).inuse == 0 && !(self.partial == slab && (*slab).next.is_null()) {
+            self.unlink(slab);
+            self.slabs -= 1;
+            kfree(PhysAddr::from(slab as usize));
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.size,
+            pages: self.slabs,
+            inuse: self.inuse,
+            allocs: self.allocs,
+            frees: self.frees,
+        }
+    }
+}
+
+/// Statistics of each slab cache, then of the large allocations.
+pub fn stats() -> impl Iterator<Item = CacheStats> {
+    let allocs = LARGE_ALLOCS.load(Ordering::Relaxed);
+    let frees = LARGE_FREES.load(Ordering::Relaxed);
+    CACHES
+        .iter()
+        .map(|c| c.lock().stats())
+        .chain(core::iter::once(CacheStats {
+            size: 0,
+            pages: LARGE_PAGES.load(Ordering::Relaxed),
+            inuse: allocs.saturating_sub(frees),
+            allocs,
+            frees,
+        }))
+}
+
+fn out_of_memory(layout: Layout) {
+    println!(
+        "kernel heap: out of memory for {} bytes aligned to {}, {} free pages",
+        layout.size(),
+        layout.align(),
+        free_pages()
+    );
+    for s in stats() {
+        println!("  {:?}", s);
+    }
+}
+
+struct SlabAllocator;
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match size_class(layout) {
+            Some(class) => CACHES[class].lock().alloc(),
+            None => {
+                let order = large_order(layout);
+                match kalloc_order(order, false) {
+                    Some(page) => {
+                        LARGE_PAGES.fetch_add(1 << order, Ordering::Relaxed);
+                        LARGE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+                        page.as_mut_ptr()
+                    }
+                    None => null_mut(),
+                }
+            }
+        };
+        if ptr.is_null() {
+            // `alloc::alloc::handle_alloc_error` panics after this.
+            out_of_memory(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => CACHES[class].lock().free(ptr),
+            None => {
+                LARGE_PAGES.fetch_sub(1 << large_order(layout), Ordering::Relaxed);
+                LARGE_FREES.fetch_add(1, Ordering::Relaxed);
+                kfree(PhysAddr::from(ptr as usize));
+            }
+        }
+    }
+}
+
+const _: () = assert!(size_of::<Slab>() < PAGE_SIZE / 2, "slab header too large");
@@ -0,0 +1,245 @@
+//! Typed pointers into user memory.
+//!
+//! System calls get user addresses as plain integers. These wrap one
+//! with the page table it's an address in and the type it points to,
+//! and only copy whole values between it and the kernel, after checking
+//! that all of them is in user memory with the needed permissions.
+//! A value is copied into the kernel once, so the process can't change
+//! it between a check and its use.
+
+use super::uvm::{UserPageTable, UserPageTableError};
+use crate::arch::def;
+use core::{
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+};
+
+/// Types that can be copied to and from user memory as bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value, since the user can store
+/// any, and there must be no padding, which would copy out
+/// uninitialized kernel memory.
+pub unsafe trait UserData: Copy {}
+
+macro_rules! user_data {
+    ($($t:ty),*) => {
+        $(unsafe impl UserData for $t {})*
+    };
+}
+
+user_data!(u8, i8, u16, i16, u32, i32, u64, i64, usize, isize);
+
+unsafe impl<T: UserData, const N: usize> UserData for [T; N] {}
+
+/// Check that `[addr, addr + len)` doesn't wrap around, and is below
+/// the trapframe, the highest page user code can map.
+fn check_range(addr: usize, len: usize) -> Result<(), UserPageTableError> {
+    match addr.checked_add(len) {
+        Some(end) if end <= def::trap_frame() => Ok(()),
+        _ => Err(UserPageTableError::BadAddress),
+    }
+}
+
+/// A pointer to a `T` in user memory
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    pagetable: UserPageTable,
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: UserData> UserPtr<T> {
+    pub fn new(pagetable: UserPageTable, addr: usize) -> Self {
+        Self {
+            pagetable,
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// The pointer `count` values further on, checked when it's used.
+    pub fn add(&self, count: usize) -> Self {
+        Self::new(
+            self.pagetable,
+            self.addr.wrapping_add(count.wrapping_mul(size_of::<T>())),
+        )
+    }
+
+    /// Copy the value in from user memory.
+    pub fn read(&self) -> Result<T, UserPageTableError> {
+        check_range(self.addr, size_of::<T>())?;
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            self.pagetable
+                .copy_in(value.as_mut_ptr() as *mut u8, self.addr, size_of::<T>())?;
+            Ok(value.assume_init())
+        }
+    }
+
+    /// Copy `value` out to user memory.
+    pub fn write(&self, value: T) -> Result<(), UserPageTableError> {
+        check_range(self.addr, size_of::<T>())?;
+        unsafe {
+            self.pagetable
+                .copy_out(self.addr, &value as *const T as *const u8, size_of::<T>())
+        }
+    }
+}
+
+/// `len` values of type `T` in user memory
+#[derive(Debug)]
+pub struct UserSlice<T> {
+    pagetable: UserPageTable,
+    addr: usize,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserSlice<T> {}
+
+impl<T: UserData> UserSlice<T> {
+    pub fn new(pagetable: UserPageTable, addr: usize, len: usize) -> Self {
+        Self {
+            pagetable,
+            addr,
+            len,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The `i`-th value, if there are that many.
+    pub fn get(&self, i: usize) -> Option<UserPtr<T>> {
+        (i < self.len).then(|| UserPtr::new(self.pagetable, self.addr).add(i))
+    }
+
+    /// Bytes taken by the first `count` values, if there are that many.
+    fn bytes(&self, count: usize) -> Result<usize, UserPageTableError> {
+        if count > self.len {
+            return Err(UserPageTableError::BadAddress);
+        }
+        let len = count
+            .checked_mul(size_of::<T>())
+            .ok_or(UserPageTableError::BadAddress)?;
+        check_range(self.addr, len)?;
+        Ok(len)
+    }
+
+    /// Copy the first `dst.len()` values in from user memory.
+    /// Fails if the slice is shorter.
+    pub fn read(&self, dst: &mut [T]) -> Result<(), UserPageTableError> {
+        let len = self.bytes(dst.len())?;
+        unsafe {
+            self.pagetable
+                .copy_in(dst.as_mut_ptr() as *mut u8, self.addr, len)
+        }
+    }
+
+    /// Copy `src` out to the start of the slice.
+    /// Fails if the slice is shorter.
+    pub fn write(&self, src: &[T]) -> Result<(), UserPageTableError> {
+        let len = self.bytes(src.len())?;
+        unsafe {
+            self.pagetable
+                .copy_out(self.addr, src.as_ptr() as *const u8, len)
+        }
+    }
+}
+
+/// A null-terminated string in user memory
+#[derive(Debug, Clone, Copy)]
+pub struct UserCStr {
+    pagetable: UserPageTable,
+    addr: usize,
+}
+
+impl UserCStr {
+    pub fn new(pagetable: UserPageTable, addr: usize) -> Self {
+        Self { pagetable, addr }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Copy the string in to `buf`, which must have room for its '\0'.
+    /// Returns the string without the '\0', or `InvalidString` if
+    /// it doesn't fit.
+    pub fn read_into<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], UserPageTableError> {
+        check_range(self.addr, 1)?;
+        let max = buf.len().min(def::trap_frame() - self.addr);
+        unsafe {
+            self.pagetable
+                .copy_in_str(buf.as_mut_ptr(), self.addr, max)?;
+        }
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        Ok(&buf[..len])
+    }
+
+    /// Copy the string in, if it fits into `N` bytes with its '\0'.
+    pub fn read_str<const N: usize>(&self) -> Result<UserString<N>, UserPageTableError> {
+        let mut buf = [0; N];
+        let len = self.read_into(&mut buf)?.len();
+        Ok(UserString { buf, len })
+    }
+}
+
+/// A string copied in from user memory, of less than `N` bytes
+#[derive(Clone, Copy)]
+pub struct UserString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> UserString<N> {
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// The string, if it's valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()).ok()
+    }
+}
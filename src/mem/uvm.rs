@@ -1,63 +1,271 @@
 use crate::{
     arch::{
-        def::{pgrounddown, pgroundup},
-        vm::{free_pagetable, PageTable},
+        asid::Asid,
+        def::{pgrounddown, pgroundup, Schema, USER_TOP},
+        uaccess::{copy_user, copy_user_str},
+        vm::{
+            current_pagetable, free_pagetable, free_proc_kernel_pagetable, proc_kernel_pagetable,
+            switch_pagetable, sync_user_mappings, PageTable,
+        },
     },
-    mem::alloc::{kalloc, kfree, page_size, LinkListAllocator, ALLOCATOR},
+    mem::alloc::{kalloc, kfree, kref, page_size, ref_count, Kalloc},
 };
-use core::ptr::addr_of;
-use rv64::vm::{PageTableError, PteFlags, PTE};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rv64::{
+    reg,
+    vm::{PageTableError, PhysAddr, PteFlags, PTE},
+};
+
+/// `PTE::RSW` value of a page shared copy-on-write after fork.
+/// Such a page is mapped read-only, and the first store to it
+/// copies it, or takes it over if no one else shares it anymore.
+const RSW_COW: usize = 0b01;
+
+/// What goes with a user page table besides its pages
+#[derive(Debug)]
+struct AddressSpace {
+    /// ASID of the user page table
+    asid: Asid,
+    /// Kernel page table of the process, which maps the user
+    /// memory below `USER_TOP` with the same pages
+    kernel: *mut PageTable,
+    /// ASID of the kernel page table
+    kernel_asid: Asid,
+    /// References to the kernel page table: the process's, and one
+    /// for each hart that stays on it after the process stopped
+    refs: AtomicUsize,
+}
 
 #[derive(Debug, Clone, Copy)]
-#[repr(transparent)]
-pub struct UserPageTable(*mut PageTable);
+pub struct UserPageTable {
+    table: *mut PageTable,
+    space: *const AddressSpace,
+}
 
 impl UserPageTable {
     pub fn new() -> Option<UserPageTable> {
-        let page = kalloc(true)?;
-        Some(UserPageTable(page.as_mut_ptr::<PageTable>()))
+        let kernel = proc_kernel_pagetable()?;
+        let Some(page) = kalloc(true) else {
+            unsafe { free_proc_kernel_pagetable(kernel) };
+            return None;
+        };
+        let space = AddressSpace {
+            asid: Asid::new(),
+            kernel,
+            kernel_asid: Asid::new(),
+            refs: AtomicUsize::new(1),
+        };
+        Some(UserPageTable {
+            table: page.as_mut_ptr::<PageTable>(),
+            space: Box::into_raw(Box::new(space)),
+        })
     }
 
     #[inline]
     pub const fn null() -> UserPageTable {
-        UserPageTable(core::ptr::null_mut())
+        UserPageTable {
+            table: core::ptr::null_mut(),
+            space: core::ptr::null(),
+        }
+    }
+
+    #[inline]
+    fn space(&self) -> &AddressSpace {
+        unsafe { &*self.space }
     }
 
     #[inline]
     pub fn is_null(&self) -> bool {
-        self.0.is_null()
+        self.table.is_null()
     }
 
     #[inline]
     pub fn as_ptr(&self) -> *const PageTable {
-        self.0
+        self.table
     }
 
     #[inline]
     pub fn as_mut(&self) -> *mut PageTable {
-        self.0
+        self.table
+    }
+
+    /// The ASID this page table runs with
+    #[inline]
+    pub fn asid(&self) -> &Asid {
+        &self.space().asid
+    }
+
+    /// `satp` value of the process's kernel page table.
+    /// Must be called with interrupts off.
+    pub fn kernel_satp(&self) -> usize {
+        let space = self.space();
+        let asid = space.kernel_asid.activate();
+        unsafe { reg::satp.make(Schema::mode(), asid, space.kernel as usize) }
+    }
+
+    /// Switch this hart to the process's kernel page table.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with interrupts off, and the page table must
+    /// stay alive while this hart uses it.
+    pub unsafe fn activate_kernel(&self) {
+        switch_pagetable(self.kernel_satp());
+    }
+
+    /// Keep the process's kernel page table from being freed while
+    /// this hart stays on it, until `release_kernel`.
+    pub fn hold_kernel(&self) {
+        self.space().refs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drop a reference to the process's kernel page table,
+    /// and free it with the last one.
+    ///
+    /// # Safety
+    ///
+    /// This hart must have left it if it held it.
+    pub unsafe fn release_kernel(&self) {
+        if self.space().refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let space = Box::from_raw(self.space as *mut AddressSpace);
+            free_proc_kernel_pagetable(space.kernel);
+        }
+    }
+
+    /// Make a change to the mappings of `[va, va + size)` seen:
+    /// in the process's kernel page table if it's below `USER_TOP`,
+    /// and by the TLBs.
+    fn changed(&self, va: usize, size: usize) {
+        let space = self.space();
+        if va < USER_TOP {
+            unsafe { sync_user_mappings(space.kernel, self.table) };
+            space.kernel_asid.flush(va, size.min(USER_TOP - va));
+        }
+        space.asid.flush(va, size);
+    }
+
+    /// Can `[va, va + len)` be accessed at the same addresses in the
+    /// kernel? Only below `USER_TOP`, with the process's kernel
+    /// page table in use.
+    fn direct(&self, va: usize, len: usize) -> bool {
+        va.checked_add(len).is_some_and(|end| end <= USER_TOP)
+            && current_pagetable() == self.space().kernel
+    }
+
+    /// Check that `[va, va + len)` is in user pages, which are writable
+    /// or copy-on-write if `write`. A store to a copy-on-write page
+    /// faults, and `kernel_trap` copies it then.
+    fn check_access(&self, va: usize, len: usize, write: bool) -> Result<(), UserPageTableError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = va.checked_add(len).ok_or(UserPageTableError::BadAddress)?;
+        for va0 in (pgrounddown(va)..end).step_by(page_size()) {
+            let (_, pte) = unsafe { (*self.table).walk(va0, 0, None::<&Kalloc>) }
+                .map_err(UserPageTableError::PageTableError)?;
+            let flags = pte.flags();
+            if !flags.valid() || !flags.user() || !flags.readable() {
+                return Err(UserPageTableError::BadAddress);
+            }
+            if write && !flags.writable() && flags.rsw() != RSW_COW {
+                return Err(UserPageTableError::BadAddress);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `len` bytes from `src` to user address `dstva`, through the
+    /// process's kernel page table.
+    /// Fails unless all of it is in writable or copy-on-write user pages
+    /// below `USER_TOP`.
+    ///
+    /// # Safety
+    ///
+    /// That page table must be in use, and `src` valid for `len` bytes.
+    pub unsafe fn copy_to_user(
+        &self,
+        dstva: usize,
+        src: *const u8,
+        len: usize,
+    ) -> Result<(), UserPageTableError> {
+        if !self.direct(dstva, len) {
+            return Err(UserPageTableError::BadAddress);
+        }
+        self.check_access(dstva, len, true)?;
+        copy_user(dstva as *mut u8, src, len).map_err(|_| UserPageTableError::BadAddress)
+    }
+
+    /// Copy `len` bytes from user address `srcva` to `dst`, through the
+    /// process's kernel page table.
+    /// Fails unless all of it is in user pages below `USER_TOP`.
+    ///
+    /// # Safety
+    ///
+    /// That page table must be in use, and `dst` valid for `len` bytes.
+    pub unsafe fn copy_from_user(
+        &self,
+        dst: *mut u8,
+        srcva: usize,
+        len: usize,
+    ) -> Result<(), UserPageTableError> {
+        if !self.direct(srcva, len) {
+            return Err(UserPageTableError::BadAddress);
+        }
+        self.check_access(srcva, len, false)?;
+        copy_user(dst, srcva as *const u8, len).map_err(|_| UserPageTableError::BadAddress)
+    }
+
+    /// Like `copy_from_user`, for the null-terminated string at `srcva`
+    /// of at most `max` bytes with the '\0'.
+    ///
+    /// # Safety
+    ///
+    /// Like `copy_from_user`, with `dst` valid for `max` bytes.
+    pub unsafe fn copy_str_from_user(
+        &self,
+        dst: *mut u8,
+        srcva: usize,
+        max: usize,
+    ) -> Result<(), UserPageTableError> {
+        let mut copied = 0;
+        while copied < max {
+            let va = srcva + copied;
+            let n = (pgrounddown(va) + page_size() - va).min(max - copied);
+            if !self.direct(va, n) {
+                return Err(UserPageTableError::BadAddress);
+            }
+            self.check_access(va, n, false)?;
+            match copy_user_str(dst.add(copied), va as *const u8, n) {
+                Ok(len) if len < n => return Ok(()),
+                Ok(_) => copied += n,
+                Err(_) => return Err(UserPageTableError::BadAddress),
+            }
+        }
+        Err(UserPageTableError::InvalidString)
     }
 
     /// Allocate PTEs and physical memory to grow process from oldsz to
-    /// newsz, which need not be page aligned.  Returns new size or 0 on error.
-    pub fn alloc(&mut self, oldsz: usize, newsz: usize) -> Option<usize> {
+    /// newsz, which need not be page aligned. The pages are always
+    /// readable and user accessible, `xperm` adds write or execute permission.
+    /// Returns new size or `None` on error.
+    pub fn alloc(&mut self, oldsz: usize, newsz: usize, xperm: PteFlags) -> Option<usize> {
         if newsz < oldsz {
             return Some(oldsz);
         }
 
         let pg_size = page_size();
-        let perm = PteFlags::new()
-            .set_writable(true)
-            .set_executable(true)
-            .set_readable(true)
-            .set_user(true);
-        let alloc = unsafe { &*addr_of!(ALLOCATOR) };
+        let perm = xperm.set_readable(true).set_user(true);
+        let alloc = &Kalloc;
 
         let oldsz = pgroundup(oldsz);
         for a in (oldsz..newsz).step_by(pg_size) {
             if let Some(page) = kalloc(true) {
                 unsafe {
-                    if (*self.0).map_pages(a, pg_size, page, perm, alloc).is_err() {
+                    if (*self.table)
+                        .map_pages(a, pg_size, page, perm, alloc)
+                        .is_err()
+                    {
                         kfree(page);
                         self.dealloc(a, oldsz);
                         return None;
@@ -68,6 +276,7 @@ impl UserPageTable {
                 return None;
             }
         }
+        self.changed(oldsz, pgroundup(newsz) - oldsz);
         Some(newsz)
     }
 
@@ -91,6 +300,18 @@ impl UserPageTable {
         newsz
     }
 
+    /// Look up a virtual address, return the physical address,
+    /// or `None` if not mapped.
+    /// Can only be used to look up user pages.
+    pub fn walk_addr(&self, va: usize) -> Option<PhysAddr> {
+        let (_, pte) = unsafe { (*self.table).walk(va, 0, None::<&Kalloc>) }.ok()?;
+        let flags = pte.flags();
+        if !flags.valid() || !flags.user() {
+            return None;
+        }
+        Some(pte.addr())
+    }
+
     pub unsafe fn map(
         &mut self,
         va: usize,
@@ -98,38 +319,36 @@ impl UserPageTable {
         pa: usize,
         perm: PteFlags,
     ) -> Result<(), UserPageTableError> {
-        let alloc = &*addr_of!(ALLOCATOR);
-        (*self.0)
+        let alloc = &Kalloc;
+        (*self.table)
             .map_pages(va, sz, pa, perm, alloc)
-            .map_err(|e| UserPageTableError::PageTableError(e))
+            .map_err(|e| UserPageTableError::PageTableError(e))?;
+        self.changed(va, sz);
+        Ok(())
     }
 
     /// Remove npages of mappings starting from va. va must be
-    /// page-aligned. The mappings must exist.
+    /// page-aligned. Heap pages that were never touched aren't
+    /// mapped, and are skipped.
     /// Optionally free the physical memory.
+    /// A superpage must be unmapped as a whole.
     pub unsafe fn unmap(&mut self, va: usize, npages: usize, do_free: bool) {
         assert_eq!(va % page_size(), 0, "uvmunmap: not aligned");
-        (va..va + npages * page_size())
-            .step_by(page_size())
-            .for_each(|a| {
-                let (_, pte) = (*self.0)
-                    .walk(a, 0, None::<&LinkListAllocator>)
-                    .expect("uvmunmap: walk");
-                let flags = pte.flags();
-                assert!(flags.valid());
-                assert!(flags.is_leaf());
-                if do_free {
-                    kfree(pte.addr());
-                }
-                *pte = PTE::new_invalid();
-            });
+        (*self.table)
+            .unmap_range(va, npages * page_size(), do_free.then_some(&Kalloc))
+            .expect("uvmunmap");
+        self.changed(va, npages * page_size());
     }
 
     pub unsafe fn free(&mut self, sz: usize) {
         if sz > 0 {
             self.unmap(0, pgroundup(sz) / page_size(), true);
         }
-        free_pagetable(self.0);
+        free_pagetable(self.table);
+        // Its ASIDs aren't given out again until the next generation,
+        // so what the TLBs still hold of it is harmless, and so is
+        // what a hart still on the kernel page table may walk.
+        self.release_kernel();
     }
 
     /// mark a PTE invalid for user access.
@@ -137,48 +356,137 @@ impl UserPageTable {
     /// Safety: va must be a valid virtual address.
     pub unsafe fn clear(&mut self, va: usize) {
         let (_, pte) =
-            unsafe { (*self.0).walk(va, 0, None::<&LinkListAllocator>) }.expect("uvmclear: walk");
+            unsafe { (*self.table).walk(va, 0, None::<&Kalloc>) }.expect("uvmclear: walk");
         *pte = PTE::new(pte.addr(), pte.flags().set_user(false));
+        self.changed(va, page_size());
     }
 
-    /// Given a parent process's page table, copy
-    /// its memory into a child's page table.
-    /// Copies both the page table and the
-    /// physical memory.
-    /// returns 0 on success, -1 on failure.
+    /// Given a parent process's page table, share
+    /// its memory with a child's page table.
+    /// Writable pages become read-only copy-on-write
+    /// pages in both, see `resolve_cow`.
+    /// returns `None` on failure.
     /// frees any allocated pages on failure.
-    /// Safety: `sz` must be a valid size.
-    pub unsafe fn clone(&self, sz: usize) -> Option<UserPageTable> {
-        let mut new = UserPageTable::new()?;
-        let pg_size = page_size();
-        let alloc = &*addr_of!(ALLOCATOR);
-        for a in (0..sz).step_by(pg_size) {
-            let (_, pte) = unsafe { (*self.0).walk(a, 0, None::<&LinkListAllocator>) }
-                .expect("UserPageTable::copy: pte should exist");
-            let flags = pte.flags();
-            assert!(flags.valid(), "UserPageTable::copy: page not present");
-
-            let mem = kalloc(true).or_else(|| {
-                new.unmap(0, a / pg_size, true);
-                None
-            })?;
+    ///
+    /// # Safety
+    ///
+    /// `sz` must be the size of the parent's memory, and `new` a page
+    /// table without mappings below it.
+    pub unsafe fn copy_to(&self, new: &mut UserPageTable, sz: usize) -> Option<()> {
+        self.copy_range(new, 0, sz, false)
+    }
 
-            core::ptr::copy_nonoverlapping(
-                pte.addr().as_ptr::<u8>(),
-                mem.as_mut_ptr::<u8>(),
-                pg_size,
-            );
+    /// Like `copy_to`, for the page-aligned range `[start, end)`.
+    /// With `shared`, writable pages stay writable in both page
+    /// tables, so that stores show up in both.
+    ///
+    /// # Safety
+    ///
+    /// `[start, end)` must be user memory of the parent, pages of it
+    /// that were never touched are skipped. Nothing may be mapped
+    /// there in `new`.
+    pub unsafe fn copy_range(
+        &self,
+        new: &mut UserPageTable,
+        start: usize,
+        end: usize,
+        shared: bool,
+    ) -> Option<()> {
+        let end = pgroundup(end);
+        if !shared {
+            (*self.table)
+                .protect_range(start, end - start, |flags| {
+                    if flags.writable() {
+                        flags.set_writable(false).set_rsw(RSW_COW)
+                    } else {
+                        flags
+                    }
+                })
+                .ok()?;
+            self.changed(start, end - start);
+        }
 
-            (*new.0)
-                .map_pages(a, pg_size, mem, flags, alloc)
+        for (va, pte, width) in (*self.table).leaves(start, end - start) {
+            let (va, pa) = (usize::from(va), pte.addr());
+            (*new.table)
+                .map_pages_as(va, width.size(), pa, pte.flags(), Some(width), &Kalloc)
                 .ok()
                 .or_else(|| {
-                    kfree(mem);
-                    new.unmap(0, a / pg_size, true);
+                    new.unmap(start, (va - start) / page_size(), true);
                     None
                 })?;
+            kref(pa);
         }
-        Some(new)
+        new.changed(start, end - start);
+        Some(())
+    }
+
+    /// Has the user page at `va` been written since it was mapped?
+    pub fn dirty(&self, va: usize) -> bool {
+        match unsafe { (*self.table).walk(va, 0, None::<&Kalloc>) } {
+            Ok((_, pte)) => pte.flags().valid() && pte.flags().user() && pte.flags().dirty(),
+            Err(_) => false,
+        }
+    }
+
+    /// Map a zeroed, writable page at `va` if nothing is mapped there.
+    /// Heap pages are only allocated like this, on first use.
+    pub fn lazy_alloc(&mut self, va: usize) -> Result<(), UserPageTableError> {
+        let va0 = pgrounddown(va);
+        if let Ok((_, pte)) = unsafe { (*self.table).walk(va0, 0, None::<&Kalloc>) } {
+            if pte.flags().valid() {
+                return Err(UserPageTableError::BadAddress);
+            }
+        }
+
+        let page = kalloc(true).ok_or(UserPageTableError::OutOfMemory)?;
+        let perm = PteFlags::new()
+            .set_readable(true)
+            .set_writable(true)
+            .set_user(true);
+        unsafe {
+            let alloc = &Kalloc;
+            (*self.table)
+                .map_pages(va0, page_size(), page, perm, alloc)
+                .map_err(|e| {
+                    kfree(page);
+                    UserPageTableError::PageTableError(e)
+                })?;
+        }
+        self.changed(va0, page_size());
+        Ok(())
+    }
+
+    /// Make the copy-on-write page at `va` writable, by copying it,
+    /// or by taking it over if this page table is its only user.
+    /// Fails if `va` isn't in a copy-on-write page.
+    pub fn resolve_cow(&mut self, va: usize) -> Result<(), UserPageTableError> {
+        let va0 = pgrounddown(va);
+        let (_, pte) = unsafe { (*self.table).walk(va0, 0, None::<&Kalloc>) }
+            .map_err(UserPageTableError::PageTableError)?;
+        let flags = pte.flags();
+        if !flags.valid() || !flags.user() || flags.rsw() != RSW_COW {
+            return Err(UserPageTableError::BadAddress);
+        }
+
+        let pa = pte.addr();
+        let flags = flags.set_writable(true).set_rsw(0);
+        if ref_count(pa) == 1 {
+            *pte = PTE::new(pa, flags);
+        } else {
+            let mem = kalloc(false).ok_or(UserPageTableError::OutOfMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    pa.as_ptr::<u8>(),
+                    mem.as_mut_ptr::<u8>(),
+                    page_size(),
+                );
+                *pte = PTE::new(mem, flags);
+                kfree(pa);
+            }
+        }
+        self.changed(va0, page_size());
+        Ok(())
     }
 
     /// Copy from kernel to user.
@@ -190,15 +498,29 @@ impl UserPageTable {
         src: *const u8,
         len: usize,
     ) -> Result<(), UserPageTableError> {
+        if self.direct(dstva, len) {
+            return self.copy_to_user(dstva, src, len);
+        }
+
         let mut len = len;
         let mut src = src;
         let mut dst = dstva;
         let pg_size = page_size();
         while len > 0 {
             let va0 = pgrounddown(dst);
-            let (_, pte) = (*self.0)
-                .walk(va0, 0, None::<&LinkListAllocator>)
+            let (_, pte) = (*self.table)
+                .walk(va0, 0, None::<&Kalloc>)
                 .map_err(|e| UserPageTableError::PageTableError(e))?;
+            let flags = pte.flags();
+            if !flags.valid() || !flags.user() {
+                return Err(UserPageTableError::BadAddress);
+            }
+            if !flags.writable() {
+                // Store to a copy-on-write page, like user code would;
+                // this updates the PTE.
+                let mut pagetable = *self;
+                pagetable.resolve_cow(va0)?;
+            }
             let n = core::cmp::min(pg_size - (dst - va0), len);
             core::ptr::copy_nonoverlapping(src, pte.addr().as_mut_ptr::<u8>().add(dst - va0), n);
             len -= n;
@@ -217,15 +539,23 @@ impl UserPageTable {
         srcva: usize,
         len: usize,
     ) -> Result<(), UserPageTableError> {
+        if self.direct(srcva, len) {
+            return self.copy_from_user(dst, srcva, len);
+        }
+
         let mut len = len;
         let mut src = srcva;
         let mut dst = dst;
         let pg_size = page_size();
         while len > 0 {
             let va0 = pgrounddown(src);
-            let (_, pte) = (*self.0)
-                .walk(va0, 0, None::<&LinkListAllocator>)
+            let (_, pte) = (*self.table)
+                .walk(va0, 0, None::<&Kalloc>)
                 .map_err(|e| UserPageTableError::PageTableError(e))?;
+            let flags = pte.flags();
+            if !flags.valid() || !flags.user() {
+                return Err(UserPageTableError::BadAddress);
+            }
             let n = core::cmp::min(pg_size - (src - va0), len);
             core::ptr::copy_nonoverlapping(pte.addr().as_ptr::<u8>().add(src - va0), dst, n);
             len -= n;
@@ -245,15 +575,24 @@ impl UserPageTable {
         srcva: usize,
         max: usize,
     ) -> Result<(), UserPageTableError> {
+        if self.direct(srcva, 1) {
+            return self.copy_str_from_user(dst, srcva, max);
+        }
+
         let mut got_null = false;
+        let mut dst = dst;
         let pg_size = page_size();
         let mut max = max;
         let mut srcva = srcva;
         while !got_null && max > 0 {
             let va0 = pgrounddown(srcva);
-            let (_, pte) = (*self.0)
-                .walk(va0, 0, None::<&LinkListAllocator>)
+            let (_, pte) = (*self.table)
+                .walk(va0, 0, None::<&Kalloc>)
                 .map_err(|e| UserPageTableError::PageTableError(e))?;
+            let flags = pte.flags();
+            if !flags.valid() || !flags.user() {
+                return Err(UserPageTableError::BadAddress);
+            }
             let n = core::cmp::min(pg_size - (srcva - va0), max);
             let p = pte.addr().as_ptr::<u8>().add(srcva - va0);
             for i in 0..n {
@@ -267,6 +606,7 @@ impl UserPageTable {
                 }
             }
             max -= n;
+            dst = dst.add(n);
             srcva = va0 + page_size();
         }
 
@@ -281,11 +621,15 @@ impl UserPageTable {
 pub enum UserPageTableError {
     PageTableError(PageTableError),
     InvalidString,
+    /// The address isn't mapped with the needed permissions
+    BadAddress,
+    /// No memory for a private copy of a copy-on-write page
+    OutOfMemory,
 }
 
 impl Into<usize> for UserPageTable {
     #[inline]
     fn into(self) -> usize {
-        self.0 as usize
+        self.table as usize
     }
 }
@@ -0,0 +1,186 @@
+//! Pipes.
+//!
+//! A pipe is a fixed ring buffer in its own page, shared by
+//! a read-only and a write-only file. Readers sleep on `nread`
+//! until there's data or no writer is left, writers sleep on
+//! `nwrite` while the buffer is full.
+
+use crate::{
+    arch::def::PG_SIZE,
+    file::{self, FileRef, FileType},
+    mem::alloc::{kalloc, kfree},
+    proc::{either_copy_in, either_copy_out, Proc, CPU},
+    spinlock::{Mutex, MutexGuard},
+};
+use core::{
+    mem::size_of,
+    ptr::{self, addr_of, NonNull},
+};
+
+const PIPESIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// Wrote to a pipe without readers
+    Broken,
+    /// The process was killed while waiting
+    Interrupted,
+}
+
+#[derive(Debug)]
+struct PipeData {
+    data: [u8; PIPESIZE],
+    nread: usize,    // number of bytes read
+    nwrite: usize,   // number of bytes written
+    readopen: bool,  // read fd is still open
+    writeopen: bool, // write fd is still open
+}
+
+#[derive(Debug)]
+pub struct Pipe(Mutex<PipeData>);
+
+const _: () = assert!(size_of::<Pipe>() <= PG_SIZE, "pipe must fit in a page");
+
+/// A pipe shared by the two ends' files, freed by the last `close`.
+#[derive(Debug, Clone, Copy)]
+pub struct PipeRef(NonNull<Pipe>);
+
+/// Create a pipe, return its read and write ends.
+/// Return `None` if out of memory or files.
+pub fn alloc() -> Option<(FileRef, FileRef)> {
+    let page = kalloc(false)?;
+    let pipe = PipeRef(NonNull::new(page.as_mut_ptr::<Pipe>())?);
+    unsafe {
+        ptr::write(
+            pipe.0.as_ptr(),
+            Pipe(Mutex::new(
+                PipeData {
+                    data: [0; PIPESIZE],
+                    nread: 0,
+                    nwrite: 0,
+                    readopen: true,
+                    writeopen: true,
+                },
+                "pipe",
+            )),
+        );
+    }
+
+    let Some(rf) = file::alloc(
+        FileType::Pipe {
+            pipe,
+            writable: false,
+        },
+        true,
+        false,
+    ) else {
+        unsafe { kfree(page) };
+        return None;
+    };
+    let Some(wf) = file::alloc(
+        FileType::Pipe {
+            pipe,
+            writable: true,
+        },
+        false,
+        true,
+    ) else {
+        // Closing both ends frees the pipe.
+        drop(rf);
+        pipe.close(true);
+        return None;
+    };
+    Some((rf, wf))
+}
+
+fn read_chan(pipe: &MutexGuard<'_, PipeData>) -> usize {
+    addr_of!(pipe.nread) as usize
+}
+
+fn write_chan(pipe: &MutexGuard<'_, PipeData>) -> usize {
+    addr_of!(pipe.nwrite) as usize
+}
+
+impl PipeRef {
+    fn get(&self) -> &Pipe {
+        // The page stays allocated until both ends are closed.
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Close one end of the pipe, free it once both are closed.
+    pub fn close(self, writable: bool) {
+        let mut pipe = self.get().0.lock();
+        if writable {
+            pipe.writeopen = false;
+            Proc::wake_up(read_chan(&pipe));
+        } else {
+            pipe.readopen = false;
+            Proc::wake_up(write_chan(&pipe));
+        }
+        if !pipe.readopen && !pipe.writeopen {
+            drop(pipe);
+            unsafe { kfree(self.0.as_ptr()) };
+        }
+    }
+
+    /// Write `n` bytes from the user address `addr`,
+    /// sleeping while the pipe is full.
+    pub fn write(&self, addr: usize, n: usize) -> Result<usize, PipeError> {
+        let p = unsafe { CPU::this_proc_ref() };
+        let mut pipe = self.get().0.lock();
+
+        let mut i = 0;
+        while i < n {
+            if !pipe.readopen {
+                return Err(PipeError::Broken);
+            }
+            if p.killed() {
+                return Err(PipeError::Interrupted);
+            }
+            if pipe.nwrite == pipe.nread + PIPESIZE {
+                Proc::wake_up(read_chan(&pipe));
+                let chan = write_chan(&pipe);
+                pipe = p.sleep(chan, pipe);
+            } else {
+                let mut ch = 0u8;
+                if either_copy_in(true, core::slice::from_mut(&mut ch), addr + i).is_err() {
+                    break;
+                }
+                let w = pipe.nwrite % PIPESIZE;
+                pipe.data[w] = ch;
+                pipe.nwrite += 1;
+                i += 1;
+            }
+        }
+        Proc::wake_up(read_chan(&pipe));
+        Ok(i)
+    }
+
+    /// Read up to `n` bytes to the user address `addr`,
+    /// sleeping until there is data or all writers are gone.
+    /// Return 0 at end of file.
+    pub fn read(&self, addr: usize, n: usize) -> Result<usize, PipeError> {
+        let p = unsafe { CPU::this_proc_ref() };
+        let mut pipe = self.get().0.lock();
+
+        while pipe.nread == pipe.nwrite && pipe.writeopen {
+            if p.killed() {
+                return Err(PipeError::Interrupted);
+            }
+            let chan = read_chan(&pipe);
+            pipe = p.sleep(chan, pipe);
+        }
+
+        let mut i = 0;
+        while i < n && pipe.nread != pipe.nwrite {
+            let ch = pipe.data[pipe.nread % PIPESIZE];
+            if either_copy_out(true, addr + i, &[ch]).is_err() {
+                break;
+            }
+            pipe.nread += 1;
+            i += 1;
+        }
+        Proc::wake_up(write_chan(&pipe));
+        Ok(i)
+    }
+}
@@ -1,6 +1,12 @@
 mod cpu;
+pub mod elf;
+mod exec;
+mod runq;
+pub mod sched;
 mod state;
 mod switch;
+mod vma;
 
 pub use cpu::*;
+pub use exec::*;
 pub use state::*;
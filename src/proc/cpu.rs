@@ -1,23 +1,46 @@
-use core::ptr::{addr_of, NonNull};
+use core::{
+    ptr::{addr_of, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use super::{state, switch::Context, Proc};
+use super::{runq::RunQueue, sched, state, switch::Context, Proc};
 use crate::{arch, spinlock};
 
 extern "C" {
     fn switch(save: *const Context, load: *const Context);
 }
 
-pub static mut CPUS: [CPU; crate::NCPU] = [CPU::new(); crate::NCPU];
+pub static mut CPUS: [CPU; crate::NCPU] = [const { CPU::new() }; crate::NCPU];
 pub static mut TICKS: spinlock::Mutex<usize> = spinlock::Mutex::new(0, "time");
 
+/// CPUs that run the scheduler, a bit for each
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
 /// Per-CPU state
-#[derive(Clone, Copy, Debug)]
+#[derive(Debug)]
 #[repr(C)]
 pub struct CPU {
     proc: Option<NonNull<Proc>>, // Guarantee: `proc` is either `None` or `Some(valid_pointer)`
     context: Context,
     noff: i32,
     interrupt_enabled: bool,
+    /// Runnable processes to run on this CPU
+    run_queue: spinlock::Mutex<RunQueue>,
+    /// Timer interrupts taken
+    ticks: AtomicUsize,
+    /// Processes moved to this CPU's run queue from another
+    migrations_in: AtomicUsize,
+    /// Processes moved from this CPU's run queue to another
+    migrations_out: AtomicUsize,
+}
+
+/// Scheduling counters of a CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuStats {
+    pub id: usize,
+    pub queued: usize,
+    pub migrations_in: usize,
+    pub migrations_out: usize,
 }
 
 impl CPU {
@@ -27,18 +50,81 @@ impl CPU {
             context: Context::new(),
             noff: 0,
             interrupt_enabled: false,
+            run_queue: spinlock::Mutex::new(RunQueue::new(), "runq"),
+            ticks: AtomicUsize::new(0),
+            migrations_in: AtomicUsize::new(0),
+            migrations_out: AtomicUsize::new(0),
         }
     }
 
+    /// The cpu struct of CPU `id`, for the parts other CPUs may use
+    #[inline]
+    pub fn get(id: usize) -> &'static CPU {
+        unsafe { &(*addr_of!(CPUS))[id] }
+    }
+
+    /// CPUs that run the scheduler, a bit for each
+    #[inline]
+    pub fn online() -> usize {
+        ONLINE.load(Ordering::Relaxed)
+    }
+
+    /// Count this CPU in, before it runs the scheduler.
+    pub fn set_online() {
+        ONLINE.fetch_or(1 << arch::cpuid(), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn run_queue(&self) -> &spinlock::Mutex<RunQueue> {
+        &self.run_queue
+    }
+
+    /// Timer interrupts taken
+    #[inline]
+    pub fn ticks(&self) -> usize {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    /// Count a process moved from the run queue of `from` to that of `to`.
+    pub fn count_migration(from: usize, to: usize) {
+        CPU::get(from)
+            .migrations_out
+            .fetch_add(1, Ordering::Relaxed);
+        CPU::get(to).migrations_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Scheduling counters of the online CPUs
+    pub fn stats() -> impl Iterator<Item = CpuStats> {
+        let online = CPU::online();
+        (0..crate::NCPU)
+            .filter(move |id| online & (1 << id) != 0)
+            .map(|id| {
+                let c = CPU::get(id);
+                CpuStats {
+                    id,
+                    queued: c.run_queue.lock().len(),
+                    migrations_in: c.migrations_in.load(Ordering::Relaxed),
+                    migrations_out: c.migrations_out.load(Ordering::Relaxed),
+                }
+            })
+    }
+
     /// Return this CPU's cpu struct.
-    /// Interrupts must be disabled.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts must be disabled, so the process can't move to
+    /// another CPU while it uses the struct.
     #[inline]
     pub unsafe fn this_mut() -> *mut CPU {
         &mut CPUS[arch::cpuid()]
     }
 
     /// Return this CPU's cpu struct.
-    /// Interrupts must be disabled.
+    ///
+    /// # Safety
+    ///
+    /// Like `this_mut`.
     #[inline]
     pub unsafe fn this() -> *const CPU {
         &CPUS[arch::cpuid()]
@@ -55,7 +141,11 @@ impl CPU {
 
     #[inline]
     /// Currently running process on this CPU
-    /// Safety: Caller must ensure that there is a process running on this CPU
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that there is a process running on this CPU,
+    /// and only use the reference from that process.
     pub unsafe fn this_proc_ref() -> &'static mut Proc {
         let _guard = CPU::push_off();
         CPUS[arch::cpuid()]
@@ -64,6 +154,12 @@ impl CPU {
             .as_mut()
     }
 
+    /// Disable interrupts until the returned lock is dropped, counting
+    /// nested calls so the outermost one turns them back on.
+    ///
+    /// # Safety
+    ///
+    /// Locks must be dropped in the reverse order they were taken.
     #[inline]
     pub unsafe fn push_off() -> InterruptLock {
         let int_enabled = arch::is_intr_on();
@@ -76,6 +172,11 @@ impl CPU {
         InterruptLock
     }
 
+    /// Undo a `push_off`, when its lock is dropped.
+    ///
+    /// # Safety
+    ///
+    /// Must be this CPU's struct, with interrupts still disabled.
     #[inline]
     pub unsafe fn pop_off(&mut self) {
         assert!(!arch::is_intr_on(), "pop_off - interruptible");
@@ -87,12 +188,22 @@ impl CPU {
     }
 
     /// Switch to another context, return to `switch_back`
+    ///
+    /// # Safety
+    ///
+    /// Must be this CPU's struct, with interrupts disabled, and `p` the
+    /// saved context of a process that is ready to run on it.
     #[inline]
     pub unsafe fn switch_to(&self, p: *const Context) {
         switch(&self.context, p);
     }
 
     /// Switch back to origin context, return to `switch_to`
+    ///
+    /// # Safety
+    ///
+    /// Must be this CPU's struct, with interrupts disabled, called by
+    /// the process it switched to, with `p` where to save its context.
     #[inline]
     pub unsafe fn switch_back(&self, p: *const Context) {
         switch(p, &self.context);
@@ -131,9 +242,23 @@ impl Drop for InterruptLock {
 }
 
 pub fn timer_interrupt() {
-    unsafe {
-        let mut ticks = TICKS.lock();
-        *ticks += 1;
-        state::Proc::wake_up(addr_of!(*ticks) as usize);
+    CPU::get(arch::cpuid())
+        .ticks
+        .fetch_add(1, Ordering::Relaxed);
+    if arch::cpuid() == 0 {
+        let ticks = unsafe {
+            let mut ticks = TICKS.lock();
+            *ticks += 1;
+            state::Proc::wake_up(addr_of!(*ticks) as usize);
+            *ticks
+        };
+        if ticks % sched::BOOST_TICKS == 0 {
+            state::Proc::boost();
+        }
+    }
+
+    // Charge the tick to the process it interrupted.
+    if let Some(p) = CPU::this_proc() {
+        unsafe { p.as_ref() }.tick();
     }
 }
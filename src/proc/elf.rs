@@ -0,0 +1,146 @@
+//! Format of an ELF64 executable file
+
+use crate::fs::InodeGuard;
+use core::mem::{size_of, MaybeUninit};
+
+/// "\x7FELF" in little endian
+pub const ELF_MAGIC: u32 = 0x464C_457F;
+
+/// `e_ident[EI_CLASS]` for 64-bit objects
+const ELF_CLASS_64: u8 = 2;
+/// `e_ident[EI_DATA]` for little endian objects
+const ELF_DATA_LSB: u8 = 1;
+/// `e_type` for executable files
+const ELF_TYPE_EXEC: u16 = 2;
+/// `e_machine` for RISC-V
+const ELF_MACHINE_RISCV: u16 = 243;
+
+/// Values for `ProgramHeader::type_`
+pub const ELF_PROG_LOAD: u32 = 1;
+
+/// Flag bits for `ProgramHeader::flags`
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub const ELF_PROG_FLAG_READ: u32 = 4;
+
+/// Anything an ELF image can be read from.
+pub trait ElfSource {
+    /// Read up to `buf.len()` bytes starting at `offset`,
+    /// return the number of bytes read.
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> usize;
+}
+
+impl ElfSource for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> usize {
+        if offset >= self.len() {
+            return 0;
+        }
+        let n = buf.len().min(self.len() - offset);
+        buf[..n].copy_from_slice(&self[offset..offset + n]);
+        n
+    }
+}
+
+impl ElfSource for InodeGuard<'_> {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> usize {
+        let (Ok(off), Ok(n)) = (u32::try_from(offset), u32::try_from(buf.len())) else {
+            return 0;
+        };
+        self.read(false, buf.as_mut_ptr() as usize, off, n)
+            .unwrap_or(0)
+    }
+}
+
+/// Read a plain-old-data structure at `offset`.
+/// Return `None` if the source is too short.
+fn read_struct<T: Copy>(src: &(impl ElfSource + ?Sized), offset: usize) -> Option<T> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    if src.read_at(buf, offset) != size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { value.assume_init() })
+}
+
+/// File header
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub magic: u32, // must equal ELF_MAGIC
+    pub elf: [u8; 12],
+    pub type_: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+impl ElfHeader {
+    /// Read the file header at the beginning of `src`.
+    pub fn read(src: &(impl ElfSource + ?Sized)) -> Option<ElfHeader> {
+        read_struct(src, 0)
+    }
+
+    /// Check that this is a little endian RISC-V 64-bit executable.
+    pub fn is_valid(&self) -> bool {
+        self.magic == ELF_MAGIC
+            && self.elf[0] == ELF_CLASS_64
+            && self.elf[1] == ELF_DATA_LSB
+            && self.type_ == ELF_TYPE_EXEC
+            && self.machine == ELF_MACHINE_RISCV
+            && self.phentsize as usize == size_of::<ProgramHeader>()
+    }
+
+    /// Read the i-th program header from `src`.
+    pub fn program_header(
+        &self,
+        src: &(impl ElfSource + ?Sized),
+        i: usize,
+    ) -> Option<ProgramHeader> {
+        if i >= self.phnum as usize {
+            return None;
+        }
+        let offset = (self.phoff as usize).checked_add(i * size_of::<ProgramHeader>())?;
+        read_struct(src, offset)
+    }
+}
+
+/// Program section header
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub type_: u32,
+    pub flags: u32,
+    pub off: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    #[inline]
+    pub fn is_load(&self) -> bool {
+        self.type_ == ELF_PROG_LOAD
+    }
+
+    #[inline]
+    pub fn executable(&self) -> bool {
+        self.flags & ELF_PROG_FLAG_EXEC != 0
+    }
+
+    #[inline]
+    pub fn writable(&self) -> bool {
+        self.flags & ELF_PROG_FLAG_WRITE != 0
+    }
+}
@@ -0,0 +1,216 @@
+use super::{
+    elf::{ElfHeader, ElfSource, ProgramHeader},
+    Proc, CPU,
+};
+use crate::{
+    arch::def::{pgroundup, PG_SIZE, USER_TOP},
+    fs::{log, namei},
+    mem::uvm::UserPageTable,
+    MAXARG,
+};
+use core::mem::size_of;
+use rv64::vm::PteFlags;
+
+/// Number of user stack pages, not including the guard page
+pub const USER_STACK_PAGES: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// No executable at the given path
+    NotFound,
+    /// Not a valid RISC-V ELF64 executable
+    BadFormat,
+    /// Ran out of memory while building the new image
+    OutOfMemory,
+    /// Arguments don't fit into `MAXARG` or the user stack
+    TooManyArgs,
+    /// Failed to copy arguments into the new image
+    BadAddress,
+}
+
+/// Replace the image of the current process with the program at `path`.
+/// Return `argc` on success, which ends up in `a0`, the first argument
+/// to `main(argc, argv)`. On failure the old image is left intact.
+pub fn exec(path: &str, argv: &[&str]) -> Result<usize, ExecError> {
+    let p = unsafe { CPU::this_proc_ref() };
+
+    // Shared file mappings don't survive a successful exec,
+    // and writing them back needs transactions of its own.
+    p.write_back_vmas();
+
+    log::begin_op();
+    let result = namei(path)
+        .ok_or(ExecError::NotFound)
+        .and_then(|ip| load(p, &ip.lock(), path, argv));
+    log::end_op();
+
+    if result.is_ok() {
+        p.forget_vmas();
+    }
+    result
+}
+
+/// Build a new user image for `p` from the ELF executable in `src`,
+/// and switch to it only after everything has been loaded.
+pub fn load(
+    p: &mut Proc,
+    src: &(impl ElfSource + ?Sized),
+    path: &str,
+    argv: &[&str],
+) -> Result<usize, ExecError> {
+    if argv.len() > MAXARG {
+        return Err(ExecError::TooManyArgs);
+    }
+
+    let elf = ElfHeader::read(src)
+        .filter(ElfHeader::is_valid)
+        .ok_or(ExecError::BadFormat)?;
+
+    let mut pagetable = p.alloc_pagetable().ok_or(ExecError::OutOfMemory)?;
+    let mut size = 0;
+
+    let sp = match build_image(&mut pagetable, &mut size, src, &elf, argv) {
+        Ok(sp) => sp,
+        Err(err) => {
+            Proc::release_pagetable(pagetable, size);
+            return Err(err);
+        }
+    };
+
+    // Save program name for debugging.
+    let name = path.rsplit('/').next().unwrap_or(path);
+
+    // Commit to the user image.
+    let (old_pagetable, old_size) = p.swap_image(pagetable, size, name.as_bytes());
+    unsafe {
+        // Leave the old image's kernel page table before it's freed.
+        let _int_lock = CPU::push_off();
+        pagetable.activate_kernel();
+    }
+    unsafe {
+        let trapframe = p.trapframe().expect("exec: no trapframe").as_mut();
+        trapframe.epc = elf.entry as usize; // initial program counter = main
+        trapframe.sp = sp; // initial stack pointer
+        trapframe.a1 = sp; // argv
+    }
+    Proc::release_pagetable(old_pagetable, old_size);
+
+    Ok(argv.len())
+}
+
+/// Load the program segments and set up the user stack with `argv`.
+/// `size` always holds the amount of memory mapped so far,
+/// so the caller can free it on failure. Return the initial stack pointer.
+fn build_image(
+    pagetable: &mut UserPageTable,
+    size: &mut usize,
+    src: &(impl ElfSource + ?Sized),
+    elf: &ElfHeader,
+    argv: &[&str],
+) -> Result<usize, ExecError> {
+    // Load program into memory.
+    for i in 0..elf.phnum as usize {
+        let ph = elf.program_header(src, i).ok_or(ExecError::BadFormat)?;
+        if !ph.is_load() {
+            continue;
+        }
+
+        let vaddr = ph.vaddr as usize;
+        let memsz = ph.memsz as usize;
+        let filesz = ph.filesz as usize;
+        if memsz < filesz || !vaddr.is_multiple_of(PG_SIZE) {
+            return Err(ExecError::BadFormat);
+        }
+        let end = vaddr.checked_add(memsz).ok_or(ExecError::BadFormat)?;
+        if end > USER_TOP || (ph.off as usize).checked_add(filesz).is_none() {
+            return Err(ExecError::BadFormat);
+        }
+
+        *size = pagetable
+            .alloc(*size, end, segment_perm(&ph))
+            .ok_or(ExecError::OutOfMemory)?;
+        load_segment(pagetable, src, vaddr, ph.off as usize, filesz)?;
+    }
+
+    // Allocate some pages at the next page boundary.
+    // Make the first inaccessible as a stack guard.
+    // Use the rest as the user stack.
+    let guard = pgroundup(*size);
+    let top = guard + (USER_STACK_PAGES + 1) * PG_SIZE;
+    if top > USER_TOP {
+        return Err(ExecError::OutOfMemory);
+    }
+    *size = pagetable
+        .alloc(*size, top, PteFlags::new().set_writable(true))
+        .ok_or(ExecError::OutOfMemory)?;
+    unsafe { pagetable.clear(guard) };
+
+    let mut sp = *size;
+    let stack_base = sp - USER_STACK_PAGES * PG_SIZE;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut ustack = [0usize; MAXARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        sp -= arg.len() + 1;
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stack_base {
+            return Err(ExecError::TooManyArgs);
+        }
+        unsafe {
+            pagetable
+                .copy_out(sp, arg.as_ptr(), arg.len())
+                .map_err(|_| ExecError::BadAddress)?;
+            pagetable
+                .copy_out(sp + arg.len(), &0u8, 1)
+                .map_err(|_| ExecError::BadAddress)?;
+        }
+        ustack[i] = sp;
+    }
+    ustack[argv.len()] = 0;
+
+    // Push the array of argv[] pointers.
+    let ustack_len = (argv.len() + 1) * size_of::<usize>();
+    sp -= ustack_len;
+    sp -= sp % 16;
+    if sp < stack_base {
+        return Err(ExecError::TooManyArgs);
+    }
+    unsafe {
+        pagetable
+            .copy_out(sp, ustack.as_ptr() as *const u8, ustack_len)
+            .map_err(|_| ExecError::BadAddress)?;
+    }
+
+    Ok(sp)
+}
+
+/// Map ELF segment permissions to PTE permissions:
+/// text is R/X, data is R/W.
+fn segment_perm(ph: &ProgramHeader) -> PteFlags {
+    PteFlags::new()
+        .set_executable(ph.executable())
+        .set_writable(ph.writable())
+}
+
+/// Load a program segment into pagetable at virtual address va.
+/// va must be page-aligned and the pages from va to va+size must
+/// already be mapped.
+fn load_segment(
+    pagetable: &UserPageTable,
+    src: &(impl ElfSource + ?Sized),
+    va: usize,
+    offset: usize,
+    size: usize,
+) -> Result<(), ExecError> {
+    for i in (0..size).step_by(PG_SIZE) {
+        let pa = pagetable
+            .walk_addr(va + i)
+            .expect("load_segment: address should exist");
+        let n = (size - i).min(PG_SIZE);
+        let dst = unsafe { core::slice::from_raw_parts_mut(pa.as_mut_ptr::<u8>(), n) };
+        if src.read_at(dst, offset + i) != n {
+            return Err(ExecError::BadFormat);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Per-CPU run queues.
+//!
+//! Each runnable process is on the run queue of one CPU, the one in
+//! its `cpu`, and only that CPU runs it, until a load balance or an
+//! idle CPU moves it. A process is only put on or taken off a queue
+//! with its lock held, and a queue's lock is never held while taking
+//! a process's lock, so the queue of a process can be checked with
+//! just its lock. The scheduler takes a copy of its queue and then
+//! looks at the processes on it one at a time.
+
+use super::Proc;
+use crate::NPROC;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RunQueue {
+    procs: [*mut Proc; NPROC],
+    len: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            procs: [core::ptr::null_mut(); NPROC],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[*mut Proc] {
+        &self.procs[..self.len]
+    }
+
+    pub fn push(&mut self, p: *mut Proc) {
+        assert!(self.len < NPROC, "run queue full");
+        self.procs[self.len] = p;
+        self.len += 1;
+    }
+
+    /// Take `p` off the queue, return whether it was on it.
+    pub fn remove(&mut self, p: *mut Proc) -> bool {
+        match self.as_slice().iter().position(|&q| q == p) {
+            Some(i) => {
+                self.len -= 1;
+                self.procs[i] = self.procs[self.len];
+                true
+            }
+            None => false,
+        }
+    }
+}
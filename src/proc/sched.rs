@@ -0,0 +1,147 @@
+//! Scheduling policies.
+//!
+//! Each CPU runs the process on its run queue that the policy puts
+//! first, and the policy keeps what it needs for that in each process's
+//! `SchedInfo`: it's charged a tick on every timer interrupt the
+//! process is running for, and told when the process wakes up, is
+//! forked or picked to run. The policy is chosen at build time, with
+//! the `sched-rr` or `sched-mlfq` feature, and is `Cfs` otherwise.
+
+mod cfs;
+mod mlfq;
+mod rr;
+
+pub use cfs::Cfs;
+pub use mlfq::Mlfq;
+pub use rr::RoundRobin;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Lowest nice value, the highest priority
+pub const NICE_MIN: i32 = -20;
+/// Highest nice value, the lowest priority
+pub const NICE_MAX: i32 = 19;
+
+/// Ticks between calls of `Policy::boost` for every process
+pub const BOOST_TICKS: usize = 10;
+
+/// Ticks of a CPU between checks whether it should take processes
+/// from the busiest CPU's run queue
+pub const BALANCE_TICKS: usize = 4;
+
+/// The policy of all processes
+pub static POLICY: &dyn Policy = if cfg!(feature = "sched-rr") {
+    &RoundRobin
+} else if cfg!(feature = "sched-mlfq") {
+    &Mlfq
+} else {
+    &Cfs
+};
+
+/// Number of processes picked to run so far
+static PICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Scheduling state of a process, guarded by its lock
+#[derive(Debug, Clone, Copy)]
+pub struct SchedInfo {
+    /// From `NICE_MIN` to `NICE_MAX`, 0 by default
+    nice: i32,
+    /// Ticks run for
+    runtime: usize,
+    /// When it was last picked to run, in `PICKS`
+    last_run: u64,
+    /// `Cfs`: runtime weighted by nice value
+    vruntime: u64,
+    /// `Mlfq`: queue it's in, 0 is the highest
+    level: usize,
+    /// `Mlfq`: ticks run for in its queue
+    used: usize,
+}
+
+impl Default for SchedInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedInfo {
+    pub const fn new() -> Self {
+        Self {
+            nice: 0,
+            runtime: 0,
+            last_run: 0,
+            vruntime: 0,
+            level: 0,
+            used: 0,
+        }
+    }
+
+    #[inline]
+    pub fn nice(&self) -> i32 {
+        self.nice
+    }
+
+    /// Set the nice value, clamped to `NICE_MIN..=NICE_MAX`.
+    pub fn set_nice(&mut self, nice: i32) {
+        self.nice = nice.clamp(NICE_MIN, NICE_MAX);
+    }
+
+    /// Ticks run for
+    #[inline]
+    pub fn runtime(&self) -> usize {
+        self.runtime
+    }
+
+    /// For the child of a process with this info.
+    pub fn fork(&self) -> Self {
+        let mut child = Self {
+            runtime: 0,
+            last_run: 0,
+            ..*self
+        };
+        POLICY.fork(&mut child);
+        child
+    }
+
+    /// The process ran for another tick.
+    pub fn tick(&mut self) {
+        self.runtime += 1;
+        POLICY.tick(self);
+    }
+
+    /// The process is picked to run.
+    pub fn picked(&mut self) {
+        self.last_run = PICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        POLICY.picked(self);
+    }
+
+    /// Should a process with this info run before one with `other`?
+    #[inline]
+    pub fn before(&self, other: &SchedInfo) -> bool {
+        POLICY.before(self, other)
+    }
+}
+
+/// How processes are charged for the time they run,
+/// and which runnable one runs first
+pub trait Policy: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Should `a` run before `b`?
+    fn before(&self, a: &SchedInfo, b: &SchedInfo) -> bool;
+
+    /// `s` ran for another tick.
+    fn tick(&self, _s: &mut SchedInfo) {}
+
+    /// `s` is picked to run.
+    fn picked(&self, _s: &mut SchedInfo) {}
+
+    /// `s` wakes up from sleeping.
+    fn wake(&self, _s: &mut SchedInfo) {}
+
+    /// `s` is the new child of a process, copied from the parent's.
+    fn fork(&self, _s: &mut SchedInfo) {}
+
+    /// Called for every process each `BOOST_TICKS`.
+    fn boost(&self, _s: &mut SchedInfo) {}
+}
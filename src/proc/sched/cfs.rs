@@ -0,0 +1,70 @@
+//! Weighted fair scheduling, like Linux's CFS.
+//!
+//! Each process has a weight from its nice value, and its virtual
+//! runtime grows by a tick divided by its weight for each tick it runs.
+//! The process with the smallest virtual runtime runs first, so over
+//! time each gets CPU time in proportion to its weight.
+
+use super::{Policy, SchedInfo, NICE_MIN};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Weight of nice value 0
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights of nice values from -20 to 19, each about 1.25 times the
+/// next, so one nice level is about 10% of the CPU. Same as Linux's.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Virtual runtime of a tick at nice 0
+const TICK_VRUNTIME: u64 = NICE_0_WEIGHT * NICE_0_WEIGHT;
+
+/// Virtual runtime a process that slept may be behind the others,
+/// so it runs soon after waking, but can't take over the CPU
+const SLEEPER_CREDIT: u64 = 3 * TICK_VRUNTIME;
+
+/// Highest virtual runtime any process had when it was picked to
+/// run, on any CPU. Not the minimum of the runnable processes: each
+/// pick is the smallest of its queue, but this only grows, like
+/// Linux's min_vruntime, so it may be ahead of some of them. Waking
+/// and new processes are moved up to it, so they don't get the CPU
+/// time they weren't there for.
+static VRUNTIME_FLOOR: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+fn weight(nice: i32) -> u64 {
+    WEIGHTS[(nice - NICE_MIN) as usize]
+}
+
+pub struct Cfs;
+
+impl Policy for Cfs {
+    fn name(&self) -> &'static str {
+        "cfs"
+    }
+
+    fn before(&self, a: &SchedInfo, b: &SchedInfo) -> bool {
+        (a.vruntime, a.last_run) < (b.vruntime, b.last_run)
+    }
+
+    fn tick(&self, s: &mut SchedInfo) {
+        s.vruntime += TICK_VRUNTIME / weight(s.nice);
+    }
+
+    fn picked(&self, s: &mut SchedInfo) {
+        VRUNTIME_FLOOR.fetch_max(s.vruntime, Ordering::Relaxed);
+    }
+
+    fn wake(&self, s: &mut SchedInfo) {
+        let min = VRUNTIME_FLOOR.load(Ordering::Relaxed);
+        s.vruntime = s.vruntime.max(min.saturating_sub(SLEEPER_CREDIT));
+    }
+
+    fn fork(&self, s: &mut SchedInfo) {
+        // no earlier than the others, so forking doesn't gain CPU time.
+        s.vruntime = s.vruntime.max(VRUNTIME_FLOOR.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,63 @@
+//! Multi-level feedback queue.
+//!
+//! Processes in higher queues run first, round robin within a queue.
+//! A process that runs for the quantum of its queue moves down one,
+//! so interactive processes stay above those that compute. Every
+//! `BOOST_TICKS` all move back up to the queue their nice value
+//! starts them in, so none starves: nice 0 and below start in the
+//! highest, positive values lower.
+
+use super::{Policy, SchedInfo, NICE_MAX};
+
+/// Number of queues
+const LEVELS: usize = 4;
+
+/// Ticks a process runs for in queue `level` before it moves down
+#[inline]
+fn quantum(level: usize) -> usize {
+    1 << level
+}
+
+/// Queue a process with nice value `nice` starts in
+#[inline]
+fn base_level(nice: i32) -> usize {
+    if nice <= 0 {
+        0
+    } else {
+        (nice as usize * (LEVELS - 1)).div_ceil(NICE_MAX as usize)
+    }
+}
+
+pub struct Mlfq;
+
+impl Policy for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+
+    fn before(&self, a: &SchedInfo, b: &SchedInfo) -> bool {
+        (a.level, a.nice, a.last_run) < (b.level, b.nice, b.last_run)
+    }
+
+    fn tick(&self, s: &mut SchedInfo) {
+        // the nice value may have changed since.
+        s.level = s.level.max(base_level(s.nice));
+        s.used += 1;
+        if s.used >= quantum(s.level) {
+            // the bottom level starts its quantum over.
+            if s.level < LEVELS - 1 {
+                s.level += 1;
+            }
+            s.used = 0;
+        }
+    }
+
+    fn fork(&self, s: &mut SchedInfo) {
+        self.boost(s);
+    }
+
+    fn boost(&self, s: &mut SchedInfo) {
+        s.level = base_level(s.nice);
+        s.used = 0;
+    }
+}
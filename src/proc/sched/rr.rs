@@ -0,0 +1,15 @@
+//! Round robin: the process that waited longest runs first.
+
+use super::{Policy, SchedInfo};
+
+pub struct RoundRobin;
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+
+    fn before(&self, a: &SchedInfo, b: &SchedInfo) -> bool {
+        a.last_run < b.last_run
+    }
+}
@@ -1,22 +1,36 @@
-use super::{cpu, switch, CPU};
+use super::{
+    cpu,
+    sched::{self, SchedInfo, POLICY},
+    switch,
+    vma::Vma,
+    CPU,
+};
 use crate::{
     arch::{
         self,
         def::{self, PG_SIZE},
         vm,
     },
-    mem::{alloc, uvm::UserPageTable},
-    spinlock::{self, Mutex},
+    file::FileRef,
+    fs::{self, log, Inode},
+    mem::{alloc, uptr::UserPtr, uvm::UserPageTable, uvm::UserPageTableError},
+    spinlock::{self, Mutex, MutexGuard},
+    NOFILE, NVMA, ROOTDEV,
 };
 use core::{
     mem::size_of,
-    ops::{Add, Sub},
+    ops::Sub,
     ptr::{addr_of, addr_of_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
 };
 use rv64::vm::PteFlags;
 
 pub static GLOBAL_LOCK: Mutex<()> = Mutex::new((), "global_proc_lock");
 
+/// Affinity that lets a process run on any CPU, `NCPU` bits,
+/// of which only the online ones are used
+const ALL_CPUS: usize = usize::MAX >> (usize::BITS as usize - crate::NCPU);
+
 pub type Pid = i32;
 static NEXT_PID: Mutex<Pid> = Mutex::new(1, "next_pid");
 
@@ -33,6 +47,21 @@ static mut _PROC_MEM: [usize; size_of::<[Proc; crate::NPROC]>() / size_of::<usiz
 pub static mut PROCS: *mut [Proc; crate::NPROC] = core::ptr::null_mut();
 pub static mut INIT_PROC: *mut Proc = core::ptr::null_mut();
 
+/// The first user program, built from `user/src/bin/init.rs`
+static INIT_CODE: &[u8] = include_bytes!(concat!(env!("USER_BIN_DIR"), "/init"));
+
+/// Set up the first user process.
+pub fn user_init() {
+    let p = unsafe { &mut *Proc::alloc().expect("user_init: no free proc") };
+    unsafe { INIT_PROC = p };
+
+    super::exec::load(p, INIT_CODE, "/init", &["init"]).expect("user_init: failed to load init");
+    p.cwd = Some(fs::iget(ROOTDEV, fs::ROOTINO));
+
+    let mut sync = p.sync.lock();
+    p.ready(&mut sync);
+}
+
 pub fn kstack_addrs() -> [usize; crate::NPROC] {
     core::array::from_fn(arch::def::kstack)
 }
@@ -41,9 +70,11 @@ pub fn init() {
     unsafe {
         PROCS = addr_of_mut!(_PROC_MEM) as *mut [Proc; crate::NPROC];
         (*PROCS).iter_mut().enumerate().for_each(|(i, proc)| {
-            *proc = Proc::new(def::kstack(i));
+            // The table is uninitialized memory, don't drop it.
+            core::ptr::write(proc, Proc::new(def::kstack(i)));
         });
     }
+    crate::println!("scheduling policy: {}", POLICY.name());
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -62,6 +93,16 @@ pub enum ForkError {
     CopyPageTableFailed,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The caller has no child matching the request
+    NoChildren,
+    /// The caller was killed while waiting
+    Killed,
+    /// Failed to copy the exit status to user space
+    BadAddress,
+}
+
 #[derive(Debug)]
 struct _ProcSync {
     state: State,
@@ -69,6 +110,11 @@ struct _ProcSync {
     killed: bool,
     xstate: i32,
     pid: Option<Pid>,
+    sched: SchedInfo,
+    /// CPU whose run queue it's on while runnable, where it last ran
+    cpu: usize,
+    /// CPUs it may run on, a bit for each
+    affinity: usize,
 }
 
 #[derive(Debug)]
@@ -91,8 +137,12 @@ pub struct Proc {
     trapframe: Option<NonNull<arch::trampoline::TrapFrame>>,
     /// swtch() here to run process
     context: switch::Context,
-    // TODO: array[NOFILE] of opened file descriptors
-    // TODO: *inode for cwd
+    /// Open files, indexed by file descriptor
+    ofile: [Option<FileRef>; NOFILE],
+    /// Memory areas mapped with `mmap`
+    vmas: [Option<Vma>; NVMA],
+    /// Current directory
+    cwd: Option<Inode>,
 }
 
 impl Proc {
@@ -105,6 +155,9 @@ impl Proc {
                     killed: false,
                     xstate: 0,
                     pid: None,
+                    sched: SchedInfo::new(),
+                    cpu: 0,
+                    affinity: ALL_CPUS,
                 },
                 "proc_sync",
             ),
@@ -115,6 +168,9 @@ impl Proc {
             pagetable: UserPageTable::null(),
             trapframe: None,
             context: switch::Context::new(),
+            ofile: [const { None }; NOFILE],
+            vmas: [const { None }; NVMA],
+            cwd: None,
         }
     }
 
@@ -126,6 +182,21 @@ impl Proc {
         self.sync.lock().state
     }
 
+    /// Process name, for debugging
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("???")
+    }
+
+    /// Size of process memory in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     pub fn trapframe(&self) -> Option<NonNull<arch::trampoline::TrapFrame>> {
         self.trapframe
     }
@@ -138,6 +209,29 @@ impl Proc {
         self.kstack
     }
 
+    /// Current directory, `None` only before the file system is set up
+    pub fn cwd(&self) -> Option<&Inode> {
+        self.cwd.as_ref()
+    }
+
+    /// The open file of file descriptor `fd`
+    pub fn file(&self, fd: usize) -> Option<&FileRef> {
+        self.ofile.get(fd)?.as_ref()
+    }
+
+    /// Allocate a file descriptor for the given file.
+    /// Takes over the file reference from the caller on success.
+    pub fn fd_alloc(&mut self, f: FileRef) -> Option<usize> {
+        let fd = self.ofile.iter().position(|f| f.is_none())?;
+        self.ofile[fd] = Some(f);
+        Some(fd)
+    }
+
+    /// Remove file descriptor `fd`, returning its open file.
+    pub fn fd_close(&mut self, fd: usize) -> Option<FileRef> {
+        self.ofile.get_mut(fd)?.take()
+    }
+
     pub fn killed(&self) -> bool {
         self.sync.lock().killed
     }
@@ -181,15 +275,36 @@ impl Proc {
     /// Give up the CPU for one scheduling round.
     pub fn r#yield(&mut self) {
         let mut sync = self.sync.lock();
-        sync.state = State::Runnable;
+        self.ready(&mut sync);
         unsafe { self.sched() };
     }
 
+    /// Make the process runnable, on the run queue of the CPU it
+    /// last ran on if it may still run there, or else on the first
+    /// one it may run on. p->lock must be held.
+    fn ready(&self, sync: &mut _ProcSync) {
+        let online = CPU::online();
+        let allowed = sync.affinity & online;
+        // set_affinity leaves an online CPU in every mask, so none is
+        // allowed only for the first process, before any CPU is online.
+        debug_assert!(allowed != 0 || online == 0, "ready: no cpu allowed");
+        if allowed != 0 && allowed & (1 << sync.cpu) == 0 {
+            let cpu = allowed.trailing_zeros() as usize;
+            CPU::count_migration(sync.cpu, cpu);
+            sync.cpu = cpu;
+        }
+        sync.state = State::Runnable;
+        CPU::get(sync.cpu)
+            .run_queue()
+            .lock()
+            .push(self as *const Proc as *mut Proc);
+    }
+
     /// Look in the process table for an UNUSED proc.
     /// If found, initialize state required to run in the kernel,
     /// and return with p->lock held.(FIXME: Is holding lock necessary?)
-    /// If there are no free procs, or a memory allocation fails, return 0.
-    pub fn alloc(pagetable: Option<UserPageTable>) -> Option<*mut Proc> {
+    /// If there are no free procs, or a memory allocation fails, return `None`.
+    pub fn alloc() -> Option<*mut Proc> {
         let p = unsafe {
             (*PROCS)
                 .iter_mut()
@@ -199,14 +314,22 @@ impl Proc {
 
         p.sync.lock().pid = Some(alloc_pid());
 
-        p.trapframe = NonNull::new(
-            alloc::kalloc(false).map(|ptr| ptr.as_mut_ptr::<arch::trampoline::TrapFrame>())?,
-        );
-
-        p.pagetable = pagetable.or(p.alloc_pagetable().or_else(|| {
+        // Allocate a trapframe page.
+        p.trapframe = alloc::kalloc(false)
+            .and_then(|ptr| NonNull::new(ptr.as_mut_ptr::<arch::trampoline::TrapFrame>()));
+        if p.trapframe.is_none() {
             p.free();
-            None
-        }))?;
+            return None;
+        }
+
+        // An empty user page table.
+        p.pagetable = match p.alloc_pagetable() {
+            Some(pagetable) => pagetable,
+            None => {
+                p.free();
+                return None;
+            }
+        };
 
         p.context.setup(fork_ret as usize, p.kstack + PG_SIZE);
 
@@ -223,6 +346,7 @@ impl Proc {
             }
             self.trapframe = None;
         }
+        self.free_vmas();
         if !self.pagetable.is_null() {
             self.free_pagetable();
             self.pagetable = UserPageTable::null();
@@ -237,11 +361,13 @@ impl Proc {
         sync.chan = 0;
         sync.killed = false;
         sync.xstate = 0;
+        sync.sched = SchedInfo::new();
+        sync.affinity = ALL_CPUS;
     }
 
     /// Create a user page table for a given process,
     /// with no user memory, but with trampoline pages.
-    fn alloc_pagetable(&self) -> Option<UserPageTable> {
+    pub(super) fn alloc_pagetable(&self) -> Option<UserPageTable> {
         // If trapframe is not set, we cannot proceed.
         let trapframe = self
             .trapframe
@@ -256,10 +382,10 @@ impl Proc {
             // at the highest user virtual address.
             // only the supervisor uses it, on the way
             // to/from user space, so not PTE_U.
-            // if !pagetable.map(def::TRAMPOLINE, sz, pa, perm)
+            // if !pagetable.map(def::trampoline(), sz, pa, perm)
             pagetable
                 .map(
-                    def::TRAMPOLINE,
+                    def::trampoline(),
                     PG_SIZE,
                     vm::trampoline(),
                     PteFlags::new().set_readable(true).set_executable(true),
@@ -273,14 +399,14 @@ impl Proc {
             // map the trapframe just below TRAMPOLINE, for trampoline.S.
             pagetable
                 .map(
-                    def::TRAP_FRAME,
+                    def::trap_frame(),
                     PG_SIZE,
                     trapframe,
                     PteFlags::new().set_readable(true).set_writable(true),
                 )
                 .ok()
                 .or_else(|| {
-                    pagetable.unmap(def::TRAMPOLINE, 1, false);
+                    pagetable.unmap(def::trampoline(), 1, false);
                     pagetable.free(0);
                     None
                 })?;
@@ -295,26 +421,157 @@ impl Proc {
         if self.pagetable.is_null() {
             return;
         }
+        Self::release_pagetable(self.pagetable, self.size);
+    }
+
+    /// Free a page table created by `alloc_pagetable`
+    /// with `size` bytes of user memory.
+    pub(super) fn release_pagetable(mut pagetable: UserPageTable, size: usize) {
         unsafe {
-            self.pagetable.unmap(def::TRAMPOLINE, 1, false);
-            self.pagetable.unmap(def::TRAP_FRAME, 1, false);
-            self.pagetable.free(self.size);
+            pagetable.unmap(def::trampoline(), 1, false);
+            pagetable.unmap(def::trap_frame(), 1, false);
+            pagetable.free(size);
+        }
+    }
+
+    /// Install a new user image, return the old page table and size
+    /// so the caller can release them.
+    pub(super) fn swap_image(
+        &mut self,
+        pagetable: UserPageTable,
+        size: usize,
+        name: &[u8],
+    ) -> (UserPageTable, usize) {
+        let len = name.len().min(self.name.len() - 1);
+        self.name = [0; 16];
+        self.name[..len].copy_from_slice(&name[..len]);
+
+        let mut old_pagetable = core::mem::replace(&mut self.pagetable, pagetable);
+        let old_size = core::mem::replace(&mut self.size, size);
+
+        // The mapped areas go away with the old image, but dropping them
+        // may close files, so that's left to `forget_vmas`.
+        self.vmas.iter().flatten().for_each(|vma| unsafe {
+            old_pagetable.unmap(vma.start, (vma.end - vma.start) / PG_SIZE, true)
+        });
+        (old_pagetable, old_size)
+    }
+
+    /// Unmap and drop all mapped areas, without writing them back.
+    fn free_vmas(&mut self) {
+        for vma in self.vmas.iter_mut().filter_map(Option::take) {
+            if !self.pagetable.is_null() {
+                unsafe {
+                    self.pagetable
+                        .unmap(vma.start, (vma.end - vma.start) / PG_SIZE, true)
+                };
+            }
+        }
+    }
+
+    /// Drop the mapped areas after `swap_image` has unmapped them.
+    /// Must be called outside of a transaction.
+    pub(super) fn forget_vmas(&mut self) {
+        self.vmas.iter_mut().for_each(|vma| drop(vma.take()));
+    }
+
+    /// Write the dirty pages of all shared file mappings back to their files.
+    pub(super) fn write_back_vmas(&self) {
+        self.vmas
+            .iter()
+            .flatten()
+            .for_each(|vma| vma.write_back(&self.pagetable, vma.start, vma.end));
+    }
+
+    /// Lowest address of the mapped areas, the limit of `grow`
+    fn mmap_floor(&self) -> usize {
+        self.vmas
+            .iter()
+            .flatten()
+            .map(|vma| vma.start)
+            .min()
+            .unwrap_or(def::trap_frame())
+    }
+
+    /// Map `len` bytes of `file` from offset `off`, or zeroed memory if
+    /// `file` is `None`, below the areas that are mapped already.
+    /// Pages are only allocated on first use, by `page_fault`.
+    /// Return the start of the area, or `None` if out of address space
+    /// or memory areas.
+    pub fn mmap(
+        &mut self,
+        len: usize,
+        prot: usize,
+        flags: usize,
+        file: Option<FileRef>,
+        off: usize,
+    ) -> Option<usize> {
+        let slot = self.vmas.iter().position(Option::is_none)?;
+        let end = self.mmap_floor();
+        let start = end.checked_sub(def::pgroundup(len))?;
+        if len == 0 || start < def::pgroundup(self.size) {
+            return None;
+        }
+        self.vmas[slot] = Some(Vma {
+            start,
+            end,
+            prot,
+            flags,
+            file,
+            off,
+        });
+        Some(start)
+    }
+
+    /// Unmap `[addr, addr + len)`, writing shared file mappings back.
+    /// The range must be at the start or the end of a mapped area,
+    /// punching holes isn't supported.
+    /// Return `false` if the range isn't like that.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> bool {
+        let Some(end) = addr.checked_add(def::pgroundup(len)) else {
+            return false;
+        };
+        if !addr.is_multiple_of(PG_SIZE) || len == 0 {
+            return false;
+        }
+        let Some(slot) = self
+            .vmas
+            .iter()
+            .position(|vma| vma.as_ref().is_some_and(|vma| vma.contains(addr)))
+        else {
+            return false;
+        };
+
+        let vma = self.vmas[slot].as_mut().unwrap();
+        if end > vma.end || (addr != vma.start && end != vma.end) {
+            return false;
+        }
+
+        vma.write_back(&self.pagetable, addr, end);
+        unsafe { self.pagetable.unmap(addr, (end - addr) / PG_SIZE, true) };
+
+        if addr == vma.start && end == vma.end {
+            self.vmas[slot] = None;
+        } else if addr == vma.start {
+            vma.off += end - vma.start;
+            vma.start = end;
+        } else {
+            vma.end = addr;
         }
+        true
     }
 
     /// Grow or shrink user memory by n bytes.
+    /// Growing only reserves the addresses, the pages are
+    /// allocated on first use by `page_fault`.
     /// Return `true` on success, `false` on failure.
     pub fn grow(&mut self, delta: isize) -> bool {
         let old_size = self.size;
         let new_size = if delta > 0 {
-            let sz = self
-                .pagetable
-                .alloc(old_size, old_size.add(delta as usize))
-                .unwrap_or(0);
-            if sz == 0 {
-                return false;
+            match old_size.checked_add(delta as usize) {
+                Some(sz) if sz <= self.mmap_floor().min(def::USER_TOP) => sz,
+                _ => return false,
             }
-            sz
         } else if delta < 0 {
             self.pagetable
                 .dealloc(old_size, old_size.sub(delta.abs() as usize))
@@ -325,23 +582,160 @@ impl Proc {
         true
     }
 
+    /// Handle a page fault at `va`, a store if `store` is true.
+    /// Allocates a heap page that was never touched, or resolves
+    /// a store to a copy-on-write page.
+    /// Past the process size, faults in a page of a mapped area.
+    /// Fails for addresses outside of those, and for pages
+    /// that can't be accessed, like the stack guard page.
+    pub fn page_fault(&mut self, va: usize, store: bool) -> Result<(), UserPageTableError> {
+        if va >= self.size {
+            let vma = self
+                .vmas
+                .iter()
+                .flatten()
+                .find(|vma| vma.contains(va))
+                .ok_or(UserPageTableError::BadAddress)?;
+            return vma.fault(&mut self.pagetable, va, store);
+        }
+        match self.pagetable.lazy_alloc(va) {
+            // Already mapped, so it must be a copy-on-write page.
+            Err(UserPageTableError::BadAddress) if store => self.pagetable.resolve_cow(va),
+            result => result,
+        }
+    }
+
+    /// Handle a page fault at `va` in the kernel's own access to user
+    /// memory, a store if `store` is true. Returns whether the access
+    /// can be retried. Only anonymous pages are faulted in, since
+    /// reading a mapped file could sleep with the locks of the access's
+    /// caller held. A store to a mapped copy-on-write page is resolved
+    /// whatever area it's in, copying it never sleeps.
+    pub fn user_access_fault(&mut self, va: usize, store: bool) -> bool {
+        if self.pagetable.walk_addr(va).is_some() {
+            return store && self.pagetable.resolve_cow(va).is_ok();
+        }
+        self.anonymous(va) && self.page_fault(va, store).is_ok()
+    }
+
+    /// Is `va` in the heap or in an anonymous mapped area?
+    /// Faulting in such a page never sleeps.
+    fn anonymous(&self, va: usize) -> bool {
+        va < self.size
+            || self
+                .vmas
+                .iter()
+                .flatten()
+                .any(|vma| vma.contains(va) && vma.file.is_none())
+    }
+
+    /// Allocate the anonymous pages of `[va, va + len)` that were never
+    /// touched, so that the kernel can copy from and to them.
+    /// Pages of mapped files are left alone, since reading them could
+    /// sleep, so this may be called with spinlocks held.
+    /// Bad addresses are left for the copy to reject.
+    pub fn touch(&mut self, va: usize, len: usize) {
+        self.touch_pages(va, len, false);
+    }
+
+    /// Like `touch`, but also reads in the pages of mapped files.
+    /// Must be called without spinlocks held.
+    pub fn fault_in(&mut self, va: usize, len: usize) {
+        self.touch_pages(va, len, true);
+    }
+
+    /// Read in the pages of mapped files in `[va, va + len)`, which
+    /// can't be faulted in while copying to or from them. Anonymous
+    /// pages are left to be allocated as they are copied to.
+    /// Must be called without spinlocks held.
+    pub fn fault_in_files(&mut self, va: usize, len: usize) {
+        let end = va.saturating_add(len);
+        for i in 0..self.vmas.len() {
+            let Some((start, stop)) = self.vmas[i]
+                .as_ref()
+                .filter(|vma| vma.file.is_some())
+                .map(|vma| (vma.start.max(va), vma.end.min(end)))
+            else {
+                continue;
+            };
+            (def::pgrounddown(start)..stop)
+                .step_by(PG_SIZE)
+                .for_each(|a| {
+                    // Fails for pages that are mapped already.
+                    let _ = self.page_fault(a, false);
+                });
+        }
+    }
+
+    /// Like `fault_in`, for a string of at most `max` bytes at `va`.
+    /// Stops at the page with its '\0', so the pages after the string
+    /// aren't allocated.
+    pub fn fault_in_str(&mut self, va: usize, max: usize) {
+        let end = va.saturating_add(max).min(def::trap_frame());
+        let mut a = va;
+        while a < end {
+            let next = (def::pgrounddown(a) + PG_SIZE).min(end);
+            self.fault_in(a, next - a);
+            let Some(pa) = self.pagetable.walk_addr(a) else {
+                return;
+            };
+            let off = a - def::pgrounddown(a);
+            let s = unsafe { core::slice::from_raw_parts(pa.as_ptr::<u8>().add(off), next - a) };
+            if s.contains(&0) {
+                return;
+            }
+            a = next;
+        }
+    }
+
+    fn touch_pages(&mut self, va: usize, len: usize, files: bool) {
+        let end = va.saturating_add(len).min(def::trap_frame());
+        (def::pgrounddown(va)..end).step_by(PG_SIZE).for_each(|a| {
+            if files || self.anonymous(a) {
+                // Fails for pages that are mapped already.
+                let _ = self.page_fault(a, false);
+            }
+        });
+    }
+
     /// Create a new process, copying the parent.
     /// Sets up child kernel stack to return as if from fork() system call.
     pub fn fork(&self) -> Result<Pid, ForkError> {
-        // Copy user memory from parent to child.
-        let new_pgtbl = unsafe {
-            self.pagetable
-                .clone(self.size)
-                .ok_or(ForkError::CopyPageTableFailed)
-        }?;
-
         // Allocate process.
-        let child_ptr = Proc::alloc(Some(new_pgtbl)).ok_or(ForkError::AllocFailed)?;
+        let child_ptr = Proc::alloc().ok_or(ForkError::AllocFailed)?;
         let child = unsafe { child_ptr.as_mut().unwrap_unchecked() };
 
+        // Copy user memory from parent to child.
+        if unsafe { self.pagetable.copy_to(&mut child.pagetable, self.size) }.is_none() {
+            child.free();
+            return Err(ForkError::CopyPageTableFailed);
+        }
+
         child.size = self.size;
         child.name = self.name.clone();
 
+        // Shared areas keep sharing their pages with the child,
+        // private ones are copied on write like the rest of memory.
+        // Pages of shared areas that were never touched are mapped
+        // first, or each side would fault in a page of its own.
+        // Those of private areas are faulted in separately.
+        child.vmas = self.vmas.clone();
+        for vma in self.vmas.iter().flatten() {
+            let mut pagetable = self.pagetable;
+            if vma.shared() && vma.populate(&mut pagetable).is_err() {
+                child.free();
+                return Err(ForkError::CopyPageTableFailed);
+            }
+            let copied = unsafe {
+                self.pagetable
+                    .copy_range(&mut child.pagetable, vma.start, vma.end, vma.shared())
+            };
+            if copied.is_none() {
+                child.free();
+                return Err(ForkError::CopyPageTableFailed);
+            }
+        }
+
         unsafe {
             // copy saved user registers.
             let trapframe = self
@@ -366,11 +760,19 @@ impl Proc {
         }
 
         // increment reference counts on open file descriptors.
-        // TODO: copy file descriptor
+        child.ofile = self.ofile.clone();
+        child.cwd = self.cwd.clone();
 
+        let (sched, cpu, affinity) = {
+            let sync = self.sync.lock();
+            (sync.sched.fork(), sync.cpu, sync.affinity)
+        };
         let pid = {
             let mut sync = child.sync.lock();
-            sync.state = State::Runnable;
+            sync.sched = sched;
+            sync.cpu = cpu;
+            sync.affinity = affinity;
+            child.ready(&mut sync);
             sync.pid
         }
         .unwrap();
@@ -401,9 +803,20 @@ impl Proc {
             assert!(INIT_PROC != self, "init exiting");
         }
 
-        // TODO: close all open files and handle fs cwd here
+        // Unmap all mapped areas, writing shared file mappings back.
+        self.write_back_vmas();
+        self.free_vmas();
 
-        {
+        // Close all open files.
+        self.ofile.iter_mut().for_each(|f| drop(f.take()));
+
+        log::begin_op();
+        self.cwd = None;
+        log::end_op();
+
+        // Keep p->lock held across sched(), so the parent
+        // can't reap us before we have switched away.
+        let _sync = {
             let _guard = GLOBAL_LOCK.lock();
 
             // Give any children to init.
@@ -412,20 +825,84 @@ impl Proc {
             // Parent might be sleeping in wait().
             Self::wake_up(self.parent.unwrap().as_ptr() as usize);
 
-            {
-                let mut sync = self.sync.lock();
-                sync.xstate = state;
-                sync.state = State::Zombie;
-            }
-        }
+            let mut sync = self.sync.lock();
+            sync.xstate = state;
+            sync.state = State::Zombie;
+            sync
+        };
+
+        // Jump into the scheduler, never to return.
         unsafe { self.sched() };
         panic!("zombie exit");
     }
 
     /// Wait for a child process to exit and return its pid.
     /// Return `None` if this process has no children.
-    pub fn wait(&mut self, _addr: usize) -> Option<Pid> {
-        todo!()
+    pub fn wait(&mut self, status: UserPtr<i32>) -> Option<Pid> {
+        self.waitpid(None, status, false).ok().flatten()
+    }
+
+    /// Wait for the child `pid`, or any child if `pid` is `None`, to exit.
+    /// Copy its exit status to `status` if it isn't null,
+    /// free the zombie and return its pid.
+    /// With `nohang`, return `Ok(None)` instead of sleeping
+    /// if no matching child has exited yet.
+    pub fn waitpid(
+        &mut self,
+        pid: Option<Pid>,
+        status: UserPtr<i32>,
+        nohang: bool,
+    ) -> Result<Option<Pid>, WaitError> {
+        let this = addr_of_mut!(*self);
+        // Before taking any lock, as it may be in a mapped file.
+        if !status.is_null() {
+            self.fault_in(status.addr(), size_of::<i32>());
+        }
+        let mut guard = GLOBAL_LOCK.lock();
+
+        loop {
+            // Scan through table looking for exited children.
+            let mut have_kids = false;
+            for pp in unsafe { (*PROCS).iter_mut() } {
+                if pp.parent.map(NonNull::as_ptr) != Some(this) {
+                    continue;
+                }
+
+                // Make sure the child isn't still in exit() or switch().
+                let sync = pp.sync.lock();
+                if pid.is_some() && sync.pid != pid {
+                    continue;
+                }
+                have_kids = true;
+
+                if sync.state == State::Zombie {
+                    let child_pid = sync.pid;
+                    let xstate = sync.xstate;
+                    drop(sync);
+
+                    if !status.is_null() {
+                        self.touch(status.addr(), size_of::<i32>());
+                        status.write(xstate).map_err(|_| WaitError::BadAddress)?;
+                    }
+                    pp.free();
+                    return Ok(child_pid);
+                }
+            }
+
+            // No point waiting if we don't have any children.
+            if !have_kids {
+                return Err(WaitError::NoChildren);
+            }
+            if self.killed() {
+                return Err(WaitError::Killed);
+            }
+            if nohang {
+                return Ok(None);
+            }
+
+            // Wait for a child to exit.
+            guard = self.sleep(this as usize, guard);
+        }
     }
 
     /// Atomically release lock and sleep on chan.
@@ -462,33 +939,140 @@ impl Proc {
         lock.lock()
     }
 
+    /// Wake up all processes sleeping on chan.
+    /// Must be called without any p->lock.
     pub fn wake_up(chan: usize) {
+        // May be called from an interrupt with no process on this CPU,
+        // or by the UART driver before the process table is set up.
+        if unsafe { PROCS.is_null() } {
+            return;
+        }
+        let this_proc = CPU::this_proc().map(|p| p.as_ptr());
         unsafe {
-            (*PROCS).iter_mut().for_each(|p| {
-                if let Some(this_proc) = CPU::this_proc() {
-                    if this_proc.as_ptr() != p {
-                        let mut sync = p.sync.lock();
-                        if sync.state == State::Sleeping && sync.chan == chan {
-                            sync.state = State::Runnable;
-                        }
+            (*PROCS)
+                .iter_mut()
+                .filter(|p| this_proc != Some(addr_of!(**p) as *mut Proc))
+                .for_each(|p| {
+                    let mut sync = p.sync.lock();
+                    if sync.state == State::Sleeping && sync.chan == chan {
+                        POLICY.wake(&mut sync.sched);
+                        p.ready(&mut sync);
                     }
-                }
-            });
+                });
         }
     }
 
-    pub fn kill(target: Pid) {
+    /// Kill the process with the given pid.
+    /// The victim won't exit until it tries to return
+    /// to user space (see user_trap() in trap.rs).
+    /// Return `false` if there is no such process.
+    pub fn kill(target: Pid) -> bool {
         unsafe {
-            (*PROCS).iter_mut().for_each(|p| {
+            (*PROCS).iter().any(|p| {
                 let mut sync = p.sync.lock();
-                if let Some(pid) = sync.pid {
-                    if pid == target {
-                        sync.killed = true;
-                        if sync.state == State::Sleeping {
-                            sync.state = State::Runnable;
-                        }
-                        return;
+                if sync.pid == Some(target) {
+                    sync.killed = true;
+                    if sync.state == State::Sleeping {
+                        // Wake process from sleep().
+                        POLICY.wake(&mut sync.sched);
+                        p.ready(&mut sync);
                     }
+                    true
+                } else {
+                    false
+                }
+            })
+        }
+    }
+
+    /// Set the nice value of the process with the given pid
+    /// to what `nice` makes of its current one, and return the new one.
+    /// Return `None` if there is no such process.
+    pub fn renice(target: Pid, nice: impl FnOnce(i32) -> i32) -> Option<i32> {
+        for p in unsafe { (*PROCS).iter() } {
+            let mut sync = p.sync.lock();
+            if sync.pid == Some(target) {
+                let nice = nice(sync.sched.nice());
+                sync.sched.set_nice(nice);
+                return Some(sync.sched.nice());
+            }
+        }
+        None
+    }
+
+    /// Online CPUs the process with the given pid may run on, a bit
+    /// for each. Return `None` if there is no such process.
+    pub fn affinity(target: Pid) -> Option<usize> {
+        unsafe { (*PROCS).iter() }.find_map(|p| {
+            let sync = p.sync.lock();
+            (sync.pid == Some(target)).then_some(sync.affinity & CPU::online())
+        })
+    }
+
+    /// Let the process with the given pid only run on the CPUs in
+    /// `mask`, which must have an online one. If it's runnable on
+    /// another, move it; if it's running there, it moves once it
+    /// gives up the CPU. Return `false` if there is no such process.
+    pub fn set_affinity(target: Pid, mask: usize) -> bool {
+        assert!(mask & CPU::online() != 0, "set_affinity: no cpu");
+        unsafe { (*PROCS).iter() }.any(|p| {
+            let mut sync = p.sync.lock();
+            if sync.pid != Some(target) {
+                return false;
+            }
+            sync.affinity = mask;
+            if sync.state == State::Runnable && mask & (1 << sync.cpu) == 0 {
+                let this = addr_of!(*p) as *mut Proc;
+                CPU::get(sync.cpu).run_queue().lock().remove(this);
+                p.ready(&mut sync);
+            }
+            true
+        })
+    }
+
+    /// Charge a timer tick to this process, if it's running.
+    pub fn tick(&self) {
+        let mut sync = self.sync.lock();
+        if sync.state == State::Running {
+            sync.sched.tick();
+        }
+    }
+
+    /// Let the policy adjust every process, each `BOOST_TICKS`.
+    pub fn boost() {
+        if unsafe { PROCS.is_null() } {
+            return;
+        }
+        unsafe {
+            (*PROCS).iter().for_each(|p| {
+                let mut sync = p.sync.lock();
+                if sync.state != State::Unused {
+                    POLICY.boost(&mut sync.sched);
+                }
+            });
+        }
+    }
+
+    /// Print a process listing to console.  For debugging.
+    /// Runs when user types ^P on console.
+    /// Only tries each process's lock, to avoid wedging a stuck
+    /// machine further: a process whose lock is held is listed as busy.
+    pub fn dump() {
+        if unsafe { PROCS.is_null() } {
+            return;
+        }
+        crate::println!();
+        unsafe {
+            (*PROCS).iter().enumerate().for_each(|(i, p)| {
+                // printing may wake up a process waiting for the uart,
+                // which takes its lock, so don't hold any then.
+                let Some((pid, state)) = p.sync.try_lock().map(|sync| (sync.pid, sync.state))
+                else {
+                    crate::println!("[{}] busy", i);
+                    return;
+                };
+                if state != State::Unused {
+                    crate::println!("{} {:?} {}", pid.unwrap_or(0), state, p.name());
                 }
             });
         }
@@ -505,27 +1089,48 @@ impl Proc {
 pub fn scheduler() -> ! {
     // Use pointer here to avoid multiple mutable references from existing
     let c = unsafe { cpu::CPU::this_mut() };
+    let id = arch::cpuid();
+    CPU::set_online();
+    let mut balance_at = 0;
+    // Kernel page table of the last process this CPU ran, which it
+    // stays on until it runs the next one.
+    let mut held = UserPageTable::null();
     loop {
         // Avoid deadlock by ensuring that devices can interrupt.
         arch::intr_on();
 
+        let ticks = CPU::get(id).ticks();
+        if ticks >= balance_at {
+            balance(id);
+            balance_at = ticks + sched::BALANCE_TICKS;
+        }
+
         unsafe {
-            (*PROCS)
-                .iter_mut()
-                .filter(|p| p.cas_state(State::Runnable, State::Running))
-                .for_each(|run| {
-                    // Switch to chosen process
-                    (*c).set_proc(Some(NonNull::new_unchecked(run)));
-
-                    // It is the process's job to release its lock and then
-                    // reacquire it before jumping back to us.
-                    let _guard = run.sync.lock();
-                    (*c).switch_to(&run.context);
-
-                    // Process is done running for now.
-                    // It should have changed its p->state before coming back.
-                    (*c).set_proc(None);
-                });
+            // Run what's on this CPU's run queue, or else take
+            // something from another's.
+            // It is the process's job to release its lock and then
+            // reacquire it before jumping back to us.
+            if let Some((run, mut sync)) = pick(id).or_else(|| steal(id)) {
+                sync.state = State::Running;
+                sync.sched.picked();
+
+                // Switch to chosen process
+                (*c).set_proc(Some(NonNull::new_unchecked(run)));
+                (*run).pagetable.activate_kernel();
+                if !held.is_null() {
+                    held.release_kernel();
+                }
+                (*c).switch_to(&(*run).context);
+
+                // Process is done running for now.
+                // It should have changed its p->state before coming back.
+                // Hold its kernel page table while holding its lock,
+                // so it isn't freed under us once it exited.
+                held = (*run).pagetable;
+                held.hold_kernel();
+                (*c).set_proc(None);
+                continue;
+            }
         }
 
         // No process to run, wait for an interrupt.
@@ -533,7 +1138,122 @@ pub fn scheduler() -> ! {
     }
 }
 
+/// Is the process with `sync` on the run queue of CPU `cpu`?
+#[inline]
+fn queued_on(sync: &_ProcSync, cpu: usize) -> bool {
+    sync.state == State::Runnable && sync.cpu == cpu
+}
+
+/// Take the process on the run queue of CPU `cpu` that the scheduling
+/// policy runs first off it, and return it locked.
+fn pick(cpu: usize) -> Option<(*mut Proc, MutexGuard<'static, _ProcSync>)> {
+    loop {
+        let queue = *CPU::get(cpu).run_queue().lock();
+        let mut best: Option<(*mut Proc, SchedInfo)> = None;
+        for &p in queue.as_slice() {
+            let sync = unsafe { (*p).sync.lock() };
+            if queued_on(&sync, cpu) && best.is_none_or(|(_, sched)| sync.sched.before(&sched)) {
+                best = Some((p, sync.sched));
+            }
+        }
+
+        let (p, _) = best?;
+        let sync = unsafe { (*p).sync.lock() };
+        // Another CPU may have taken it since.
+        if queued_on(&sync, cpu) {
+            CPU::get(cpu).run_queue().lock().remove(p);
+            return Some((p, sync));
+        }
+    }
+}
+
+/// Move a process that may run on CPU `to` off the run queue of
+/// CPU `from`, and return it locked.
+fn migrate(from: usize, to: usize) -> Option<(*mut Proc, MutexGuard<'static, _ProcSync>)> {
+    let queue = *CPU::get(from).run_queue().lock();
+    queue.as_slice().iter().find_map(|&p| {
+        let mut sync = unsafe { (*p).sync.lock() };
+        if !queued_on(&sync, from) || sync.affinity & (1 << to) == 0 {
+            return None;
+        }
+        CPU::get(from).run_queue().lock().remove(p);
+        CPU::count_migration(from, to);
+        sync.cpu = to;
+        Some((p, sync))
+    })
+}
+
+/// With nothing to run, take a process from another CPU's run queue.
+fn steal(cpu: usize) -> Option<(*mut Proc, MutexGuard<'static, _ProcSync>)> {
+    let online = CPU::online();
+    (1..crate::NCPU)
+        .map(|i| (cpu + i) % crate::NCPU)
+        .filter(|&other| online & (1 << other) != 0)
+        .find_map(|other| migrate(other, cpu))
+}
+
+/// Move a process from the run queue of the busiest CPU to that of
+/// CPU `cpu`, if it has at least two more.
+fn balance(cpu: usize) {
+    let len = |id: usize| CPU::get(id).run_queue().lock().len();
+    let online = CPU::online();
+    let busiest = (0..crate::NCPU)
+        .filter(|&id| id != cpu && online & (1 << id) != 0)
+        .max_by_key(|&id| len(id));
+    if let Some(busiest) = busiest {
+        if len(busiest) >= len(cpu) + 2 {
+            if let Some((p, sync)) = migrate(busiest, cpu) {
+                CPU::get(cpu).run_queue().lock().push(p);
+                drop(sync);
+            }
+        }
+    }
+}
+
+/// A fork child's very first scheduling by scheduler()
+/// will switch to fork_ret.
 fn fork_ret() {
-    // TODO: once.Do(fsinit)
+    static FIRST: AtomicBool = AtomicBool::new(true);
+
+    // Still holding p->lock from scheduler.
+    unsafe { CPU::this_proc_ref().sync.force_unlock() };
+
+    if FIRST.swap(false, Ordering::SeqCst) {
+        // File system initialization must be run in the context of a
+        // regular process (e.g., because it calls sleep), and thus cannot
+        // be run from main().
+        fs::init(ROOTDEV);
+    }
+
     arch::trap::user_trap_ret();
 }
+
+/// Copy to either a user address, or kernel address,
+/// depending on user_dst.
+pub fn either_copy_out(user_dst: bool, dst: usize, src: &[u8]) -> Result<(), UserPageTableError> {
+    if user_dst {
+        let p = unsafe { CPU::this_proc_ref() };
+        p.touch(dst, src.len());
+        unsafe { p.pagetable.copy_out(dst, src.as_ptr(), src.len()) }
+    } else {
+        unsafe { core::ptr::copy(src.as_ptr(), dst as *mut u8, src.len()) };
+        Ok(())
+    }
+}
+
+/// Copy from either a user address, or kernel address,
+/// depending on user_src.
+pub fn either_copy_in(
+    user_src: bool,
+    dst: &mut [u8],
+    src: usize,
+) -> Result<(), UserPageTableError> {
+    if user_src {
+        let p = unsafe { CPU::this_proc_ref() };
+        p.touch(src, dst.len());
+        unsafe { p.pagetable.copy_in(dst.as_mut_ptr(), src, dst.len()) }
+    } else {
+        unsafe { core::ptr::copy(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        Ok(())
+    }
+}
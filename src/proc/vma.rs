@@ -0,0 +1,133 @@
+//! Memory areas mapped with `mmap`.
+//!
+//! Areas are placed top down below the trapframe, above the
+//! memory of `sbrk`, and their pages are only allocated and
+//! read from the file on the first page fault. Dirty pages of
+//! shared file mappings are written back by `munmap` and `exit`.
+
+use crate::{
+    arch::def::{pgrounddown, PG_SIZE},
+    file::FileRef,
+    mem::{
+        alloc::{kalloc, kfree},
+        mman::{MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE},
+        uvm::{UserPageTable, UserPageTableError},
+    },
+};
+use rv64::vm::PteFlags;
+
+/// A mapped memory area, `[start, end)` is page aligned.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// `PROT_*` bits
+    pub prot: usize,
+    /// `MAP_*` bits
+    pub flags: usize,
+    /// The mapped file, `None` for anonymous memory
+    pub file: Option<FileRef>,
+    /// File offset of `start`
+    pub off: usize,
+}
+
+impl Vma {
+    pub fn contains(&self, va: usize) -> bool {
+        (self.start..self.end).contains(&va)
+    }
+
+    pub fn shared(&self) -> bool {
+        self.flags & MAP_SHARED != 0
+    }
+
+    /// PTE permissions of the pages
+    fn perm(&self) -> PteFlags {
+        PteFlags::new()
+            .set_readable(self.prot & PROT_READ != 0)
+            .set_writable(self.prot & PROT_WRITE != 0)
+            .set_executable(self.prot & PROT_EXEC != 0)
+            .set_user(true)
+    }
+
+    /// Handle a page fault at `va` in this area.
+    /// Maps a page with the file's content on first touch,
+    /// or resolves a store to a private page shared copy-on-write by fork.
+    pub fn fault(
+        &self,
+        pagetable: &mut UserPageTable,
+        va: usize,
+        store: bool,
+    ) -> Result<(), UserPageTableError> {
+        let va0 = pgrounddown(va);
+        let need = if store { PROT_WRITE } else { PROT_READ };
+        if self.prot & need == 0 {
+            return Err(UserPageTableError::BadAddress);
+        }
+        if pagetable.walk_addr(va0).is_some() {
+            // A store to a private page that fork shared copy-on-write.
+            return if store {
+                pagetable.resolve_cow(va0)
+            } else {
+                Err(UserPageTableError::BadAddress)
+            };
+        }
+
+        self.fill(pagetable, va0)
+    }
+
+    /// Map all pages of the area that were never touched,
+    /// so that a fork child shares them with its parent.
+    pub fn populate(&self, pagetable: &mut UserPageTable) -> Result<(), UserPageTableError> {
+        for va in (self.start..self.end).step_by(PG_SIZE) {
+            if pagetable.walk_addr(va).is_none() {
+                self.fill(pagetable, va)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Map a page at `va0` with the file's content, or zeroed.
+    fn fill(&self, pagetable: &mut UserPageTable, va0: usize) -> Result<(), UserPageTableError> {
+        let page = kalloc(true).ok_or(UserPageTableError::OutOfMemory)?;
+        if let Some(f) = &self.file {
+            // Past the end of the file reads as zeros.
+            let off = self.off + (va0 - self.start);
+            if f.read_at(page.as_mut_ptr::<u8>() as usize, off, PG_SIZE)
+                .is_err()
+            {
+                unsafe { kfree(page) };
+                return Err(UserPageTableError::BadAddress);
+            }
+        }
+        unsafe {
+            pagetable
+                .map(va0, PG_SIZE, page.into(), self.perm())
+                .inspect_err(|_| kfree(page))
+        }
+    }
+
+    /// Write the dirty pages of `[start, end)` back to the file,
+    /// if this is a shared file mapping. Only the part of a page
+    /// before the end of the file is written, so the file never grows.
+    pub fn write_back(&self, pagetable: &UserPageTable, start: usize, end: usize) {
+        let Some(f) = self.file.as_ref().filter(|_| self.shared()) else {
+            return;
+        };
+        let Ok(st) = f.stat() else {
+            return;
+        };
+        let size = st.size as usize;
+        for va in (start..end).step_by(PG_SIZE) {
+            let off = self.off + (va - self.start);
+            if off >= size || !pagetable.dirty(va) {
+                continue;
+            }
+            let Some(pa) = pagetable.walk_addr(va) else {
+                continue;
+            };
+            let n = PG_SIZE.min(size - off);
+            // Nothing to do about a failed write at munmap or exit.
+            let _ = f.write_at(pa.as_ptr::<u8>() as usize, off, n);
+        }
+    }
+}
@@ -29,11 +29,12 @@ impl<T> SleepMutex<T> {
         }
     }
 
-    pub fn lock(&mut self) -> SleepMutexGuard<'_, T> {
+    pub fn lock(&self) -> SleepMutexGuard<'_, T> {
         unsafe {
             let mut guard = self.locked.lock();
             while guard.locked {
-                guard = CPU::this_proc_ref().sleep(addr_of!(self) as usize, guard);
+                // Sleep on the mutex itself, which is what the guard wakes up.
+                guard = CPU::this_proc_ref().sleep(addr_of!(*self) as usize, guard);
             }
             guard.locked = true;
             guard.pid = CPU::this_proc_ref().pid();
@@ -79,7 +80,11 @@ impl<'a, T: 'a> SleepMutexGuard<'a, T> {
 
 impl<'a, T> core::ops::Drop for SleepMutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.mutex.locked.lock().locked = false;
+        {
+            let mut sync = self.mutex.locked.lock();
+            sync.locked = false;
+            sync.pid = None;
+        }
         Proc::wake_up(addr_of!(*self.mutex) as usize);
     }
 }
@@ -6,7 +6,7 @@ use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
     ptr,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 
 #[derive(Debug)]
@@ -14,6 +14,16 @@ pub struct Mutex<T> {
     name: &'static str,     // Name of the lock for debugging
     data: UnsafeCell<T>,    // The data being protected
     locked: AtomicPtr<CPU>, // The CPU holding the lock
+    acquires: AtomicUsize,  // Number of times the lock was taken
+    spins: AtomicUsize,     // Number of failed tries to take it
+}
+
+/// Contention counters of a lock
+#[derive(Debug, Clone, Copy)]
+pub struct LockStats {
+    pub name: &'static str,
+    pub acquires: usize,
+    pub spins: usize,
 }
 
 impl<T> Mutex<T> {
@@ -22,6 +32,8 @@ impl<T> Mutex<T> {
             name: name,
             data: UnsafeCell::new(value),
             locked: AtomicPtr::new(core::ptr::null_mut()),
+            acquires: AtomicUsize::new(0),
+            spins: AtomicUsize::new(0),
         }
     }
 
@@ -43,20 +55,52 @@ impl<T> Mutex<T> {
                     )
                     .is_ok()
                 {
+                    self.acquires.fetch_add(1, Ordering::Relaxed);
                     return MutexGuard {
                         mutex: self,
                         _int_lock: int_lock,
                     };
                 }
+                self.spins.fetch_add(1, Ordering::Relaxed);
                 core::hint::spin_loop();
             }
         }
     }
 
+    /// Take the lock only if it's free, don't spin.
+    /// Also fails if this CPU holds it already.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        unsafe {
+            let int_lock = CPU::push_off();
+            let cpu = CPU::this_mut();
+            self.locked
+                .compare_exchange(
+                    core::ptr::null_mut(),
+                    cpu,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .ok()?;
+            self.acquires.fetch_add(1, Ordering::Relaxed);
+            Some(MutexGuard {
+                mutex: self,
+                _int_lock: int_lock,
+            })
+        }
+    }
+
     pub fn holding(&self) -> bool {
         unsafe { (self.locked.load(Ordering::Relaxed) as *const CPU) == CPU::this() }
     }
 
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            name: self.name,
+            acquires: self.acquires.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+        }
+    }
+
     /// Only call with holding the lock
     pub unsafe fn get(&self) -> &T {
         &*self.data.get()
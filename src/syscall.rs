@@ -0,0 +1,278 @@
+//! System call dispatch.
+//!
+//! Follows the classic xv6 calling convention:
+//! - `a7`: system call number
+//! - `a0`..`a5`: arguments
+//! - `a0`: return value, a negative errno on failure
+
+mod file;
+pub mod num;
+mod proc;
+
+pub use num::*;
+
+use crate::{
+    arch::trampoline::TrapFrame,
+    file::FileError,
+    fs::FsError,
+    io::device::DeviceError,
+    mem::{
+        uptr::{UserCStr, UserData, UserPtr},
+        uvm::UserPageTableError,
+    },
+    pipe::PipeError,
+    println,
+    proc::{ExecError, WaitError, CPU},
+};
+use core::mem::size_of;
+
+/// Number of slots in the system call table
+const NSYSCALL: usize = 30;
+
+/// Error numbers returned to user space as `-errno` in `a0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum SysError {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENXIO = 6,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+}
+
+impl From<SysError> for usize {
+    #[inline]
+    fn from(err: SysError) -> usize {
+        (-(err as isize)) as usize
+    }
+}
+
+impl From<UserPageTableError> for SysError {
+    #[inline]
+    fn from(_: UserPageTableError) -> SysError {
+        SysError::EFAULT
+    }
+}
+
+impl From<ExecError> for SysError {
+    #[inline]
+    fn from(err: ExecError) -> SysError {
+        match err {
+            ExecError::NotFound => SysError::ENOENT,
+            ExecError::BadFormat => SysError::ENOEXEC,
+            ExecError::OutOfMemory => SysError::ENOMEM,
+            ExecError::TooManyArgs => SysError::E2BIG,
+            ExecError::BadAddress => SysError::EFAULT,
+        }
+    }
+}
+
+impl From<WaitError> for SysError {
+    #[inline]
+    fn from(err: WaitError) -> SysError {
+        match err {
+            WaitError::NoChildren => SysError::ECHILD,
+            WaitError::Killed => SysError::EINTR,
+            WaitError::BadAddress => SysError::EFAULT,
+        }
+    }
+}
+
+impl From<FsError> for SysError {
+    #[inline]
+    fn from(err: FsError) -> SysError {
+        match err {
+            FsError::BadAddress => SysError::EFAULT,
+            FsError::BadOffset => SysError::EINVAL,
+            FsError::FileTooLarge => SysError::EFBIG,
+            FsError::Exists => SysError::EEXIST,
+            FsError::NoSpace => SysError::ENOSPC,
+        }
+    }
+}
+
+impl From<DeviceError> for SysError {
+    #[inline]
+    fn from(err: DeviceError) -> SysError {
+        match err {
+            DeviceError::BadAddress => SysError::EFAULT,
+            DeviceError::Interrupted => SysError::EINTR,
+            DeviceError::Unsupported => SysError::ENOTTY,
+        }
+    }
+}
+
+impl From<FileError> for SysError {
+    #[inline]
+    fn from(err: FileError) -> SysError {
+        match err {
+            FileError::NotReadable | FileError::NotWritable => SysError::EBADF,
+            FileError::BadAddress => SysError::EFAULT,
+            FileError::NoDevice => SysError::ENXIO,
+            FileError::Unsupported => SysError::ENOTTY,
+            FileError::Device(err) => err.into(),
+            FileError::Fs(err) => err.into(),
+            FileError::Pipe(PipeError::Broken) => SysError::EPIPE,
+            FileError::Pipe(PipeError::Interrupted) => SysError::EINTR,
+        }
+    }
+}
+
+pub type SysResult = Result<usize, SysError>;
+
+type SysCall = fn() -> SysResult;
+
+/// Maps system call numbers to the functions that handle them.
+/// Numbers without a handler yet are answered with `ENOSYS`.
+const SYSCALLS: [Option<SysCall>; NSYSCALL] = {
+    let mut table: [Option<SysCall>; NSYSCALL] = [None; NSYSCALL];
+    table[SYS_FORK] = Some(proc::sys_fork);
+    table[SYS_EXIT] = Some(proc::sys_exit);
+    table[SYS_WAIT] = Some(proc::sys_wait);
+    table[SYS_KILL] = Some(proc::sys_kill);
+    table[SYS_EXEC] = Some(proc::sys_exec);
+    table[SYS_GETPID] = Some(proc::sys_getpid);
+    table[SYS_SBRK] = Some(proc::sys_sbrk);
+    table[SYS_SLEEP] = Some(proc::sys_sleep);
+    table[SYS_UPTIME] = Some(proc::sys_uptime);
+    table[SYS_WAITPID] = Some(proc::sys_waitpid);
+    table[SYS_NICE] = Some(proc::sys_nice);
+    table[SYS_SETPRIORITY] = Some(proc::sys_setpriority);
+    table[SYS_SCHED_SETAFFINITY] = Some(proc::sys_sched_setaffinity);
+    table[SYS_SCHED_GETAFFINITY] = Some(proc::sys_sched_getaffinity);
+    table[SYS_READ] = Some(file::sys_read);
+    table[SYS_WRITE] = Some(file::sys_write);
+    table[SYS_OPEN] = Some(file::sys_open);
+    table[SYS_CLOSE] = Some(file::sys_close);
+    table[SYS_DUP] = Some(file::sys_dup);
+    table[SYS_FSTAT] = Some(file::sys_fstat);
+    table[SYS_MKNOD] = Some(file::sys_mknod);
+    table[SYS_IOCTL] = Some(file::sys_ioctl);
+    table[SYS_PIPE] = Some(file::sys_pipe);
+    table[SYS_MMAP] = Some(file::sys_mmap);
+    table[SYS_MUNMAP] = Some(file::sys_munmap);
+    table
+};
+
+/// Dispatch the system call requested by the current process,
+/// and store its return value into `a0` of the trapframe.
+pub fn syscall() {
+    let p = unsafe { CPU::this_proc_ref() };
+    let num = trapframe().a7;
+
+    let ret = match SYSCALLS.get(num).copied().flatten() {
+        Some(handler) => handler().unwrap_or_else(usize::from),
+        None => {
+            println!(
+                "{} {}: unknown sys call {}",
+                p.pid().unwrap_or(-1),
+                p.name(),
+                num
+            );
+            SysError::ENOSYS.into()
+        }
+    };
+
+    unsafe { p.trapframe().expect("syscall: no trapframe").as_mut() }.a0 = ret;
+}
+
+#[inline]
+fn trapframe() -> &'static TrapFrame {
+    unsafe {
+        CPU::this_proc_ref()
+            .trapframe()
+            .expect("syscall: no trapframe")
+            .as_ref()
+    }
+}
+
+/// Fetch the raw value of the n-th system call argument.
+pub fn arg_raw(n: usize) -> usize {
+    let trapframe = trapframe();
+    match n {
+        0 => trapframe.a0,
+        1 => trapframe.a1,
+        2 => trapframe.a2,
+        3 => trapframe.a3,
+        4 => trapframe.a4,
+        5 => trapframe.a5,
+        _ => panic!("arg_raw: invalid argument index {}", n),
+    }
+}
+
+/// Fetch the n-th system call argument as a 32-bit integer.
+#[inline]
+pub fn arg_int(n: usize) -> i32 {
+    arg_raw(n) as i32
+}
+
+/// Fetch the n-th system call argument as a pointer.
+/// Doesn't check for legality, since `copy_in`/`copy_out` will do that.
+#[inline]
+pub fn arg_addr(n: usize) -> usize {
+    arg_raw(n)
+}
+
+/// Fetch the n-th system call argument as a pointer to a `T`
+/// in the current process, checked when it's used.
+pub fn arg_ptr<T: UserData>(n: usize) -> UserPtr<T> {
+    let p = unsafe { CPU::this_proc_ref() };
+    UserPtr::new(p.pagetable(), arg_addr(n))
+}
+
+/// Fetch the n-th system call argument as a null-terminated string,
+/// copying it into `buf`. Returns the string without the trailing '\0'.
+pub fn arg_str(n: usize, buf: &mut [u8]) -> Result<&str, SysError> {
+    let p = unsafe { CPU::this_proc_ref() };
+    fetch_str(UserCStr::new(p.pagetable(), arg_addr(n)), buf)
+}
+
+/// Fetch the `T` at `ptr` from the current process.
+pub fn fetch<T: UserData>(ptr: UserPtr<T>) -> Result<T, SysError> {
+    let p = unsafe { CPU::this_proc_ref() };
+    p.fault_in(ptr.addr(), size_of::<T>());
+    Ok(ptr.read()?)
+}
+
+/// Store `value` at `ptr` in the current process.
+pub fn store<T: UserData>(ptr: UserPtr<T>, value: T) -> Result<(), SysError> {
+    let p = unsafe { CPU::this_proc_ref() };
+    p.fault_in(ptr.addr(), size_of::<T>());
+    Ok(ptr.write(value)?)
+}
+
+/// Fetch the null-terminated string `s` from the current process
+/// into `buf`. Returns the string without the trailing '\0'.
+pub fn fetch_str(s: UserCStr, buf: &mut [u8]) -> Result<&str, SysError> {
+    let p = unsafe { CPU::this_proc_ref() };
+    p.fault_in_str(s.addr(), buf.len());
+    let s = s.read_into(buf).map_err(|e| match e {
+        UserPageTableError::InvalidString => SysError::ENAMETOOLONG,
+        _ => SysError::EFAULT,
+    })?;
+    core::str::from_utf8(s).map_err(|_| SysError::EINVAL)
+}
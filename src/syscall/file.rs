@@ -0,0 +1,272 @@
+//! File-system system calls.
+//! Mostly argument checking, since we don't trust
+//! user code, and calls into file.rs and fs.rs.
+
+use super::{arg_addr, arg_int, arg_ptr, arg_raw, arg_str, store, SysError, SysResult};
+use crate::{
+    arch::def::PG_SIZE,
+    file::{self, FileRef, FileType},
+    fs::{
+        fcntl::{O_CREATE, O_RDWR, O_TRUNC, O_WRONLY},
+        ialloc, log, namei, namei_parent, Inode, T_DEVICE, T_DIR, T_FILE,
+    },
+    mem::mman::{MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE},
+    pipe,
+    proc::CPU,
+    MAXPATH, NDEV,
+};
+
+/// Fetch the n-th system call argument as a file descriptor
+/// and return both the descriptor and the corresponding file.
+fn arg_fd(n: usize) -> Result<(usize, &'static FileRef), SysError> {
+    let fd = arg_raw(n);
+    let f = unsafe { CPU::this_proc_ref() }
+        .file(fd)
+        .ok_or(SysError::EBADF)?;
+    Ok((fd, f))
+}
+
+/// Fetch the n-th system call argument as a byte count.
+fn arg_count(n: usize) -> Result<usize, SysError> {
+    usize::try_from(arg_int(n)).map_err(|_| SysError::EINVAL)
+}
+
+pub fn sys_dup() -> SysResult {
+    let (_, f) = arg_fd(0)?;
+    let f = f.clone();
+    unsafe { CPU::this_proc_ref() }
+        .fd_alloc(f)
+        .ok_or(SysError::EMFILE)
+}
+
+pub fn sys_read() -> SysResult {
+    let (_, f) = arg_fd(0)?;
+    let addr = arg_addr(1);
+    let n = arg_count(2)?;
+    // Pages of mapped files can't be read in under the locks of read.
+    unsafe { CPU::this_proc_ref() }.fault_in_files(addr, n);
+    Ok(f.read(addr, n)?)
+}
+
+pub fn sys_write() -> SysResult {
+    let (_, f) = arg_fd(0)?;
+    let addr = arg_addr(1);
+    let n = arg_count(2)?;
+    // Pages of mapped files can't be read in under the locks of write.
+    unsafe { CPU::this_proc_ref() }.fault_in_files(addr, n);
+    Ok(f.write(addr, n)?)
+}
+
+pub fn sys_close() -> SysResult {
+    let (fd, _) = arg_fd(0)?;
+    drop(unsafe { CPU::this_proc_ref() }.fd_close(fd));
+    Ok(0)
+}
+
+pub fn sys_fstat() -> SysResult {
+    let (_, f) = arg_fd(0)?;
+    let st = f.stat()?;
+    store(arg_ptr(1), st)?;
+    Ok(0)
+}
+
+/// `ioctl(fd, request, arg)` sends a device specific request
+/// to the driver of a device file.
+pub fn sys_ioctl() -> SysResult {
+    let (_, f) = arg_fd(0)?;
+    let request = arg_raw(1);
+    let arg = arg_raw(2);
+    Ok(f.ioctl(request, arg)?)
+}
+
+/// Create the inode `path` of type `type_`, and link it into its directory.
+/// An `open` with `O_CREATE` of an existing file just opens it.
+/// Must be called inside a transaction.
+fn create(path: &str, type_: i16, major: i16, minor: i16) -> Result<Inode, SysError> {
+    let (dp, name) = namei_parent(path).ok_or(SysError::ENOENT)?;
+    let mut dir = dp.lock();
+
+    if let Some((ip, _)) = dir.lookup(name) {
+        drop(dir);
+        let existing = ip.lock().type_;
+        if type_ == T_FILE && (existing == T_FILE || existing == T_DEVICE) {
+            return Ok(ip);
+        }
+        return Err(SysError::EEXIST);
+    }
+
+    let ip = ialloc(dp.dev(), type_).ok_or(SysError::ENOSPC)?;
+    let mut guard = ip.lock();
+    guard.major = major;
+    guard.minor = minor;
+    guard.nlink = 1;
+    guard.update();
+
+    let linked = (|| {
+        if type_ == T_DIR {
+            // Create . and .. entries.
+            // No ip->nlink++ for ".": avoid cyclic ref count.
+            guard.link(".", ip.inum())?;
+            guard.link("..", dp.inum())?;
+        }
+        dir.link(name, ip.inum())
+    })();
+
+    if let Err(err) = linked {
+        // de-allocate ip.
+        guard.nlink = 0;
+        guard.update();
+        return Err(err.into());
+    }
+
+    if type_ == T_DIR {
+        // now that success is guaranteed:
+        dir.nlink += 1; // for ".."
+        dir.update();
+    }
+
+    drop(guard);
+    Ok(ip)
+}
+
+/// Open the file `path` for `open`.
+/// Must be called inside a transaction.
+fn open(path: &str, omode: usize) -> Result<FileRef, SysError> {
+    let ip = if omode & O_CREATE != 0 {
+        create(path, T_FILE, 0, 0)?
+    } else {
+        namei(path).ok_or(SysError::ENOENT)?
+    };
+
+    let mut guard = ip.lock();
+    let readable = omode & O_WRONLY == 0;
+    let writable = omode & O_WRONLY != 0 || omode & O_RDWR != 0;
+
+    if guard.type_ == T_DIR && writable {
+        return Err(SysError::EISDIR);
+    }
+
+    let type_ = if guard.type_ == T_DEVICE {
+        if !(0..NDEV as i16).contains(&guard.major) {
+            return Err(SysError::ENXIO);
+        }
+        FileType::Device {
+            ip: ip.clone(),
+            major: guard.major,
+            minor: guard.minor,
+        }
+    } else {
+        FileType::Inode { ip: ip.clone() }
+    };
+
+    if omode & O_TRUNC != 0 && guard.type_ == T_FILE {
+        guard.trunc();
+    }
+
+    file::alloc(type_, readable, writable).ok_or(SysError::ENFILE)
+}
+
+/// `open(path, omode)` returns a new file descriptor for `path`.
+pub fn sys_open() -> SysResult {
+    let mut path = [0u8; MAXPATH];
+    let path = arg_str(0, &mut path)?;
+    let omode = arg_raw(1);
+
+    log::begin_op();
+    let f = open(path, omode);
+    log::end_op();
+
+    // Closing the file on failure starts its own transaction.
+    unsafe { CPU::this_proc_ref() }
+        .fd_alloc(f?)
+        .ok_or(SysError::EMFILE)
+}
+
+/// `mknod(path, major, minor)` creates a device file.
+pub fn sys_mknod() -> SysResult {
+    let mut path = [0u8; MAXPATH];
+    let path = arg_str(0, &mut path)?;
+    let major = arg_int(1) as i16;
+    let minor = arg_int(2) as i16;
+
+    log::begin_op();
+    let result = create(path, T_DEVICE, major, minor).map(drop);
+    log::end_op();
+
+    result.map(|_| 0)
+}
+
+/// `pipe(fds)` creates a pipe, and stores the file descriptors
+/// of its read and write ends into the user array `fds[2]`.
+pub fn sys_pipe() -> SysResult {
+    let fds = arg_ptr::<[i32; 2]>(0); // user pointer to array of two integers
+    let (rf, wf) = pipe::alloc().ok_or(SysError::ENFILE)?;
+
+    let p = unsafe { CPU::this_proc_ref() };
+    let fd0 = p.fd_alloc(rf).ok_or(SysError::EMFILE)?;
+    let Some(fd1) = p.fd_alloc(wf) else {
+        p.fd_close(fd0);
+        return Err(SysError::EMFILE);
+    };
+
+    if let Err(err) = store(fds, [fd0 as i32, fd1 as i32]) {
+        p.fd_close(fd0);
+        p.fd_close(fd1);
+        return Err(err);
+    }
+    Ok(0)
+}
+
+/// `mmap(addr, len, prot, flags, fd, off)` maps `len` bytes of the file `fd`
+/// from offset `off`, or zeroed memory with `MAP_ANONYMOUS`, and returns
+/// the address of the mapping. `addr` is only a hint, and ignored.
+pub fn sys_mmap() -> SysResult {
+    let len = arg_raw(1);
+    let prot = arg_raw(2);
+    let flags = arg_raw(3);
+    let off = arg_raw(5);
+
+    let shared = flags & MAP_SHARED != 0;
+    if len == 0 || shared == (flags & MAP_PRIVATE != 0) || !off.is_multiple_of(PG_SIZE) {
+        return Err(SysError::EINVAL);
+    }
+    // File offsets are 32 bits, all of the mapping must have one.
+    if off
+        .checked_add(len)
+        .is_none_or(|end| end > u32::MAX as usize)
+    {
+        return Err(SysError::EINVAL);
+    }
+
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        let (_, f) = arg_fd(4)?;
+        if !f.is_inode() {
+            return Err(SysError::ENODEV);
+        }
+        if prot & PROT_READ != 0 && !f.readable() {
+            return Err(SysError::EACCES);
+        }
+        // Private mappings never write to the file.
+        if shared && prot & PROT_WRITE != 0 && !f.writable() {
+            return Err(SysError::EACCES);
+        }
+        Some(f.clone())
+    };
+
+    unsafe { CPU::this_proc_ref() }
+        .mmap(len, prot, flags, file, off)
+        .ok_or(SysError::ENOMEM)
+}
+
+/// `munmap(addr, len)` unmaps the start or the end of a mapping.
+pub fn sys_munmap() -> SysResult {
+    let addr = arg_addr(0);
+    let len = arg_raw(1);
+    if unsafe { CPU::this_proc_ref() }.munmap(addr, len) {
+        Ok(0)
+    } else {
+        Err(SysError::EINVAL)
+    }
+}
@@ -0,0 +1,31 @@
+//! System call numbers, shared by the kernel and user programs.
+
+pub const SYS_FORK: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_WAIT: usize = 3;
+pub const SYS_PIPE: usize = 4;
+pub const SYS_READ: usize = 5;
+pub const SYS_KILL: usize = 6;
+pub const SYS_EXEC: usize = 7;
+pub const SYS_FSTAT: usize = 8;
+pub const SYS_CHDIR: usize = 9;
+pub const SYS_DUP: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_SBRK: usize = 12;
+pub const SYS_SLEEP: usize = 13;
+pub const SYS_UPTIME: usize = 14;
+pub const SYS_OPEN: usize = 15;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_MKNOD: usize = 17;
+pub const SYS_UNLINK: usize = 18;
+pub const SYS_LINK: usize = 19;
+pub const SYS_MKDIR: usize = 20;
+pub const SYS_CLOSE: usize = 21;
+pub const SYS_WAITPID: usize = 22;
+pub const SYS_IOCTL: usize = 23;
+pub const SYS_MMAP: usize = 24;
+pub const SYS_MUNMAP: usize = 25;
+pub const SYS_NICE: usize = 26;
+pub const SYS_SETPRIORITY: usize = 27;
+pub const SYS_SCHED_SETAFFINITY: usize = 28;
+pub const SYS_SCHED_GETAFFINITY: usize = 29;
@@ -0,0 +1,194 @@
+use super::{arg_int, arg_ptr, arg_str, fetch, fetch_str, store, SysError, SysResult};
+use crate::{
+    arch::def::PG_SIZE,
+    mem::{
+        alloc::{kalloc, kfree},
+        uptr::UserCStr,
+    },
+    proc::{self, ForkError, Pid, Proc, CPU, TICKS},
+    MAXARG, MAXPATH,
+};
+use core::ptr::addr_of;
+use rv64::vm::PhysAddr;
+
+pub fn sys_exit() -> SysResult {
+    let n = arg_int(0);
+    unsafe { CPU::this_proc_ref() }.exit(n);
+}
+
+pub fn sys_getpid() -> SysResult {
+    let pid = unsafe { CPU::this_proc_ref() }
+        .pid()
+        .expect("sys_getpid: no pid");
+    Ok(pid as usize)
+}
+
+pub fn sys_fork() -> SysResult {
+    match unsafe { CPU::this_proc_ref() }.fork() {
+        Ok(pid) => Ok(pid as usize),
+        Err(ForkError::AllocFailed) => Err(SysError::EAGAIN),
+        Err(ForkError::CopyPageTableFailed) => Err(SysError::ENOMEM),
+    }
+}
+
+/// `options` flag for `waitpid`: return immediately if no child has exited
+pub const WNOHANG: i32 = 1;
+
+pub fn sys_wait() -> SysResult {
+    let status = arg_ptr(0);
+    let pid = unsafe { CPU::this_proc_ref() }.waitpid(None, status, false)?;
+    Ok(pid.expect("sys_wait: blocking wait returned no pid") as usize)
+}
+
+/// `waitpid(pid, status, options)`, a `pid` not greater than 0 waits for any child.
+/// Returns 0 with `WNOHANG` if no matching child has exited yet.
+pub fn sys_waitpid() -> SysResult {
+    let pid = arg_int(0);
+    let status = arg_ptr(1);
+    let options = arg_int(2);
+
+    let target = if pid > 0 { Some(pid) } else { None };
+    let nohang = options & WNOHANG != 0;
+    let pid = unsafe { CPU::this_proc_ref() }.waitpid(target, status, nohang)?;
+    Ok(pid.map_or(0, |pid| pid as usize))
+}
+
+pub fn sys_sbrk() -> SysResult {
+    let n = arg_int(0);
+    let p = unsafe { CPU::this_proc_ref() };
+    let addr = p.size();
+    if !p.grow(n as isize) {
+        return Err(SysError::ENOMEM);
+    }
+    Ok(addr)
+}
+
+pub fn sys_sleep() -> SysResult {
+    let n = arg_int(0).max(0) as usize;
+    let p = unsafe { CPU::this_proc_ref() };
+    let mut ticks = unsafe { (*addr_of!(TICKS)).lock() };
+    let ticks0 = *ticks;
+    while *ticks - ticks0 < n {
+        if p.killed() {
+            return Err(SysError::EINTR);
+        }
+        let chan = addr_of!(*ticks) as usize;
+        ticks = p.sleep(chan, ticks);
+    }
+    Ok(0)
+}
+
+pub fn sys_kill() -> SysResult {
+    let pid = arg_int(0);
+    if Proc::kill(pid) {
+        Ok(0)
+    } else {
+        Err(SysError::ESRCH)
+    }
+}
+
+/// `nice(inc)` adds `inc` to the nice value of the calling process,
+/// a lower value for a higher priority, and returns the new value as
+/// 20 - nice, like Linux's getpriority, so that it isn't negative
+/// and can't be taken for an error.
+pub fn sys_nice() -> SysResult {
+    let inc = arg_int(0);
+    let pid = unsafe { CPU::this_proc_ref() }
+        .pid()
+        .expect("sys_nice: no pid");
+    let nice = Proc::renice(pid, |nice| nice.saturating_add(inc)).ok_or(SysError::ESRCH)?;
+    Ok((20 - nice) as usize)
+}
+
+/// `setpriority(pid, nice)` sets the nice value of process `pid`,
+/// or of the calling process if `pid` is 0.
+pub fn sys_setpriority() -> SysResult {
+    let pid = arg_pid(0);
+    let nice = arg_int(1);
+    Proc::renice(pid, |_| nice).ok_or(SysError::ESRCH)?;
+    Ok(0)
+}
+
+/// `sched_setaffinity(pid, mask)` lets process `pid`, or the calling
+/// process if `pid` is 0, only run on the CPUs in `*mask`, a bit for each.
+pub fn sys_sched_setaffinity() -> SysResult {
+    let pid = arg_pid(0);
+    let mask = fetch(arg_ptr::<u64>(1))? as usize;
+    if mask & CPU::online() == 0 {
+        return Err(SysError::EINVAL);
+    }
+    if Proc::set_affinity(pid, mask) {
+        Ok(0)
+    } else {
+        Err(SysError::ESRCH)
+    }
+}
+
+/// `sched_getaffinity(pid, mask)` stores the CPUs process `pid`, or the
+/// calling process if `pid` is 0, may run on into `*mask`.
+pub fn sys_sched_getaffinity() -> SysResult {
+    let pid = arg_pid(0);
+    let mask = Proc::affinity(pid).ok_or(SysError::ESRCH)?;
+    store(arg_ptr::<u64>(1), mask as u64)?;
+    Ok(0)
+}
+
+/// Fetch the n-th system call argument as a pid, 0 for the caller's.
+fn arg_pid(n: usize) -> Pid {
+    match arg_int(n) {
+        0 => unsafe { CPU::this_proc_ref() }
+            .pid()
+            .expect("arg_pid: no pid"),
+        pid => pid,
+    }
+}
+
+/// Return how many clock tick interrupts have occurred since start.
+pub fn sys_uptime() -> SysResult {
+    let ticks = unsafe { (*addr_of!(TICKS)).lock() };
+    Ok(*ticks)
+}
+
+pub fn sys_exec() -> SysResult {
+    let mut path = [0u8; MAXPATH];
+    let path = arg_str(0, &mut path)?;
+    let uargv = arg_ptr::<usize>(1);
+    let pagetable = unsafe { CPU::this_proc_ref() }.pagetable();
+
+    // Each argument string is copied into its own page.
+    let mut pages: [Option<PhysAddr>; MAXARG] = [None; MAXARG];
+    let mut argv: [&str; MAXARG] = [""; MAXARG];
+    let mut argc = 0;
+
+    let result = loop {
+        let uarg = match fetch(uargv.add(argc)) {
+            Ok(uarg) => uarg,
+            Err(err) => break Err(err),
+        };
+        if uarg == 0 {
+            break proc::exec(path, &argv[..argc]).map_err(SysError::from);
+        }
+        if argc >= MAXARG {
+            break Err(SysError::E2BIG);
+        }
+
+        let page = match kalloc(false) {
+            Some(page) => page,
+            None => break Err(SysError::ENOMEM),
+        };
+        pages[argc] = Some(page);
+
+        let buf = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr::<u8>(), PG_SIZE) };
+        match fetch_str(UserCStr::new(pagetable, uarg), buf) {
+            Ok(arg) => argv[argc] = arg,
+            Err(err) => break Err(err),
+        }
+        argc += 1;
+    };
+
+    pages
+        .iter()
+        .flatten()
+        .for_each(|page| unsafe { kfree(*page) });
+    result
+}
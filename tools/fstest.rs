@@ -0,0 +1,321 @@
+//! Tests of the file system on the host, against a `RamDisk` holding
+//! the image `build.rs` makes. The buffer cache, log, inodes and
+//! directories are the kernel's own, compiled over stand-ins for the
+//! parts of the kernel that need a hart: spin locks, processes and
+//! user memory.
+//!
+//! The tests share the disk and the caches, so `just test-fs` runs
+//! them one at a time.
+
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src"]
+mod kernel {
+    pub mod fs;
+    pub mod sleeplock;
+    pub mod io {
+        pub mod block;
+        pub mod ramdisk;
+    }
+}
+
+use kernel::{fs, io, sleeplock};
+
+// same as lib.rs
+const NINODE: usize = 50;
+const NDEV: usize = 10;
+const ROOTDEV: u32 = 1;
+const NBUF: usize = fs::MAXOPBLOCKS * 3;
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => { std::println!($($arg)*) };
+}
+
+mod spinlock {
+    use std::sync::PoisonError;
+
+    pub use std::sync::MutexGuard;
+
+    /// Just a lock: there are no interrupts to turn off on the host.
+    #[derive(Debug)]
+    pub struct Mutex<T>(std::sync::Mutex<T>);
+
+    unsafe impl<T> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T, _name: &'static str) -> Mutex<T> {
+            Mutex(std::sync::Mutex::new(value))
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+mod proc {
+    use crate::{fs::Inode, spinlock::MutexGuard};
+
+    /// The test's thread, as the process the file system runs for.
+    pub struct Proc;
+
+    pub struct CPU;
+
+    impl CPU {
+        pub unsafe fn this_proc_ref() -> &'static mut Proc {
+            Box::leak(Box::new(Proc))
+        }
+    }
+
+    impl Proc {
+        pub fn pid(&self) -> Option<i32> {
+            Some(1)
+        }
+
+        /// Tests only use absolute paths.
+        pub fn cwd(&self) -> Option<&Inode> {
+            None
+        }
+
+        /// Nobody else would ever wake the test up.
+        pub fn sleep<'a, T>(&self, _chan: usize, _guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+            panic!("sleep: nothing to wait for");
+        }
+
+        pub fn wake_up(_chan: usize) {}
+    }
+
+    pub fn either_copy_out(user_dst: bool, dst: usize, src: &[u8]) -> Result<(), ()> {
+        assert!(!user_dst, "either_copy_out: no user memory");
+        unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+        Ok(())
+    }
+
+    pub fn either_copy_in(user_src: bool, dst: &mut [u8], src: usize) -> Result<(), ()> {
+        assert!(!user_src, "either_copy_in: no user memory");
+        unsafe { std::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        Ok(())
+    }
+}
+
+mod mem {
+    pub mod uptr {
+        pub unsafe trait UserData {}
+    }
+}
+
+use fs::{
+    bio::bread,
+    bmap_bit, ialloc, ioffset,
+    log::{begin_op, end_op, log_write},
+    namei, namei_parent, DInode, FsError, OnDisk, SuperBlock, BSIZE, FSMAGIC, FSSIZE, NDIRECT,
+    ROOTINO, T_DIR, T_FILE,
+};
+use std::sync::Once;
+
+/// A block past the user programs, that no file uses
+const SCRATCH: u32 = FSSIZE as u32 - 1;
+
+fn setup() -> &'static SuperBlock {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        io::ramdisk::init();
+        fs::init(ROOTDEV);
+    });
+    fs::superblock()
+}
+
+/// Read a block from the disk itself, not the buffer cache.
+fn disk_read(blockno: u32) -> [u8; BSIZE] {
+    let mut data = [0; BSIZE];
+    io::block::device(ROOTDEV).read(blockno, &mut data);
+    data
+}
+
+/// Write a block behind the buffer cache's back.
+fn disk_write(blockno: u32, data: &[u8; BSIZE]) {
+    io::block::device(ROOTDEV).write(blockno, data);
+}
+
+/// Recycle every buffer, so that the next `bread` of any other
+/// block goes to the disk.
+fn evict_all(sb: &SuperBlock) {
+    for i in 0..NBUF as u32 {
+        drop(bread(ROOTDEV, sb.inodestart + i));
+    }
+}
+
+/// Number of blocks marked free in the bitmap on the disk.
+fn free_blocks(sb: &SuperBlock) -> usize {
+    (0..sb.size)
+        .filter(|&b| {
+            let (i, mask) = bmap_bit(b);
+            disk_read(sb.bblock(b))[i] & mask == 0
+        })
+        .count()
+}
+
+/// Number of committed blocks in the log header on the disk.
+fn log_len(sb: &SuperBlock) -> u32 {
+    u32::read_from(&disk_read(sb.logstart))
+}
+
+#[test]
+fn bio_round_trip() {
+    let sb = setup();
+    assert_eq!(
+        SuperBlock::read_from(bread(ROOTDEV, 1).data()).magic,
+        FSMAGIC
+    );
+
+    let mut bp = bread(ROOTDEV, SCRATCH);
+    bp.data_mut().fill(0xa5);
+    bp.write();
+    drop(bp);
+    assert_eq!(disk_read(SCRATCH), [0xa5; BSIZE]);
+
+    // Cached until the buffer is recycled.
+    disk_write(SCRATCH, &[0x5a; BSIZE]);
+    assert_eq!(*bread(ROOTDEV, SCRATCH).data(), [0xa5; BSIZE]);
+    evict_all(sb);
+    assert_eq!(*bread(ROOTDEV, SCRATCH).data(), [0x5a; BSIZE]);
+}
+
+#[test]
+fn log_commit() {
+    let sb = setup();
+    let mut bp = bread(ROOTDEV, SCRATCH);
+    bp.data_mut().fill(0);
+    bp.write();
+    drop(bp);
+
+    begin_op();
+    let mut bp = bread(ROOTDEV, SCRATCH);
+    bp.data_mut().fill(1);
+    log_write(&bp);
+    drop(bp);
+    assert_eq!(disk_read(SCRATCH), [0; BSIZE], "written before commit");
+    end_op();
+
+    assert_eq!(disk_read(sb.logstart + 1), [1; BSIZE], "not logged");
+    assert_eq!(disk_read(SCRATCH), [1; BSIZE], "not installed");
+    assert_eq!(log_len(sb), 0, "log not cleared");
+}
+
+#[test]
+fn log_recovery() {
+    let sb = setup();
+    let mut bp = bread(ROOTDEV, SCRATCH);
+    bp.data_mut().fill(0);
+    bp.write();
+    drop(bp);
+
+    // Crash right after the commit point: the header names the
+    // block, but it never made it home.
+    let mut head = [0; BSIZE];
+    1u32.write_to(&mut head);
+    SCRATCH.write_to(&mut head[4..]);
+    disk_write(sb.logstart + 1, &[2; BSIZE]);
+    disk_write(sb.logstart, &head);
+    evict_all(sb);
+
+    fs::log::init(ROOTDEV, sb);
+    assert_eq!(disk_read(SCRATCH), [2; BSIZE], "not recovered");
+    assert_eq!(log_len(sb), 0, "log not cleared");
+}
+
+#[test]
+fn inode_alloc_and_trunc() {
+    let sb = setup();
+    let nfree = free_blocks(sb);
+
+    begin_op();
+    let ip = ialloc(ROOTDEV, T_FILE).expect("out of inodes");
+    {
+        let mut ip = ip.lock();
+        ip.nlink = 1;
+        ip.update();
+    }
+    end_op();
+    let inum = ip.inum();
+    let dinode = |inum| DInode::read_from(&disk_read(sb.iblock(inum))[ioffset(inum)..]);
+    assert_eq!(dinode(inum).type_, T_FILE);
+
+    // Past the direct blocks, and a few at a time, to keep
+    // each transaction within MAXOPBLOCKS.
+    let data = (0..(NDIRECT + 2) * BSIZE)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    for (i, chunk) in data.chunks(2 * BSIZE).enumerate() {
+        begin_op();
+        let off = (i * 2 * BSIZE) as u32;
+        let n = ip
+            .lock()
+            .write(false, chunk.as_ptr() as usize, off, chunk.len() as u32);
+        assert_eq!(n, Ok(chunk.len()));
+        end_op();
+    }
+    // The data blocks and the indirect one
+    assert_eq!(free_blocks(sb), nfree - (NDIRECT + 3));
+
+    let mut back = vec![0u8; data.len() + 1];
+    let n = ip
+        .lock()
+        .read(false, back.as_mut_ptr() as usize, 0, back.len() as u32);
+    assert_eq!(n, Ok(data.len()));
+    assert_eq!(back[..data.len()], data);
+
+    begin_op();
+    {
+        let mut ip = ip.lock();
+        ip.trunc();
+        assert_eq!(ip.size, 0);
+        assert_eq!(ip.addrs, [0; NDIRECT + 1]);
+    }
+    end_op();
+    assert_eq!(free_blocks(sb), nfree);
+    assert_eq!(dinode(inum).size, 0);
+
+    // Dropping the last reference with no links frees the inode.
+    begin_op();
+    ip.lock().nlink = 0;
+    drop(ip);
+    end_op();
+    assert_eq!(dinode(inum).type_, 0);
+}
+
+#[test]
+fn dir_lookup() {
+    setup();
+    let ip = namei("/init").expect("no /init in the image");
+    assert_eq!(ip.lock().type_, T_FILE);
+    assert!(namei("/nonexistent").is_none());
+    assert!(
+        namei("/init/nonexistent").is_none(),
+        "init is not a directory"
+    );
+
+    let (dp, name) = namei_parent("/newfile").unwrap();
+    assert_eq!((dp.inum(), name), (ROOTINO, "newfile"));
+    assert_eq!(dp.lock().type_, T_DIR);
+
+    begin_op();
+    let ip = ialloc(ROOTDEV, T_FILE).expect("out of inodes");
+    {
+        let mut ip = ip.lock();
+        ip.nlink = 1;
+        ip.update();
+    }
+    {
+        let mut dp = dp.lock();
+        assert_eq!(dp.link("newfile", ip.inum()), Ok(()));
+        assert_eq!(dp.link("newfile", ip.inum()), Err(FsError::Exists));
+        assert!(!dp.is_dir_empty());
+    }
+    end_op();
+
+    let (found, _) = dp.lock().lookup("newfile").expect("not linked");
+    assert_eq!(found.inum(), ip.inum());
+    assert_eq!(namei("//newfile").map(|ip| ip.inum()), Some(ip.inum()));
+}
@@ -0,0 +1,160 @@
+//! Build a file system image for the kernel, ported from xv6's mkfs.
+//! `build.rs` runs it on the host with the user programs.
+
+use crate::layout::*;
+use std::{fs, mem::size_of, path::Path};
+
+// Disk layout:
+// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+const NBITMAP: usize = FSSIZE / BPB + 1;
+const NINODEBLOCKS: usize = NINODES / IPB + 1;
+const NLOG: usize = LOGSIZE;
+/// Number of meta blocks (boot, sb, nlog, inode, bitmap)
+const NMETA: usize = 2 + NLOG + NINODEBLOCKS + NBITMAP;
+/// Number of data blocks
+const NBLOCKS: usize = FSSIZE - NMETA;
+
+struct Image {
+    disk: Vec<u8>,
+    sb: SuperBlock,
+    free_inode: u32,
+    free_block: u32,
+}
+
+impl Image {
+    fn new() -> Image {
+        let sb = SuperBlock {
+            magic: FSMAGIC,
+            size: FSSIZE as u32,
+            nblocks: NBLOCKS as u32,
+            ninodes: NINODES as u32,
+            nlog: NLOG as u32,
+            logstart: 2,
+            inodestart: 2 + NLOG as u32,
+            bmapstart: (2 + NLOG + NINODEBLOCKS) as u32,
+        };
+        let mut img = Image {
+            disk: vec![0; FSSIZE * BSIZE],
+            sb,
+            free_inode: 1,
+            free_block: NMETA as u32, // the first free block that we can allocate
+        };
+        sb.write_to(img.block_mut(1));
+        img
+    }
+
+    fn block_mut(&mut self, b: u32) -> &mut [u8] {
+        let start = b as usize * BSIZE;
+        &mut self.disk[start..start + BSIZE]
+    }
+
+    fn read_inode(&mut self, inum: u32) -> DInode {
+        let b = self.sb.iblock(inum);
+        DInode::read_from(&self.block_mut(b)[ioffset(inum)..])
+    }
+
+    fn write_inode(&mut self, inum: u32, din: &DInode) {
+        let b = self.sb.iblock(inum);
+        din.write_to(&mut self.block_mut(b)[ioffset(inum)..]);
+    }
+
+    fn alloc_inode(&mut self, type_: i16) -> u32 {
+        let inum = self.free_inode;
+        self.free_inode += 1;
+        assert!((inum as usize) < NINODES, "mkfs: out of inodes");
+        let din = DInode {
+            type_,
+            nlink: 1,
+            ..Default::default()
+        };
+        self.write_inode(inum, &din);
+        inum
+    }
+
+    fn alloc_block(&mut self) -> u32 {
+        let b = self.free_block;
+        self.free_block += 1;
+        assert!((b as usize) < FSSIZE, "mkfs: out of blocks");
+        b
+    }
+
+    /// Append `data` to the end of inode `inum`.
+    fn append(&mut self, inum: u32, mut data: &[u8]) {
+        let mut din = self.read_inode(inum);
+        let mut off = din.size as usize;
+        while !data.is_empty() {
+            let fbn = off / BSIZE;
+            assert!(fbn < MAXFILE, "mkfs: file too large");
+            let b = if fbn < NDIRECT {
+                if din.addrs[fbn] == 0 {
+                    din.addrs[fbn] = self.alloc_block();
+                }
+                din.addrs[fbn]
+            } else {
+                if din.addrs[NDIRECT] == 0 {
+                    din.addrs[NDIRECT] = self.alloc_block();
+                }
+                let indirect = din.addrs[NDIRECT];
+                let slot = (fbn - NDIRECT) * size_of::<u32>();
+                let mut b = u32::read_from(&self.block_mut(indirect)[slot..]);
+                if b == 0 {
+                    b = self.alloc_block();
+                    b.write_to(&mut self.block_mut(indirect)[slot..]);
+                }
+                b
+            };
+            let start = off % BSIZE;
+            let n = data.len().min(BSIZE - start);
+            self.block_mut(b)[start..start + n].copy_from_slice(&data[..n]);
+            off += n;
+            data = &data[n..];
+        }
+        din.size = off as u32;
+        self.write_inode(inum, &din);
+    }
+
+    fn append_dirent(&mut self, dir: u32, inum: u32, name: &str) {
+        let de = Dirent::new(inum as u16, name.as_bytes());
+        let mut buf = [0; size_of::<Dirent>()];
+        de.write_to(&mut buf);
+        self.append(dir, &buf);
+    }
+
+    /// Mark all blocks allocated so far as used in the bitmap.
+    fn write_bitmap(&mut self) {
+        let used = self.free_block as usize;
+        assert!(used < BPB, "mkfs: bitmap too small");
+        let bmapstart = self.sb.bmapstart;
+        let bitmap = self.block_mut(bmapstart);
+        for b in 0..used as u32 {
+            let (i, m) = bmap_bit(b);
+            bitmap[i] |= m;
+        }
+    }
+}
+
+/// Write a file system image to `img` with `files` in the root directory,
+/// given as (name, contents).
+pub fn mkfs(img: &Path, files: &[(String, Vec<u8>)]) {
+    let mut fs = Image::new();
+
+    let rootino = fs.alloc_inode(T_DIR);
+    assert_eq!(rootino, ROOTINO);
+    fs.append_dirent(rootino, rootino, ".");
+    fs.append_dirent(rootino, rootino, "..");
+
+    for (name, contents) in files {
+        assert!(name.len() <= DIRSIZ, "mkfs: name too long: {name}");
+        let inum = fs.alloc_inode(T_FILE);
+        fs.append_dirent(rootino, inum, name);
+        fs.append(inum, contents);
+    }
+
+    // fix size of root inode dir
+    let mut din = fs.read_inode(rootino);
+    din.size = (din.size as usize / BSIZE + 1) as u32 * BSIZE as u32;
+    fs.write_inode(rootino, &din);
+
+    fs.write_bitmap();
+    fs::write(img, &fs.disk).expect("mkfs: failed to write image");
+}
@@ -0,0 +1,15 @@
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    // Link every user program at virtual address 0.
+    println!(
+        "cargo:rustc-link-arg-bins=-T{}",
+        dir.join("user.ld").display()
+    );
+    println!("cargo:rerun-if-changed=user.ld");
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+//! The first user process, started by the kernel's `user_init`.
+//! Sets up the console as standard input, output and error,
+//! then adopts orphaned processes and reaps them when they exit.
+
+use user::{dup, mknod, open, sleep, wait, write, CONSOLE, O_RDWR};
+
+#[no_mangle]
+fn main(_argc: usize, _argv: *const *const u8) -> i32 {
+    if open(c"console", O_RDWR) < 0 {
+        mknod(c"console", CONSOLE, 0);
+        open(c"console", O_RDWR);
+    }
+    dup(0); // stdout
+    dup(0); // stderr
+
+    write(1, b"init: starting\n");
+
+    loop {
+        // Returns whenever a parentless process exits,
+        // back off for a while if there is nobody to wait for.
+        if wait(None) < 0 {
+            sleep(10);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+#![no_std]
+
+//! Runtime for xv6 user programs: the program entry point
+//! and wrappers around the system calls.
+//!
+//! A program provides `#[no_mangle] fn main(argc: usize, argv: *const *const u8) -> i32`,
+//! `_start` calls it and exits with its return value.
+
+use core::{ffi::CStr, panic::PanicInfo, ptr};
+
+#[path = "../../src/fs/fcntl.rs"]
+mod fcntl;
+#[path = "../../src/mem/mman.rs"]
+mod mman;
+#[path = "../../src/syscall/num.rs"]
+mod num;
+#[path = "../../src/fs/stat.rs"]
+mod stat;
+
+pub use fcntl::*;
+pub use mman::*;
+pub use num::*;
+pub use stat::Stat;
+
+/// `options` flag for `waitpid`: return immediately if no child has exited
+pub const WNOHANG: i32 = 1;
+
+/// Major device number of the console
+pub const CONSOLE: i16 = 1;
+
+/// Major device number of the kernel statistics
+pub const STATS: i16 = 2;
+
+/// Console `ioctl` request to turn raw mode on (`arg` != 0) or off
+pub const CONSOLE_SET_RAW: usize = 1;
+
+#[no_mangle]
+#[link_section = ".text.entry"]
+pub extern "C" fn _start(argc: usize, argv: *const *const u8) -> ! {
+    extern "Rust" {
+        fn main(argc: usize, argv: *const *const u8) -> i32;
+    }
+    exit(unsafe { main(argc, argv) })
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}
+
+/// Trap into the kernel with the classic xv6 ABI:
+/// `a7` is the system call number, `a0`..`a5` the arguments,
+/// the return value comes back in `a0`.
+#[inline(always)]
+fn syscall(num: usize, args: [usize; 6]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a7") num,
+        );
+    }
+    ret
+}
+
+pub fn fork() -> i32 {
+    syscall(SYS_FORK, [0; 6]) as i32
+}
+
+pub fn exit(status: i32) -> ! {
+    syscall(SYS_EXIT, [status as usize, 0, 0, 0, 0, 0]);
+    unreachable!("exit returned")
+}
+
+/// Wait for any child to exit, store its exit status into `status`.
+pub fn wait(status: Option<&mut i32>) -> i32 {
+    let addr = status.map_or(ptr::null_mut(), |s| s as *mut i32);
+    syscall(SYS_WAIT, [addr as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+/// Wait for the child `pid` to exit, or any child if `pid` is not greater than 0.
+pub fn waitpid(pid: i32, status: Option<&mut i32>, options: i32) -> i32 {
+    let addr = status.map_or(ptr::null_mut(), |s| s as *mut i32);
+    syscall(
+        SYS_WAITPID,
+        [pid as usize, addr as usize, options as usize, 0, 0, 0],
+    ) as i32
+}
+
+pub fn kill(pid: i32) -> i32 {
+    syscall(SYS_KILL, [pid as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+/// Add `inc` to the nice value of this process, lower runs sooner.
+/// Returns the new nice value, which the kernel returns as 20 - nice.
+pub fn nice(inc: i32) -> i32 {
+    match syscall(SYS_NICE, [inc as usize, 0, 0, 0, 0, 0]) as isize {
+        r if r < 0 => r as i32,
+        r => 20 - r as i32,
+    }
+}
+
+/// Set the nice value of process `pid`, this one if `pid` is 0.
+pub fn setpriority(pid: i32, nice: i32) -> i32 {
+    syscall(SYS_SETPRIORITY, [pid as usize, nice as usize, 0, 0, 0, 0]) as i32
+}
+
+/// Let process `pid`, this one if `pid` is 0, only run on the CPUs
+/// in `mask`, a bit for each.
+pub fn sched_setaffinity(pid: i32, mask: u64) -> i32 {
+    syscall(
+        SYS_SCHED_SETAFFINITY,
+        [pid as usize, &mask as *const u64 as usize, 0, 0, 0, 0],
+    ) as i32
+}
+
+/// Store the CPUs process `pid`, this one if `pid` is 0, may run on
+/// into `mask`.
+pub fn sched_getaffinity(pid: i32, mask: &mut u64) -> i32 {
+    syscall(
+        SYS_SCHED_GETAFFINITY,
+        [pid as usize, mask as *mut u64 as usize, 0, 0, 0, 0],
+    ) as i32
+}
+
+/// Replace the current program, `argv` must end with a null pointer.
+pub fn exec(path: &CStr, argv: &[*const u8]) -> i32 {
+    assert!(argv.last().is_some_and(|arg| arg.is_null()));
+    syscall(
+        SYS_EXEC,
+        [path.as_ptr() as usize, argv.as_ptr() as usize, 0, 0, 0, 0],
+    ) as i32
+}
+
+pub fn getpid() -> i32 {
+    syscall(SYS_GETPID, [0; 6]) as i32
+}
+
+/// Grow the process memory by `n` bytes, return the start of the new memory.
+pub fn sbrk(n: isize) -> isize {
+    syscall(SYS_SBRK, [n as usize, 0, 0, 0, 0, 0])
+}
+
+/// Sleep for `ticks` clock ticks.
+pub fn sleep(ticks: i32) -> i32 {
+    syscall(SYS_SLEEP, [ticks as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+/// Clock ticks since boot
+pub fn uptime() -> usize {
+    syscall(SYS_UPTIME, [0; 6]) as usize
+}
+
+/// Open `path` with the `O_*` flags in `omode`, return a file descriptor.
+pub fn open(path: &CStr, omode: usize) -> i32 {
+    syscall(SYS_OPEN, [path.as_ptr() as usize, omode, 0, 0, 0, 0]) as i32
+}
+
+pub fn close(fd: i32) -> i32 {
+    syscall(SYS_CLOSE, [fd as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+/// Read up to `buf.len()` bytes, return how many were read, 0 at end of file.
+pub fn read(fd: i32, buf: &mut [u8]) -> isize {
+    syscall(
+        SYS_READ,
+        [fd as usize, buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0],
+    )
+}
+
+/// Write `buf`, return how many bytes were written.
+pub fn write(fd: i32, buf: &[u8]) -> isize {
+    syscall(
+        SYS_WRITE,
+        [fd as usize, buf.as_ptr() as usize, buf.len(), 0, 0, 0],
+    )
+}
+
+/// Return a new file descriptor for the same open file as `fd`.
+pub fn dup(fd: i32) -> i32 {
+    syscall(SYS_DUP, [fd as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+pub fn fstat(fd: i32, st: &mut Stat) -> i32 {
+    syscall(
+        SYS_FSTAT,
+        [fd as usize, st as *mut Stat as usize, 0, 0, 0, 0],
+    ) as i32
+}
+
+/// Create the device file `path`.
+pub fn mknod(path: &CStr, major: i16, minor: i16) -> i32 {
+    syscall(
+        SYS_MKNOD,
+        [
+            path.as_ptr() as usize,
+            major as usize,
+            minor as usize,
+            0,
+            0,
+            0,
+        ],
+    ) as i32
+}
+
+/// Send a device specific `request` to the driver of the device file `fd`.
+pub fn ioctl(fd: i32, request: usize, arg: usize) -> i32 {
+    syscall(SYS_IOCTL, [fd as usize, request, arg, 0, 0, 0]) as i32
+}
+
+/// Create a pipe, `fds[0]` is its read end and `fds[1]` its write end.
+pub fn pipe(fds: &mut [i32; 2]) -> i32 {
+    syscall(SYS_PIPE, [fds.as_mut_ptr() as usize, 0, 0, 0, 0, 0]) as i32
+}
+
+/// Map `len` bytes of the file `fd` from offset `off`, or zeroed memory
+/// with `MAP_ANONYMOUS`. Return the address of the mapping, or a negative
+/// error number.
+pub fn mmap(len: usize, prot: usize, flags: usize, fd: i32, off: usize) -> isize {
+    syscall(SYS_MMAP, [0, len, prot, flags, fd as usize, off])
+}
+
+/// Unmap the start or the end of a mapping.
+pub fn munmap(addr: usize, len: usize) -> i32 {
+    syscall(SYS_MUNMAP, [addr, len, 0, 0, 0, 0]) as i32
+}